@@ -0,0 +1,137 @@
+//! This module contains the behaviour of a malicious party, it is used to test that cheating is caught.
+//! A corrupted party runs the honest code, but its MAC key share, its preprocessing data
+//! or the messages that it sends are changed according to an `Attack`.
+
+use crate::algebra::Fp;
+use crate::crypto::commit::Scheme;
+use crate::error::TransportError;
+use crate::message::{PartyID, PartyMsg, PrepMsg};
+use crate::transport::Transport;
+
+use num_traits::One;
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// `Attack` describes how a corrupted party deviates from the protocol.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Attack {
+    /// Use a MAC key share that does not match the MACs in the preprocessing data.
+    WrongMacKey,
+    /// Change the shares that are sent in `PartyMsg::Elem`, e.g., the shares in an `Open`.
+    FlipElem,
+    /// Send an opening that does not match the commitment in the MAC check.
+    BadOpening,
+    /// Change the MAC of the share of `a` in every triple.
+    CorruptTripleMac,
+}
+
+fn flip(x: &Fp) -> Fp {
+    x + Fp::one()
+}
+
+impl Attack {
+    /// Return the MAC key share that the corrupted party uses instead of `alpha_share`.
+    pub fn alpha_share(&self, alpha_share: &Fp) -> Fp {
+        match self {
+            Attack::WrongMacKey => flip(alpha_share),
+            _ => alpha_share.clone(),
+        }
+    }
+
+    /// Return the preprocessing message that the corrupted party uses instead of `m`.
+    pub fn prep_msg(&self, m: PrepMsg) -> PrepMsg {
+        match (self, m) {
+            (Attack::CorruptTripleMac, PrepMsg::Triple(mut t)) => {
+                t.a.mac = flip(&t.a.mac);
+                PrepMsg::Triple(t)
+            }
+            (_, m) => m,
+        }
+    }
+}
+
+/// A transport that changes the messages that are sent by a corrupted party,
+/// the messages are forwarded unchanged if `attack` is `None`.
+/// The same change is sent to every party, so the corrupted party does not equivocate.
+pub struct AdversaryTransport<T> {
+    inner: T,
+    attack: Option<Attack>,
+    rng: Mutex<ChaCha20Rng>,
+}
+
+impl<T> AdversaryTransport<T> {
+    pub fn new(inner: T, attack: Option<Attack>) -> AdversaryTransport<T> {
+        AdversaryTransport {
+            inner,
+            attack,
+            rng: Mutex::new(ChaCha20Rng::from_entropy()),
+        }
+    }
+
+    fn tamper(&self, m: PartyMsg) -> PartyMsg {
+        match (self.attack, m) {
+            (Some(Attack::FlipElem), PartyMsg::Elem(x)) => PartyMsg::Elem(flip(&x)),
+            (Some(Attack::BadOpening), PartyMsg::Opening(o)) => {
+                // an opening of the same value with fresh randomness does not match the commitment
+                let rng = &mut *self.rng.lock().expect("rng lock poisoned");
+                PartyMsg::Opening(Scheme {}.commit(o.get_v(), rng).1)
+            }
+            (_, m) => m,
+        }
+    }
+}
+
+impl<T: Transport<PartyMsg, PartyMsg>> Transport<PartyMsg, PartyMsg> for AdversaryTransport<T> {
+    fn peers(&self) -> usize {
+        self.inner.peers()
+    }
+
+    fn send(&self, to: PartyID, m: PartyMsg) -> Result<(), TransportError> {
+        self.inner.send(to, self.tamper(m))
+    }
+
+    fn recv_from(&self, from: PartyID, timeout: Duration) -> Result<PartyMsg, TransportError> {
+        self.inner.recv_from(from, timeout)
+    }
+
+    fn broadcast(&self, m: PartyMsg) -> Result<(), TransportError> {
+        // tamper once so that every party receives the same message
+        let m = self.tamper(m);
+        for to in 0..self.peers() {
+            self.inner.send(to as PartyID, m.clone())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::ChanTransport;
+
+    const TEST_CAP: usize = 5;
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn test_adversary_transport() {
+        let rng = &mut ChaCha20Rng::from_entropy();
+        let x = Fp::random(rng);
+        let (c, o) = Scheme {}.commit(x.clone(), rng);
+
+        let honest = AdversaryTransport::new(ChanTransport::mesh(1, TEST_CAP).pop().unwrap(), None);
+        honest.broadcast(PartyMsg::Elem(x.clone())).unwrap();
+        assert_eq!(honest.recv_from(0, TIMEOUT).unwrap().into_elem().unwrap(), x);
+
+        let flipper = AdversaryTransport::new(ChanTransport::mesh(1, TEST_CAP).pop().unwrap(), Some(Attack::FlipElem));
+        flipper.send(0, PartyMsg::Elem(x.clone())).unwrap();
+        assert_eq!(flipper.recv_from(0, TIMEOUT).unwrap().into_elem().unwrap(), flip(&x));
+
+        let opener = AdversaryTransport::new(ChanTransport::mesh(1, TEST_CAP).pop().unwrap(), Some(Attack::BadOpening));
+        opener.broadcast(PartyMsg::Opening(o)).unwrap();
+        let bad = opener.recv_from(0, TIMEOUT).unwrap().into_opening().unwrap();
+        assert_eq!(bad.get_v(), x);
+        assert!(!Scheme {}.verify(&bad, &c));
+    }
+}
@@ -10,6 +10,9 @@ use std::mem::transmute;
 use std::ops::*;
 use std::str::FromStr;
 
+/// The modulus of the prime field in decimal, it must be the same as the one given to `PrimeFieldModulus`.
+pub const FIELD_MODULUS: &str = "52435875175126190479447740508185965837690552500527637822603658699938581184513";
+
 #[derive(PrimeField, Serialize, Deserialize)]
 #[PrimeFieldModulus = "52435875175126190479447740508185965837690552500527637822603658699938581184513"]
 #[PrimeFieldGenerator = "7"]
@@ -199,6 +202,12 @@ mod test {
         Fp::from_str(&x.to_string()).unwrap() == x
     }
 
+    #[test]
+    fn test_modulus() {
+        // parsing is done in the field, so the modulus becomes zero
+        assert_eq!(Fp(InnerFp::from_str_vartime(FIELD_MODULUS).unwrap()), Fp::zero());
+    }
+
     #[quickcheck]
     fn prop_limb_size(x: Fp) -> bool {
         x.0 .0.len() == LIMB_SIZE
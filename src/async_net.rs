@@ -0,0 +1,492 @@
+//! This module implements an asynchronous networking backend on top of tokio.
+//! It is enabled with the `async-net` feature and exposes the same channel interface as the threaded backend,
+//! so the protocol code does not change.
+//! Every stream is driven by two tasks on a small shared runtime,
+//! and a single forwarding thread moves outgoing messages from the channels into the runtime.
+//! When the channel of incoming messages is full, the stream is not read until the forwarding thread
+//! has delivered the pending message, so a slow consumer never blocks a worker of the runtime.
+//! Hence the number of OS threads does not grow with the number of streams.
+
+use crate::frame::{self, Header, Tagged, HEADER_LEN};
+use crate::io::{WrappedStream, TCPSTREAM_CAP};
+use crate::net::Stream;
+use crate::noise;
+
+use crossbeam::channel::{bounded, unbounded, Receiver, Select, SelectedOperation, Sender, TrySendError};
+use log::{error, info};
+use serde::{de::DeserializeOwned, Serialize};
+use std::cell::Cell;
+use std::io;
+use std::net::Shutdown;
+use std::sync::OnceLock;
+use std::thread;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+
+const WORKER_THREADS: usize = 2;
+
+type Reader = Box<dyn AsyncRead + Unpin + Send>;
+type Writer = Box<dyn AsyncWrite + Unpin + Send>;
+
+struct Backend {
+    rt: Runtime,
+    register: Sender<Registration>,
+}
+
+static BACKEND: OnceLock<Backend> = OnceLock::new();
+
+fn backend() -> &'static Backend {
+    BACKEND.get_or_init(|| {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(WORKER_THREADS)
+            .thread_name("ezmpc-async")
+            .enable_io()
+            .build()
+            .expect("failed to start the tokio runtime");
+        let (register, register_r) = unbounded();
+        thread::Builder::new()
+            .name("ezmpc-forward".to_string())
+            .spawn(move || forward(register_r))
+            .expect("failed to start the forwarding thread");
+        Backend { rt, register }
+    })
+}
+
+enum Registration {
+    Source(Box<dyn Source>),
+    Pending(Box<dyn Pending>),
+}
+
+enum Outgoing {
+    Data(Vec<u8>),
+    Shutdown,
+}
+
+// A source of outgoing messages, it hides the message type so that
+// the forwarding thread can select over streams that carry different messages.
+trait Source: Send {
+    // register the message and the shutdown channel, in that order
+    fn register<'a>(&'a self, sel: &mut Select<'a>) -> (usize, usize);
+
+    // complete the selected operation, return false if the stream is closed
+    fn complete(&self, op: SelectedOperation<'_>, shutdown: bool) -> bool;
+}
+
+struct Link<S> {
+    writer_r: Receiver<S>,
+    shutdown_r: Receiver<()>,
+    out: UnboundedSender<Outgoing>,
+}
+
+impl<S: 'static + Send + Serialize> Source for Link<S> {
+    fn register<'a>(&'a self, sel: &mut Select<'a>) -> (usize, usize) {
+        (sel.recv(&self.writer_r), sel.recv(&self.shutdown_r))
+    }
+
+    fn complete(&self, op: SelectedOperation<'_>, shutdown: bool) -> bool {
+        let msg = if shutdown {
+            let _ = op.recv(&self.shutdown_r);
+            None
+        } else {
+            op.recv(&self.writer_r).ok()
+        };
+        match msg {
+            Some(msg) => {
+                let data = bincode::serialize(&msg).expect("serialization failed");
+                self.out.send(Outgoing::Data(data)).is_ok()
+            }
+            None => {
+                // flush the queued messages since the peer may still be waiting for them
+                for msg in self.writer_r.try_iter() {
+                    let data = bincode::serialize(&msg).expect("serialization failed");
+                    let _ = self.out.send(Outgoing::Data(data));
+                }
+                let _ = self.out.send(Outgoing::Shutdown);
+                false
+            }
+        }
+    }
+}
+
+// An incoming message that did not fit into the channel of its stream.
+trait Pending: Send {
+    fn register<'a>(&'a self, sel: &mut Select<'a>) -> usize;
+
+    // deliver the message and wake up the read task
+    fn complete(&self, op: SelectedOperation<'_>);
+}
+
+struct Delivery<R> {
+    reader_s: Sender<R>,
+    msg: Cell<Option<R>>,
+    done: Cell<Option<oneshot::Sender<bool>>>,
+}
+
+impl<R: 'static + Send> Pending for Delivery<R> {
+    fn register<'a>(&'a self, sel: &mut Select<'a>) -> usize {
+        sel.send(&self.reader_s)
+    }
+
+    fn complete(&self, op: SelectedOperation<'_>) {
+        let msg = self.msg.take().expect("message already delivered");
+        let ok = op.send(&self.reader_s, msg).is_ok();
+        if let Some(done) = self.done.take() {
+            let _ = done.send(ok);
+        }
+    }
+}
+
+// move outgoing messages of all the streams into the runtime,
+// and deliver the incoming messages that are waiting for space in their channels
+fn forward(register_r: Receiver<Registration>) {
+    let mut sources: Vec<Box<dyn Source>> = Vec::new();
+    let mut pending: Vec<Box<dyn Pending>> = Vec::new();
+    loop {
+        let closed = {
+            let mut sel = Select::new();
+            let register_idx = sel.recv(&register_r);
+            let ops: Vec<_> = sources.iter().map(|s| s.register(&mut sel)).collect();
+            let pending_ops: Vec<_> = pending.iter().map(|p| p.register(&mut sel)).collect();
+            let op = sel.select();
+            let idx = op.index();
+            if idx == register_idx {
+                match op.recv(&register_r) {
+                    Ok(registration) => {
+                        drop(sel);
+                        match registration {
+                            Registration::Source(source) => sources.push(source),
+                            Registration::Pending(p) => pending.push(p),
+                        }
+                        continue;
+                    }
+                    Err(_) => return,
+                }
+            }
+            if let Some(i) = pending_ops.iter().position(|x| *x == idx) {
+                pending[i].complete(op);
+                drop(sel);
+                pending.swap_remove(i);
+                continue;
+            }
+            let (i, shutdown) = ops
+                .iter()
+                .enumerate()
+                .find_map(|(i, (msg_idx, shutdown_idx))| match idx {
+                    x if x == *msg_idx => Some((i, false)),
+                    x if x == *shutdown_idx => Some((i, true)),
+                    _ => None,
+                })
+                .expect("unknown operation");
+            if sources[i].complete(op, shutdown) {
+                None
+            } else {
+                Some(i)
+            }
+        };
+        if let Some(i) = closed {
+            sources.swap_remove(i);
+        }
+    }
+}
+
+async fn read_loop<R>(
+    mut reader: Reader,
+    mut decryptor: Option<noise::Decryptor>,
+    reader_s: &Sender<R>,
+    register: &Sender<Registration>,
+    max_frame_size: usize,
+) -> io::Result<()>
+where
+    R: 'static + Send + DeserializeOwned + Tagged,
+{
+    loop {
+        let mut header = [0u8; HEADER_LEN];
+        reader.read_exact(&mut header).await?;
+        let n = Header::from_bytes(&header).check(R::TAG, max_frame_size)?;
+        let mut value_buf = vec![0u8; n];
+        reader.read_exact(&mut value_buf).await?;
+        if let Some(dec) = decryptor.as_mut() {
+            value_buf = dec.decrypt(&value_buf)?;
+        }
+
+        let msg: R = frame::deserialize(&value_buf)?;
+        let delivered = match reader_s.try_send(msg) {
+            Ok(()) => true,
+            // the channel is full, stop reading until the forwarding thread delivered the message
+            Err(TrySendError::Full(msg)) => {
+                let (done_s, done_r) = oneshot::channel();
+                let delivery = Delivery {
+                    reader_s: reader_s.clone(),
+                    msg: Cell::new(Some(msg)),
+                    done: Cell::new(Some(done_s)),
+                };
+                register.send(Registration::Pending(Box::new(delivery))).is_ok() && done_r.await.unwrap_or(false)
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        };
+        if !delivered {
+            return Err(io::Error::other("receiver disconnected"));
+        }
+    }
+}
+
+async fn write_loop(mut writer: Writer, tag: u8, mut encryptor: Option<noise::Encryptor>, mut out_r: UnboundedReceiver<Outgoing>) -> io::Result<()> {
+    while let Some(x) = out_r.recv().await {
+        match x {
+            Outgoing::Data(mut data) => {
+                if let Some(enc) = encryptor.as_mut() {
+                    data = enc.encrypt(&data)?;
+                }
+                writer.write_all(&Header::new(tag, data.len()).to_bytes()).await?;
+                writer.write_all(&data).await?;
+            }
+            Outgoing::Shutdown => break,
+        }
+    }
+    Ok(())
+}
+
+/// The handle of a wrapped stream, `join` waits until the stream is closed.
+pub struct JoinHandle {
+    done: Receiver<Result<(), String>>,
+}
+
+impl JoinHandle {
+    /// Wait for the stream to close, an error is returned if one of its tasks panicked.
+    pub fn join(self) -> thread::Result<()> {
+        match self.done.recv() {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(Box::new(e)),
+            Err(e) => Err(Box::new(e.to_string())),
+        }
+    }
+}
+
+/// Wrap a stream into channels, like `io::wrap_stream` but the stream is driven by the async runtime.
+/// If a `session` is given, every message is encrypted and authenticated using it.
+/// A protocol violation of the peer closes the stream and is sent on the error channel.
+pub fn wrap_stream<S, R>(stream: Stream, session: Option<noise::Session>, max_frame_size: usize) -> WrappedStream<S, R, JoinHandle>
+where
+    S: 'static + Sync + Send + Clone + Serialize + Tagged,
+    R: 'static + Sync + Send + Clone + DeserializeOwned + Tagged,
+{
+    let backend = backend();
+    let (reader_s, reader_r) = bounded(TCPSTREAM_CAP);
+    let (writer_s, writer_r) = bounded(TCPSTREAM_CAP);
+    let (error_s, error_r) = bounded(1);
+    let (shutdown_s, shutdown_r) = bounded(1);
+    let (done_s, done_r) = bounded(1);
+    let (out_s, out_r) = unbounded_channel();
+    let (encryptor, decryptor) = match session {
+        Some(session) => {
+            let (enc, dec) = session.split();
+            (Some(enc), Some(dec))
+        }
+        None => (None, None),
+    };
+
+    // keep a blocking handle so that both directions can be shutdown
+    let reader_raw = stream.try_clone().unwrap();
+    let writer_raw = stream.try_clone().unwrap();
+    let _guard = backend.rt.enter();
+    let (reader, writer): (Reader, Writer) = match stream {
+        Stream::Tcp(s) => {
+            s.set_nonblocking(true).unwrap();
+            let (r, w) = tokio::net::TcpStream::from_std(s).expect("failed to register the stream").into_split();
+            (Box::new(r), Box::new(w))
+        }
+        Stream::Unix(s) => {
+            s.set_nonblocking(true).unwrap();
+            let (r, w) = tokio::net::UnixStream::from_std(s).expect("failed to register the stream").into_split();
+            (Box::new(r), Box::new(w))
+        }
+    };
+
+    let register = backend.register.clone();
+    let read_hdl = backend.rt.spawn(async move {
+        if let Err(e) = read_loop(reader, decryptor, &reader_s, &register, max_frame_size).await {
+            match frame::violation(&e) {
+                Some(v) => {
+                    error!(
+                        "[{:?}] protocol violation by {:?}: {}",
+                        reader_raw.local_addr(),
+                        reader_raw.peer_addr(),
+                        v
+                    );
+                    // report the violation before the receiver is disconnected
+                    let _ = error_s.try_send(v);
+                }
+                None => info!("[{:?}] read failed but probably not an issue: {:?}", reader_raw.local_addr(), e),
+            }
+        }
+        drop(reader_s);
+        // try to shutdown because the writer might've closed the stream too
+        let _ = reader_raw.shutdown(Shutdown::Both);
+    });
+    let write_hdl = backend.rt.spawn(async move {
+        match write_loop(writer, S::TAG, encryptor, out_r).await {
+            Ok(()) => info!("[{:?}] closing stream with peer {:?}", writer_raw.local_addr(), writer_raw.peer_addr()),
+            Err(e) => error!("[{:?}] write error: {:?}", writer_raw.local_addr(), e),
+        }
+        // try to shutdown because the reader might've closed the stream too
+        let _ = writer_raw.shutdown(Shutdown::Both);
+    });
+    backend.rt.spawn(async move {
+        let res = match (write_hdl.await, read_hdl.await) {
+            (Ok(()), Ok(())) => Ok(()),
+            (Err(e), _) | (_, Err(e)) => Err(e.to_string()),
+        };
+        let _ = done_s.send(res);
+    });
+
+    backend
+        .register
+        .send(Registration::Source(Box::new(Link {
+            writer_r,
+            shutdown_r,
+            out: out_s,
+        })))
+        .expect("forwarding thread stopped");
+    (writer_s, reader_r, error_r, shutdown_s, JoinHandle { done: done_r })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::FrameError;
+    use crate::frame::DEFAULT_MAX_FRAME_SIZE;
+    use serde::Deserialize;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::net::UnixStream;
+
+    #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+    struct Msg {
+        a: usize,
+    }
+
+    impl Tagged for Msg {
+        const TAG: u8 = 42;
+    }
+
+    fn pair(listener: &TcpListener, unix: bool, secure: bool) -> (Stream, Stream, Option<(noise::Session, noise::Session)>) {
+        let (mut client, mut server) = if unix {
+            let (client, server) = UnixStream::pair().unwrap();
+            (Stream::Unix(client), Stream::Unix(server))
+        } else {
+            let addr = listener.local_addr().unwrap();
+            let hdl = thread::spawn(move || TcpStream::connect(addr).unwrap());
+            let (server, _) = listener.accept().unwrap();
+            (Stream::Tcp(hdl.join().unwrap()), Stream::Tcp(server))
+        };
+        if !secure {
+            return (client, server, None);
+        }
+        let (public0, secret0) = noise::generate_keypair();
+        let (public1, secret1) = noise::generate_keypair();
+        let hdl = thread::spawn(move || {
+            let session = noise::handshake_responder(&mut server, 1, &secret1, 0, &public0).unwrap();
+            (server, session)
+        });
+        let client_session = noise::handshake_initiator(&mut client, 0, &secret0, 1, &public1).unwrap();
+        let (server, server_session) = hdl.join().unwrap();
+        (client, server, Some((client_session, server_session)))
+    }
+
+    #[test]
+    fn test_async_stream_wrapper() {
+        const LINKS: usize = 8;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut links = Vec::new();
+        for i in 0..LINKS {
+            let (client, server, sessions) = pair(&listener, i % 4 < 2, i % 2 == 0);
+            let (s0, s1) = match sessions {
+                Some((s0, s1)) => (Some(s0), Some(s1)),
+                None => (None, None),
+            };
+            links.push((
+                wrap_stream::<Msg, Msg>(client, s0, DEFAULT_MAX_FRAME_SIZE),
+                wrap_stream::<Msg, Msg>(server, s1, DEFAULT_MAX_FRAME_SIZE),
+            ));
+        }
+
+        // send more messages than the channel capacity in both directions
+        for (i, ((sender0, receiver0, _, _, _), (sender1, receiver1, _, _, _))) in links.iter().enumerate() {
+            let sender0 = sender0.clone();
+            let hdl = thread::spawn(move || (0..TCPSTREAM_CAP * 2).for_each(|a| sender0.send(Msg { a: a + i }).unwrap()));
+            for a in 0..TCPSTREAM_CAP * 2 {
+                assert_eq!(receiver1.recv().unwrap(), Msg { a: a + i });
+            }
+            hdl.join().unwrap();
+            sender1.send(Msg { a: i }).unwrap();
+            assert_eq!(receiver0.recv().unwrap(), Msg { a: i });
+        }
+
+        // closing one side also closes the other side
+        for ((_, _, _, shutdown0, handle0), (_, receiver1, errors1, shutdown1, handle1)) in links {
+            shutdown0.send(()).unwrap();
+            handle0.join().unwrap();
+            assert!(receiver1.recv().is_err());
+            assert!(errors1.try_recv().is_err());
+            shutdown1.send(()).unwrap();
+            handle1.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_async_backpressure() {
+        const LINKS: usize = 4 * WORKER_THREADS;
+        let links: Vec<_> = (0..LINKS)
+            .map(|_| {
+                let (client, server) = UnixStream::pair().unwrap();
+                (
+                    wrap_stream::<Msg, Msg>(Stream::Unix(client), None, DEFAULT_MAX_FRAME_SIZE),
+                    wrap_stream::<Msg, Msg>(Stream::Unix(server), None, DEFAULT_MAX_FRAME_SIZE),
+                )
+            })
+            .collect();
+
+        // fill the channels of every link without consuming them,
+        // then the runtime does not start more threads than its workers
+        for ((sender, _, _, _, _), _) in &links {
+            for a in 0..TCPSTREAM_CAP + 2 {
+                sender.send(Msg { a }).unwrap();
+            }
+        }
+        thread::sleep(std::time::Duration::from_millis(100));
+        let threads = std::fs::read_dir("/proc/self/task")
+            .unwrap()
+            .filter(|t| std::fs::read_to_string(t.as_ref().unwrap().path().join("comm")).unwrap().trim() == "ezmpc-async")
+            .count();
+        assert!(threads <= WORKER_THREADS, "{} runtime threads", threads);
+
+        // the messages are delivered in order once the consumer catches up
+        for (_, (_, receiver, _, _, _)) in &links {
+            for a in 0..TCPSTREAM_CAP + 2 {
+                assert_eq!(receiver.recv().unwrap(), Msg { a });
+            }
+        }
+        for ((_, _, _, shutdown0, handle0), (_, _, _, shutdown1, handle1)) in links {
+            shutdown0.send(()).unwrap();
+            handle0.join().unwrap();
+            shutdown1.send(()).unwrap();
+            handle1.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_async_frame_violation() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        // dropping the sender would close the stream, so keep it until the violation is seen
+        let (_sender, receiver, errors, shutdown, handle) = wrap_stream::<Msg, Msg>(Stream::Unix(server), None, DEFAULT_MAX_FRAME_SIZE);
+
+        // the header is rejected before the payload is read
+        client.write_all(&Header::new(Msg::TAG, usize::MAX).to_bytes()).unwrap();
+        assert!(receiver.recv().is_err());
+        assert_eq!(errors.recv().unwrap(), FrameError::TooLarge(u64::MAX));
+        shutdown.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,260 @@
+//! This module implements a challenge-response protocol that proves the identity of a party
+//! using its long-term signing key.
+//! The party that accepts a connection sends a random challenge
+//! and the party that made the connection signs it together with its claimed identity.
+//! The public verification keys are listed in the public configuration.
+
+use crate::message::PartyID;
+use crate::noise::deserialize_key;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use ed25519_dalek::{Signature, Signer, Verifier};
+use rand::{CryptoRng, Rng, RngCore};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::io;
+use thiserror::Error;
+
+const DOMAIN: &[u8] = b"ezmpc-auth";
+const CHALLENGE_LEN: usize = 32;
+
+// status codes sent by the verifier
+const CHALLENGE: u8 = 0;
+const ACCEPTED: u8 = 1;
+const REJECTED_UNKNOWN: u8 = 2;
+const REJECTED_DUPLICATE: u8 = 3;
+const REJECTED_SIGNATURE: u8 = 4;
+
+/// `AuthError` describes why a party was not allowed to join.
+#[derive(Error, Copy, Clone, Eq, PartialEq, Debug)]
+pub enum AuthError {
+    #[error("party {0} is not expected to connect")]
+    UnknownParty(PartyID),
+    #[error("party {0} is already connected")]
+    DuplicateParty(PartyID),
+    #[error("party {0} failed to prove its identity")]
+    BadSignature(PartyID),
+    #[error("unexpected authentication status {1} for party {0}")]
+    BadStatus(PartyID, u8),
+}
+
+impl AuthError {
+    fn status(&self) -> u8 {
+        match self {
+            AuthError::UnknownParty(_) => REJECTED_UNKNOWN,
+            AuthError::DuplicateParty(_) => REJECTED_DUPLICATE,
+            AuthError::BadSignature(_) => REJECTED_SIGNATURE,
+            AuthError::BadStatus(_, x) => *x,
+        }
+    }
+
+    fn from_status(id: PartyID, status: u8) -> AuthError {
+        match status {
+            REJECTED_UNKNOWN => AuthError::UnknownParty(id),
+            REJECTED_DUPLICATE => AuthError::DuplicateParty(id),
+            REJECTED_SIGNATURE => AuthError::BadSignature(id),
+            x => AuthError::BadStatus(id, x),
+        }
+    }
+}
+
+impl From<AuthError> for io::Error {
+    fn from(e: AuthError) -> Self {
+        io::Error::new(io::ErrorKind::PermissionDenied, e)
+    }
+}
+
+/// The public key used to verify the identity of a party, it is serialized as a base64 string.
+#[derive(Clone, Eq, PartialEq)]
+pub struct VerifyKey(ed25519_dalek::VerifyingKey);
+
+/// The long-term signing key of a party, it is serialized as a base64 string.
+#[derive(Clone)]
+pub struct SigningKey(ed25519_dalek::SigningKey);
+
+impl SigningKey {
+    /// Generate a fresh signing key.
+    pub fn generate<R: CryptoRng + RngCore>(rng: &mut R) -> SigningKey {
+        SigningKey(ed25519_dalek::SigningKey::generate(rng))
+    }
+
+    /// Return the key that is used to verify signatures from this key.
+    pub fn verify_key(&self) -> VerifyKey {
+        VerifyKey(self.0.verifying_key())
+    }
+}
+
+impl fmt::Debug for VerifyKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&base64::encode(self.0.as_bytes()))
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SigningKey(..)")
+    }
+}
+
+impl Serialize for VerifyKey {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&base64::encode(self.0.as_bytes()))
+    }
+}
+
+impl<'de> Deserialize<'de> for VerifyKey {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let bytes = deserialize_key(d)?;
+        ed25519_dalek::VerifyingKey::from_bytes(&bytes)
+            .map(VerifyKey)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for SigningKey {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&base64::encode(self.0.to_bytes()))
+    }
+}
+
+impl<'de> Deserialize<'de> for SigningKey {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        deserialize_key(d).map(|bytes| SigningKey(ed25519_dalek::SigningKey::from_bytes(&bytes)))
+    }
+}
+
+// the context identifies the verifier so that a signature cannot be replayed to a different verifier
+fn signed_message(context: &[u8], id: PartyID, challenge: &[u8; CHALLENGE_LEN]) -> Vec<u8> {
+    let mut out = DOMAIN.to_vec();
+    out.write_u64::<LittleEndian>(context.len() as u64).unwrap();
+    out.extend_from_slice(context);
+    out.write_u32::<LittleEndian>(id).unwrap();
+    out.extend_from_slice(challenge);
+    out
+}
+
+/// Prove to the peer on the other side of `stream` that we are `my_id`.
+/// An error that wraps an `AuthError` is returned if the peer rejects us.
+pub fn prove<S: io::Read + io::Write>(stream: &mut S, my_id: PartyID, my_key: &SigningKey, context: &[u8]) -> io::Result<()> {
+    stream.write_u32::<LittleEndian>(my_id)?;
+    let status = stream.read_u8()?;
+    if status != CHALLENGE {
+        return Err(AuthError::from_status(my_id, status).into());
+    }
+
+    let mut challenge = [0u8; CHALLENGE_LEN];
+    stream.read_exact(&mut challenge)?;
+    let signature = my_key.0.sign(&signed_message(context, my_id, &challenge));
+    stream.write_all(&signature.to_bytes())?;
+
+    match stream.read_u8()? {
+        ACCEPTED => Ok(()),
+        status => Err(AuthError::from_status(my_id, status).into()),
+    }
+}
+
+/// Challenge the peer on the other side of `stream` to prove its identity.
+/// The function `lookup` should return the verification key of the claimed identity,
+/// or an `AuthError` if the identity is not allowed to connect, e.g., it is unknown or a duplicate.
+/// If the peer is rejected, it is informed of the reason and an error that wraps an `AuthError` is returned.
+pub fn challenge<S, F>(stream: &mut S, context: &[u8], lookup: F, rng: &mut impl Rng) -> io::Result<PartyID>
+where
+    S: io::Read + io::Write,
+    F: FnOnce(PartyID) -> Result<VerifyKey, AuthError>,
+{
+    let id = stream.read_u32::<LittleEndian>()?;
+    let key = match lookup(id) {
+        Ok(key) => key,
+        Err(e) => {
+            stream.write_u8(e.status())?;
+            return Err(e.into());
+        }
+    };
+
+    let challenge: [u8; CHALLENGE_LEN] = rng.gen();
+    stream.write_u8(CHALLENGE)?;
+    stream.write_all(&challenge)?;
+
+    let mut signature = [0u8; Signature::BYTE_SIZE];
+    stream.read_exact(&mut signature)?;
+    let signature = Signature::from_bytes(&signature);
+    match key.0.verify(&signed_message(context, id, &challenge), &signature) {
+        Ok(()) => {
+            stream.write_u8(ACCEPTED)?;
+            Ok(id)
+        }
+        Err(_) => {
+            let e = AuthError::BadSignature(id);
+            stream.write_u8(e.status())?;
+            Err(e.into())
+        }
+    }
+}
+
+/// Extract the `AuthError` from an error returned by `prove` or `challenge`, if there is one.
+pub fn auth_error(e: &io::Error) -> Option<AuthError> {
+    e.get_ref().and_then(|inner| inner.downcast_ref::<AuthError>()).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    const TEST_SEED: [u8; 32] = [8u8; 32];
+    const CONTEXT: &[u8] = b"test";
+
+    fn run(id: PartyID, key: SigningKey, expected: VerifyKey, context: &'static [u8]) -> (io::Result<()>, io::Result<PartyID>) {
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+        let hdl = thread::spawn(move || {
+            let rng = &mut ChaCha20Rng::from_seed(TEST_SEED);
+            challenge(
+                &mut b,
+                CONTEXT,
+                |x| if x == 0 { Ok(expected) } else { Err(AuthError::UnknownParty(x)) },
+                rng,
+            )
+        });
+        let proved = prove(&mut a, id, &key, context);
+        (proved, hdl.join().unwrap())
+    }
+
+    #[test]
+    fn test_auth() {
+        let rng = &mut ChaCha20Rng::from_seed(TEST_SEED);
+        let key = SigningKey::generate(rng);
+        let other_key = SigningKey::generate(rng);
+
+        // the correct key is accepted
+        let (proved, verified) = run(0, key.clone(), key.verify_key(), CONTEXT);
+        proved.unwrap();
+        assert_eq!(verified.unwrap(), 0);
+
+        // an impostor is rejected
+        let (proved, verified) = run(0, other_key.clone(), key.verify_key(), CONTEXT);
+        assert_eq!(auth_error(&proved.unwrap_err()), Some(AuthError::BadSignature(0)));
+        assert_eq!(auth_error(&verified.unwrap_err()), Some(AuthError::BadSignature(0)));
+
+        // a signature for a different verifier is rejected
+        let (proved, _) = run(0, key.clone(), key.verify_key(), b"other");
+        assert_eq!(auth_error(&proved.unwrap_err()), Some(AuthError::BadSignature(0)));
+
+        // an unknown identity is rejected
+        let (proved, verified) = run(1, key.clone(), key.verify_key(), CONTEXT);
+        assert_eq!(auth_error(&proved.unwrap_err()), Some(AuthError::UnknownParty(1)));
+        assert_eq!(auth_error(&verified.unwrap_err()), Some(AuthError::UnknownParty(1)));
+    }
+
+    #[test]
+    fn test_key_serde() {
+        let rng = &mut ChaCha20Rng::from_seed(TEST_SEED);
+        let key = SigningKey::generate(rng);
+        let key_str = ron::to_string(&key).unwrap();
+        let verify_key_str = ron::to_string(&key.verify_key()).unwrap();
+        assert_eq!(ron::from_str::<SigningKey>(&key_str).unwrap().verify_key(), key.verify_key());
+        assert_eq!(ron::from_str::<VerifyKey>(&verify_key_str).unwrap(), key.verify_key());
+    }
+}
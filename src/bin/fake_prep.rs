@@ -3,37 +3,45 @@ use ezmpc::io;
 use clap::{App, Arg};
 use env_logger;
 use ezmpc::io::PrivateConf;
-use std::net::SocketAddr;
+use std::path::Path;
 use std::str::FromStr;
 
-const LISTEN_ADDR_STR: &'static str = "LISTEN_ADDR";
-const MAX_TRIPLES_STR: &'static str = "max_triples";
-const MAX_RAND_PER_PARTY_STR: &'static str = "max_rand_per_party";
+const SERVE_STR: &'static str = "serve";
+const OUT_DIR_STR: &'static str = "out-dir";
+const TRIPLES_STR: &'static str = "triples";
+const RAND_SHARES_STR: &'static str = "rand-shares";
 
 fn main() -> Result<(), ezmpc::error::ApplicationError> {
     env_logger::init();
 
     #[rustfmt::skip]
     let matches = App::new("ezmpc fake prep")
-        .arg(Arg::new(LISTEN_ADDR_STR)
-            .help("Set the listening socket address")
-            .required(true)
-            .index(1))
+        .arg(Arg::new(SERVE_STR)
+            .help("Serve the preprocessing data with the settings in this prep server .ron file")
+            .long(SERVE_STR)
+            .takes_value(true)
+            .required_unless_present(OUT_DIR_STR)
+            .conflicts_with(OUT_DIR_STR))
+        .arg(Arg::new(OUT_DIR_STR)
+            .help("Write the preprocessing data of every party to a file in this directory")
+            .long(OUT_DIR_STR)
+            .takes_value(true)
+            .requires_all(&[TRIPLES_STR, RAND_SHARES_STR]))
+        .arg(Arg::new(TRIPLES_STR)
+            .help("Set the number of triples in the files")
+            .long(TRIPLES_STR)
+            .takes_value(true))
+        .arg(Arg::new(RAND_SHARES_STR)
+            .help("Set the number of random sharings for every party in the files")
+            .long(RAND_SHARES_STR)
+            .takes_value(true))
         .arg(Arg::new(PrivateConf::arg_name())
             .help("Set the private conf files to calculate alpha")
+            .required(true)
+            .index(1)
             .setting(clap::ArgSettings::MultipleValues))
-        .arg(Arg::new(MAX_RAND_PER_PARTY_STR)
-            .help("Set the maximum number of random shares per party")
-            .short('r')
-            .default_value("100"))
-        .arg(Arg::new(MAX_TRIPLES_STR)
-            .help("Set the maximum number of triples")
-            .short('t')
-            .default_value("100"))
         .get_matches();
 
-    let listen_addr: SocketAddr = matches.value_of(LISTEN_ADDR_STR).unwrap().parse()?;
-
     let fnames: Vec<_> = matches.values_of(PrivateConf::arg_name()).unwrap().collect();
     let mut priv_confs = vec![];
     for fname in fnames {
@@ -41,8 +49,15 @@ fn main() -> Result<(), ezmpc::error::ApplicationError> {
         priv_confs.push(priv_conf);
     }
 
-    let r = usize::from_str(matches.value_of(MAX_RAND_PER_PARTY_STR).unwrap())?;
-    let t = usize::from_str(matches.value_of(MAX_TRIPLES_STR).unwrap())?;
-
-    io::fake_prep_main(listen_addr, priv_confs, r, t)
+    match matches.value_of(OUT_DIR_STR) {
+        Some(out_dir) => {
+            let triple_count = usize::from_str(matches.value_of(TRIPLES_STR).unwrap())?;
+            let rand_share_count = usize::from_str(matches.value_of(RAND_SHARES_STR).unwrap())?;
+            io::fake_prep_store_main(Path::new(out_dir), priv_confs, triple_count, rand_share_count)
+        }
+        None => {
+            let prep_conf = io::PrepServerConfig::from_file(matches.value_of(SERVE_STR).unwrap())?;
+            io::fake_prep_main(prep_conf, priv_confs)
+        }
+    }
 }
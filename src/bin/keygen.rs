@@ -0,0 +1,26 @@
+use ezmpc::io;
+
+use clap::{App, Arg};
+
+fn main() -> Result<(), ezmpc::error::ApplicationError> {
+    env_logger::init();
+
+    #[rustfmt::skip]
+    let matches = App::new("ezmpc keygen")
+        .about("Generate the MAC key share of a node together with the other nodes")
+        .arg(Arg::new(io::PublicConf::arg_name())
+            .help("Set the public .ron file")
+            .takes_value(true)
+            .required(true))
+        .arg(Arg::new(io::PrivateConf::arg_name())
+            .help("Set the private .ron file, the MAC key share is written to it")
+            .takes_value(true)
+            .required(true))
+        .get_matches();
+
+    let public_f = matches.value_of(io::PublicConf::arg_name()).unwrap();
+    let public_ron = io::PublicConf::from_file(public_f)?;
+
+    let private_f = matches.value_of(io::PrivateConf::arg_name()).unwrap();
+    io::keygen_main(public_ron, private_f)
+}
@@ -1,4 +1,5 @@
 use ezmpc::io;
+use ezmpc::optimizer;
 use ezmpc::vm;
 
 use clap::{App, Arg};
@@ -6,6 +7,8 @@ use env_logger;
 
 const PROG_FILE_STR: &'static str = "PROGRAM";
 const INPUT_STR: &'static str = "INPUT";
+const OPTIMIZE_STR: &'static str = "optimize";
+const PREP_FILE_STR: &'static str = "prep-file";
 
 fn main() -> Result<(), ezmpc::error::ApplicationError> {
     env_logger::init();
@@ -27,18 +30,33 @@ fn main() -> Result<(), ezmpc::error::ApplicationError> {
         .arg(Arg::new(INPUT_STR)
             .help("Set the secret input to ezmpc")
             .setting(clap::ArgSettings::MultipleValues))
+        .arg(Arg::new(OPTIMIZE_STR)
+            .help("Optimize the program before running it, all parties must use the same setting")
+            .short('O')
+            .long(OPTIMIZE_STR))
+        .arg(Arg::new(PREP_FILE_STR)
+            .help("Read the preprocessing data from this file instead of the preprocessing server")
+            .long(PREP_FILE_STR)
+            .takes_value(true))
         .get_matches();
 
     let public_f = matches.value_of(io::PublicConf::arg_name()).unwrap();
     let public_ron = io::PublicConf::from_file(public_f)?;
 
     let private_f = matches.value_of(io::PrivateConf::arg_name()).unwrap();
-    let private_ron = io::PrivateConf::from_file(private_f)?;
+    let mut private_ron = io::PrivateConf::from_file(private_f)?;
+    if let Some(prep_f) = matches.value_of(PREP_FILE_STR) {
+        private_ron.prep_file = Some(prep_f.into());
+    }
 
     let prog_f = matches.value_of(PROG_FILE_STR).unwrap();
-    let prog: Vec<vm::Instruction> = io::read_prog(prog_f)?;
+    let mut prog: Vec<vm::Instruction> = io::read_prog(prog_f)?;
+    if matches.is_present(OPTIMIZE_STR) {
+        prog = optimizer::optimize(&prog);
+    }
 
-    let inputs: Vec<_> = matches.values_of(INPUT_STR).unwrap().collect();
+    // a node may have no inputs
+    let inputs: Vec<_> = matches.values_of(INPUT_STR).map(|v| v.collect()).unwrap_or_default();
     let reg = io::create_register(private_ron.id, &prog, inputs)?;
     let res = io::online_node_main(public_ron, private_ron, reg, prog, None)?;
 
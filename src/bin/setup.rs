@@ -0,0 +1,36 @@
+use ezmpc::io;
+
+use clap::{App, Arg};
+use std::path::Path;
+use std::str::FromStr;
+
+const N_STR: &'static str = "N";
+const BASE_PORT_STR: &'static str = "BASE_PORT";
+const OUT_DIR_STR: &'static str = "OUT_DIR";
+
+fn main() -> Result<(), ezmpc::error::ApplicationError> {
+    env_logger::init();
+
+    #[rustfmt::skip]
+    let matches = App::new("ezmpc setup")
+        .about("Write the configs and a launch script for a cluster on the local host")
+        .arg(Arg::new(N_STR)
+            .help("Set the number of nodes")
+            .required(true)
+            .index(1))
+        .arg(Arg::new(BASE_PORT_STR)
+            .help("Set the port of the synchronizer, the other processes use the ports after it")
+            .required(true)
+            .index(2))
+        .arg(Arg::new(OUT_DIR_STR)
+            .help("Set the output directory")
+            .required(true)
+            .index(3))
+        .get_matches();
+
+    let n = usize::from_str(matches.value_of(N_STR).unwrap())?;
+    let base_port = u16::from_str(matches.value_of(BASE_PORT_STR).unwrap())?;
+    let out_dir = Path::new(matches.value_of(OUT_DIR_STR).unwrap());
+    std::fs::create_dir_all(out_dir)?;
+    io::setup_main(n, base_port, out_dir)
+}
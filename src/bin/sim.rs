@@ -0,0 +1,133 @@
+use ezmpc::algebra::Fp;
+use ezmpc::error::ApplicationError;
+use ezmpc::fault::FaultConfig;
+use ezmpc::io;
+use ezmpc::optimizer;
+use ezmpc::sim;
+use ezmpc::vm;
+
+use clap::{App, Arg};
+use std::str::FromStr;
+use std::time::Duration;
+
+const N_STR: &'static str = "N";
+const PROG_FILE_STR: &'static str = "PROGRAM";
+const INPUT_STR: &'static str = "input";
+const OPTIMIZE_STR: &'static str = "optimize";
+const DELAY_STR: &'static str = "delay";
+const DROP_STR: &'static str = "drop";
+const CORRUPT_STR: &'static str = "corrupt";
+const REORDER_STR: &'static str = "reorder";
+const DISCONNECT_STR: &'static str = "disconnect-after";
+
+// parse an input of the form `ID:VALUE`
+fn parse_input(s: &str) -> Result<(usize, Fp), ApplicationError> {
+    let (id, value) = s
+        .split_once(':')
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("input {} is not ID:VALUE", s)))?;
+    Ok((usize::from_str(id)?, Fp::from_str(value)?))
+}
+
+fn main() -> Result<(), ApplicationError> {
+    env_logger::init();
+
+    #[rustfmt::skip]
+    let matches = App::new("ezmpc simulator")
+        .about("Run all the parties of a computation in one process")
+        .arg(Arg::new(N_STR)
+            .help("Set the number of parties")
+            .required(true)
+            .index(1))
+        .arg(Arg::new(PROG_FILE_STR)
+            .help("Set the program file")
+            .required(true)
+            .index(2))
+        .arg(Arg::new(INPUT_STR)
+            .help("Add a secret input as ID:VALUE, the inputs of a party are used in the given order")
+            .short('i')
+            .long(INPUT_STR)
+            .takes_value(true)
+            .multiple_occurrences(true))
+        .arg(Arg::new(OPTIMIZE_STR)
+            .help("Optimize the program before running it")
+            .short('O')
+            .long(OPTIMIZE_STR))
+        .arg(Arg::new(DELAY_STR)
+            .help("Delay every message by this many milliseconds")
+            .long(DELAY_STR)
+            .takes_value(true))
+        .arg(Arg::new(DROP_STR)
+            .help("Drop every message with this probability")
+            .long(DROP_STR)
+            .takes_value(true))
+        .arg(Arg::new(CORRUPT_STR)
+            .help("Flip a bit in every message with this probability")
+            .long(CORRUPT_STR)
+            .takes_value(true))
+        .arg(Arg::new(REORDER_STR)
+            .help("Deliver every message after the next one from the same party with this probability")
+            .long(REORDER_STR)
+            .takes_value(true))
+        .arg(Arg::new(DISCONNECT_STR)
+            .help("Disconnect a party after it sent this many messages")
+            .long(DISCONNECT_STR)
+            .takes_value(true))
+        .get_matches();
+
+    let n = usize::from_str(matches.value_of(N_STR).unwrap())?;
+    let mut prog: Vec<vm::Instruction> = io::read_prog(matches.value_of(PROG_FILE_STR).unwrap())?;
+    if matches.is_present(OPTIMIZE_STR) {
+        prog = optimizer::optimize(&prog);
+    }
+
+    let mut inputs = vec![vec![]; n];
+    for s in matches.values_of(INPUT_STR).into_iter().flatten() {
+        let (id, value) = parse_input(s)?;
+        match inputs.get_mut(id) {
+            Some(v) => v.push(value),
+            None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("there is no party {}", id)).into()),
+        }
+    }
+
+    let mut faults = FaultConfig::default();
+    if let Some(ms) = matches.value_of(DELAY_STR) {
+        faults.delay = Duration::from_millis(u64::from_str(ms)?);
+    }
+    if let Some(rate) = matches.value_of(DROP_STR) {
+        faults.drop_rate = f64::from_str(rate)?;
+    }
+    if let Some(rate) = matches.value_of(CORRUPT_STR) {
+        faults.corrupt_rate = f64::from_str(rate)?;
+    }
+    if let Some(rate) = matches.value_of(REORDER_STR) {
+        faults.reorder_rate = f64::from_str(rate)?;
+    }
+    if let Some(k) = matches.value_of(DISCONNECT_STR) {
+        faults.disconnect_after = Some(usize::from_str(k)?);
+    }
+
+    let report = sim::simulate_with_faults(n, prog, inputs, faults)?;
+    for (k, output) in report.outputs.iter().enumerate() {
+        match output {
+            Some(x) => println!("output {}: {}", k, x.to_string()),
+            None => println!("output {}: differs between the parties", k),
+        }
+    }
+    for (i, (outputs, stats)) in report.party_outputs.iter().zip(&report.stats).enumerate() {
+        let outputs: Vec<String> = outputs.iter().map(|x| x.to_string()).collect();
+        println!(
+            "party {}: sent {} messages ({} bytes), received {} messages ({} bytes), outputs [{}]",
+            i,
+            stats.sent,
+            stats.bytes_sent,
+            stats.received,
+            stats.bytes_received,
+            outputs.join(", ")
+        );
+    }
+    println!(
+        "every party received {} triples and {} random sharings, the computation took {:?}",
+        report.triples, report.rand_shares, report.elapsed
+    );
+    Ok(())
+}
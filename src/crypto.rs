@@ -113,6 +113,30 @@ pub fn auth_triple(n: usize, alpha: &Fp, rng: &mut impl Rng) -> (Vec<AuthShare>,
     )
 }
 
+/// Generate the messages of a random sharing for `n` parties where only the party `owner` knows the random value,
+/// the message of party `i` is at index `i`.
+pub fn gen_fake_rand_share(n: usize, alpha: &Fp, owner: PartyID, seq: u64, rng: &mut impl Rng) -> Vec<RandShareMsg> {
+    let r: Fp = Fp::random(rng);
+    auth_share(&r, n, alpha, rng)
+        .into_iter()
+        .enumerate()
+        .map(|(i, share)| RandShareMsg {
+            seq,
+            share,
+            clear: if owner as usize == i { Some(r.clone()) } else { None },
+            party_id: owner,
+        })
+        .collect()
+}
+
+/// Generate the messages of a random triple for `n` parties, the message of party `i` is at index `i`.
+pub fn gen_fake_triple(n: usize, alpha: &Fp, seq: u64, rng: &mut impl Rng) -> Vec<TripleMsg> {
+    let (triple_a, triple_b, triple_c) = auth_triple(n, alpha, rng);
+    multizip((triple_a, triple_b, triple_c))
+        .map(|(a, b, c)| TripleMsg { seq, a, b, c })
+        .collect()
+}
+
 // The first dimension should be the number of preprocessing elements,
 // the second dimension should be the party size.
 pub fn gen_fake_prep(
@@ -122,37 +146,11 @@ pub fn gen_fake_prep(
     triple_count: usize,
     rng: &mut impl Rng,
 ) -> (Vec<Vec<RandShareMsg>>, Vec<Vec<TripleMsg>>) {
-    // write the random shares
-    let mut rand_share_out = Vec::new();
-    for clear_id in 0..n {
-        for _ in 0..rand_count_per_party {
-            let r: Fp = Fp::random(rng);
-            let auth_shares = auth_share(&r, n, &alpha, rng);
-            let rand_shares: Vec<_> = auth_shares
-                .iter()
-                .enumerate()
-                .map(|(i, share)| RandShareMsg {
-                    share: share.clone(),
-                    clear: if clear_id == i { Some(r.clone()) } else { None },
-                    party_id: clear_id as PartyID,
-                })
-                .collect();
-            rand_share_out.push(rand_shares);
-        }
-    }
-
-    // write the triples
-    let mut triple_out = Vec::new();
-    for _ in 0..triple_count {
-        let (triple_a, triple_b, triple_c) = auth_triple(n, &alpha, rng);
-        let mut tmp = Vec::new();
-        tmp.reserve_exact(n);
-        for (a, b, c) in multizip((triple_a, triple_b, triple_c)) {
-            tmp.push(TripleMsg { a, b, c });
-        }
-        triple_out.push(tmp);
-    }
-
+    let rand_share_out = (0..n)
+        .flat_map(|owner| (0..rand_count_per_party).map(move |seq| (owner, seq)))
+        .map(|(owner, seq)| gen_fake_rand_share(n, alpha, owner as PartyID, seq as u64, rng))
+        .collect();
+    let triple_out = (0..triple_count).map(|seq| gen_fake_triple(n, alpha, seq as u64, rng)).collect();
     (rand_share_out, triple_out)
 }
 
@@ -161,13 +159,43 @@ pub mod commit {
 
     use bincode;
     use rand::Rng;
-    use serde::{Deserialize, Serialize};
+    use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
     use sha3;
     use sha3::Digest;
     use std::fmt;
 
+    // In human readable formats such as RON, the commitments and the openings are stored as base64 strings
+    // of their bincode encoding, otherwise the derived implementation (`remote = "Self"`) is used.
+    macro_rules! impl_base64_serde {
+        ($t:ty) => {
+            impl Serialize for $t {
+                fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+                    if s.is_human_readable() {
+                        let v = bincode::serialize(self).map_err(ser::Error::custom)?;
+                        s.serialize_str(&base64::encode(v))
+                    } else {
+                        <$t>::serialize(self, s)
+                    }
+                }
+            }
+
+            impl<'de> Deserialize<'de> for $t {
+                fn deserialize<D: Deserializer<'de>>(d: D) -> Result<$t, D::Error> {
+                    if d.is_human_readable() {
+                        let s = String::deserialize(d)?;
+                        let v = base64::decode(s).map_err(de::Error::custom)?;
+                        bincode::deserialize(&v).map_err(de::Error::custom)
+                    } else {
+                        <$t>::deserialize(d)
+                    }
+                }
+            }
+        };
+    }
+
     /// This is the structure that represents a commitment.
-    #[derive(Serialize, Deserialize, Clone)]
+    #[derive(Serialize, Deserialize, Clone, Eq, PartialEq)]
+    #[serde(remote = "Self")]
     pub struct Commitment {
         c: [u8; 32],
     }
@@ -182,11 +210,15 @@ pub mod commit {
 
     /// This is the structure that represents an opening of a commitment.
     #[derive(Serialize, Deserialize, Clone)]
+    #[serde(remote = "Self")]
     pub struct Opening {
         v: Fp,
         r: [u8; 32],
     }
 
+    impl_base64_serde!(Commitment);
+    impl_base64_serde!(Opening);
+
     impl fmt::Debug for Opening {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
             f.debug_struct("Opening")
@@ -459,4 +491,18 @@ mod tests {
         let (_, bad_opening) = scheme.commit(secret_bad, rng);
         !scheme.verify(&bad_opening, &commitment)
     }
+
+    #[quickcheck]
+    fn prop_commitment_serde(secret: Fp) -> bool {
+        let rng = &mut ChaCha20Rng::from_seed(TEST_SEED);
+        let scheme = commit::Scheme {};
+        let (commitment, opening) = scheme.commit(secret, rng);
+
+        // base64 strings in ron, bytes in bincode
+        let ron_str = ron::to_string(&(&commitment, &opening)).unwrap();
+        let (c1, o1): (commit::Commitment, commit::Opening) = ron::from_str(&ron_str).unwrap();
+        let buf = bincode::serialize(&(&commitment, &opening)).unwrap();
+        let (c2, o2): (commit::Commitment, commit::Opening) = bincode::deserialize(&buf).unwrap();
+        ron_str.starts_with("(\"") && c1 == commitment && c2 == commitment && scheme.verify(&o1, &c1) && scheme.verify(&o2, &c2)
+    }
 }
@@ -8,21 +8,100 @@ use base64::DecodeError;
 use bincode;
 use crossbeam::channel;
 use ron;
+use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::net::AddrParseError;
-use std::num::ParseIntError;
+use std::num::{ParseFloatError, ParseIntError};
 use std::time::Duration;
 use thiserror::Error;
 
-pub(crate) const TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_OPEN_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_SYNC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `Timeouts` decides how long we wait before giving up, for every class of operation.
+/// They are serialized in milliseconds.
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
+#[serde(default)]
+pub struct Timeouts {
+    /// Waiting for the synchronizer, or for the synchronizer, waiting for the parties to finish an instruction.
+    /// It must be larger than `open`, because an instruction such as the MAC check runs several rounds
+    /// that are each bounded by `open`.
+    #[serde(with = "duration_ms")]
+    pub sync: Duration,
+    /// Waiting for the messages of the other parties, e.g., when opening a value.
+    #[serde(with = "duration_ms")]
+    pub open: Duration,
+    /// Waiting for the preprocessing data.
+    #[serde(with = "duration_ms")]
+    pub prep: Duration,
+    /// Waiting for a broken link between two parties to be connected again, see the `resume` module.
+    /// It must be smaller than `open`, because the computation only carries on
+    /// if the link is back before the `open` timeout expires.
+    #[serde(with = "duration_ms")]
+    pub reconnect: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            sync: DEFAULT_SYNC_TIMEOUT,
+            open: DEFAULT_OPEN_TIMEOUT,
+            prep: DEFAULT_TIMEOUT,
+            reconnect: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+impl Timeouts {
+    /// Check that the timeouts are ordered as described on the fields, i.e., `reconnect < open < sync`.
+    pub fn check(&self) -> Result<(), std::io::Error> {
+        let msg = if self.reconnect >= self.open {
+            "the reconnect timeout must be smaller than the open timeout"
+        } else if self.open >= self.sync {
+            "the open timeout must be smaller than the sync timeout"
+        } else {
+            return Ok(());
+        };
+        Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg))
+    }
+}
+
+mod duration_ms {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub(crate) fn serialize<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(d.as_millis() as u64)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        u64::deserialize(d).map(Duration::from_millis)
+    }
+}
 
 /// `MACCheckError` describes the different failure states when checking a MAC.
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+/// A bad commitment identifies the parties that cheated,
+/// but a sum that is not zero only tells us that some party cheated.
+/// Identifying the cheater in the latter case needs an identifiable-abort MAC check
+/// on top of an authenticated broadcast, which is not implemented.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
 pub enum MACCheckError {
-    BadCommitment,
+    /// The openings of these parties do not match their commitments.
+    BadCommitment(Vec<message::PartyID>),
     SumIsNotZero,
 }
 
+impl MACCheckError {
+    /// Return the parties that are known to have cheated.
+    pub fn culprits(&self) -> &[message::PartyID] {
+        match self {
+            MACCheckError::BadCommitment(ids) => ids,
+            MACCheckError::SumIsNotZero => &[],
+        }
+    }
+}
+
 impl fmt::Display for MACCheckError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "output failed with error {:?}", self)
@@ -31,6 +110,83 @@ impl fmt::Display for MACCheckError {
 
 impl std::error::Error for MACCheckError {}
 
+/// `AbortReason` describes why a party aborted the computation,
+/// it is sent to the other parties and the synchronizer so that they can stop too.
+#[derive(Error, Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub enum AbortReason {
+    #[error(transparent)]
+    MACCheck(#[from] MACCheckError),
+    #[error("party {0} sent an unexpected message")]
+    UnexpectedMessage(message::PartyID),
+    #[error("the synchronizer sent an unexpected message")]
+    UnexpectedSyncMessage,
+    #[error("a party sent different messages to different parties")]
+    Equivocation,
+    /// These parties run a different program than the party that aborted.
+    #[error("parties {0:?} run a different program")]
+    ProgramMismatch(Vec<message::PartyID>),
+    /// These parties used different triples or random sharings than the party that aborted, see `message::PrepSeqs`.
+    #[error("parties {0:?} used different preprocessing data")]
+    PrepMismatch(Vec<message::PartyID>),
+    /// Another party or the synchronizer told us about an abort that we did not see ourselves,
+    /// the reason cannot be verified so it does not blame anyone.
+    #[error("relayed abort: {0}")]
+    Relayed(Box<AbortReason>),
+}
+
+impl AbortReason {
+    /// Return the parties that are known to have cheated, an operator may exclude them from the next computation.
+    pub fn culprits(&self) -> &[message::PartyID] {
+        match self {
+            AbortReason::MACCheck(e) => e.culprits(),
+            AbortReason::UnexpectedMessage(id) => std::slice::from_ref(id),
+            // a different program or different preprocessing data is a misconfiguration,
+            // and from the view of the odd party it is the others that differ
+            AbortReason::UnexpectedSyncMessage
+            | AbortReason::Equivocation
+            | AbortReason::ProgramMismatch(_)
+            | AbortReason::PrepMismatch(_)
+            | AbortReason::Relayed(_) => &[],
+        }
+    }
+
+    /// Return the reason as it is seen by a party that received it from someone else.
+    pub(crate) fn relayed(self) -> AbortReason {
+        match self {
+            r @ AbortReason::Relayed(_) => r,
+            r => AbortReason::Relayed(Box::new(r)),
+        }
+    }
+}
+
+/// `TransportError` describes why a message could not be delivered to or received from a peer.
+#[derive(Error, Copy, Clone, Eq, PartialEq, Debug)]
+pub enum TransportError {
+    #[error("unknown party {0}")]
+    UnknownParty(message::PartyID),
+    #[error("timed out waiting for party {0}")]
+    Timeout(message::PartyID),
+    #[error("connection with party {0} is closed")]
+    Disconnected(message::PartyID),
+    #[error("malformed message for party {0}")]
+    BadMessage(message::PartyID),
+}
+
+/// `FrameError` describes how a peer violated the framing of the messages on a stream, see the `frame` module.
+#[derive(Error, Copy, Clone, Eq, PartialEq, Debug)]
+pub enum FrameError {
+    #[error("unsupported frame version {0}")]
+    BadVersion(u8),
+    #[error("expected a message with tag {expected} but got tag {got}")]
+    BadTag { expected: u8, got: u8 },
+    #[error("frame of {0} bytes is too large")]
+    TooLarge(u64),
+    #[error("malformed payload")]
+    BadPayload,
+    #[error("payload failed authentication")]
+    Unauthenticated,
+}
+
 /// `MPCError` is a wrapper for all the errors in this software to make error handling easier.
 /// We do not use a generic parameter for the `SendError`s
 /// so that functions that return `Result` also do not need a generic parameter,
@@ -41,19 +197,23 @@ pub enum MPCError {
     EmptyError,
     #[error("cannot create register")]
     RegCreationError,
+    #[error("expected preprocessing element {expected} but got {got}")]
+    PrepSeqError { expected: u64, got: u64 },
+    #[error("the preprocessing data for {0:?} was already consumed")]
+    PrepConsumedError(message::PrepRequest),
     #[error(transparent)]
     MACCheckError(#[from] MACCheckError),
+    #[error("aborted by party {by}: {reason}")]
+    Aborted { by: message::PartyID, reason: AbortReason },
+    #[error(transparent)]
+    TransportError(#[from] TransportError),
     #[error(transparent)]
     RecvError(#[from] channel::RecvError),
     #[error(transparent)]
     RecvTimeoutError(#[from] channel::RecvTimeoutError),
     #[error(transparent)]
-    SendErrorSyncMsg(#[from] channel::SendError<message::SyncMsg>),
-    #[error(transparent)]
     SendErrorSyncReplyMsg(#[from] channel::SendError<message::SyncReplyMsg>),
     #[error(transparent)]
-    SendErrorNodeMsg(#[from] channel::SendError<message::PartyMsg>),
-    #[error(transparent)]
     SendErrorInputRandMsg(#[from] channel::SendError<message::RandShareMsg>),
     #[error(transparent)]
     SendErrorAction(#[from] channel::SendError<vm::Action>),
@@ -63,10 +223,6 @@ pub enum MPCError {
     SendErrorFp(#[from] channel::SendError<Fp>),
     #[error(transparent)]
     SendErrorOutputResult(#[from] channel::SendError<Result<(), MACCheckError>>),
-    #[error(transparent)]
-    TrySendErrorTriple(#[from] channel::TrySendError<message::TripleMsg>),
-    #[error(transparent)]
-    TrySendErrorRandShareMsg(#[from] channel::TrySendError<message::RandShareMsg>),
 }
 
 #[derive(Error, Debug)]
@@ -80,6 +236,8 @@ pub enum ApplicationError {
     #[error(transparent)]
     ParseIntError(#[from] ParseIntError),
     #[error(transparent)]
+    ParseFloatError(#[from] ParseFloatError),
+    #[error(transparent)]
     AddrParseError(#[from] AddrParseError),
     #[error(transparent)]
     Base64Error(#[from] DecodeError),
@@ -0,0 +1,310 @@
+//! This module injects network faults into a transport, it is used to test how the parties behave
+//! when the links are slow, lose messages, reorder them, break or deliver garbage.
+//! A failure that is injected is reported by the transport as a `TransportError`,
+//! so it reaches the caller as an error instead of a panic.
+//! The faults on the wire are injected into a stream by `fault_stream`,
+//! so that they go through the framing and the decryption of the `io` module.
+
+use crate::error::TransportError;
+use crate::message::PartyID;
+use crate::net::Stream;
+use crate::transport::Transport;
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// `FaultConfig` describes the faults that a `FaultTransport` injects, the default injects no fault.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct FaultConfig {
+    /// Every received message is delayed by this duration.
+    pub delay: Duration,
+    /// The probability that a sent message is lost.
+    pub drop_rate: f64,
+    /// The probability that one bit of a received message is flipped.
+    pub corrupt_rate: f64,
+    /// The probability that a received message is delivered after the next message from the same peer,
+    /// if the next message arrived already.
+    pub reorder_rate: f64,
+    /// The probability that one bit of a byte received on the wire is flipped, it only applies to `fault_stream`.
+    pub wire_corrupt_rate: f64,
+    /// The transport is disconnected after sending this many messages.
+    pub disconnect_after: Option<usize>,
+}
+
+fn happens<G: Rng>(rng: &mut G, rate: f64) -> bool {
+    rate > 0.0 && rng.gen_bool(rate.min(1.0))
+}
+
+/// A transport that injects the faults in `conf` into the messages of the inner transport.
+pub struct FaultTransport<T> {
+    inner: T,
+    conf: FaultConfig,
+    sent: AtomicUsize,
+    rng: Mutex<ChaCha20Rng>,
+    // the serialized messages that are held back to be delivered after the next one, indexed by the sender
+    held: Mutex<HashMap<PartyID, Vec<u8>>>,
+}
+
+impl<T> FaultTransport<T> {
+    pub fn new(inner: T, conf: FaultConfig) -> FaultTransport<T> {
+        FaultTransport {
+            inner,
+            conf,
+            sent: AtomicUsize::new(0),
+            rng: Mutex::new(ChaCha20Rng::from_entropy()),
+            held: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn disconnected(&self) -> bool {
+        match self.conf.disconnect_after {
+            Some(k) => self.sent.load(Ordering::SeqCst) >= k,
+            None => false,
+        }
+    }
+
+    fn happens(&self, rate: f64) -> bool {
+        happens(&mut *self.rng.lock().expect("rng lock poisoned"), rate)
+    }
+
+    // receive the next message from `from`, the message is swapped with the one after it if it is reordered
+    fn recv_reordered<S, R>(&self, from: PartyID, timeout: Duration) -> Result<R, TransportError>
+    where
+        R: Serialize + DeserializeOwned,
+        T: Transport<S, R>,
+    {
+        if let Some(data) = self.held.lock().expect("held lock poisoned").remove(&from) {
+            return bincode::deserialize(&data).map_err(|_| TransportError::BadMessage(from));
+        }
+        let m = self.inner.recv_from(from, timeout)?;
+        if !self.happens(self.conf.reorder_rate) {
+            return Ok(m);
+        }
+        // the message is delivered in order if the next message did not arrive yet
+        match self.inner.recv_from(from, Duration::from_secs(0)) {
+            Ok(next) => {
+                let data = bincode::serialize(&m).map_err(|_| TransportError::BadMessage(from))?;
+                self.held.lock().expect("held lock poisoned").insert(from, data);
+                Ok(next)
+            }
+            Err(_) => Ok(m),
+        }
+    }
+
+    // flip a random bit of the serialized message, the result might not deserialize anymore
+    fn corrupt<R: Serialize + DeserializeOwned>(&self, from: PartyID, m: R) -> Result<R, TransportError> {
+        let mut data = bincode::serialize(&m).map_err(|_| TransportError::BadMessage(from))?;
+        if data.is_empty() {
+            return Ok(m);
+        }
+        let (i, bit) = {
+            let rng = &mut *self.rng.lock().expect("rng lock poisoned");
+            (rng.gen_range(0..data.len()), rng.gen_range(0..8))
+        };
+        data[i] ^= 1 << bit;
+        bincode::deserialize(&data).map_err(|_| TransportError::BadMessage(from))
+    }
+}
+
+impl<S, R, T> Transport<S, R> for FaultTransport<T>
+where
+    R: Serialize + DeserializeOwned,
+    T: Transport<S, R>,
+{
+    fn peers(&self) -> usize {
+        self.inner.peers()
+    }
+
+    fn send(&self, to: PartyID, m: S) -> Result<(), TransportError> {
+        if self.disconnected() {
+            return Err(TransportError::Disconnected(to));
+        }
+        self.sent.fetch_add(1, Ordering::SeqCst);
+        if self.happens(self.conf.drop_rate) {
+            return Ok(());
+        }
+        self.inner.send(to, m)
+    }
+
+    fn recv_from(&self, from: PartyID, timeout: Duration) -> Result<R, TransportError> {
+        if self.disconnected() {
+            return Err(TransportError::Disconnected(from));
+        }
+        let m = self.recv_reordered(from, timeout)?;
+        thread::sleep(self.conf.delay);
+        if self.happens(self.conf.corrupt_rate) {
+            self.corrupt(from, m)
+        } else {
+            Ok(m)
+        }
+    }
+}
+
+/// Put a proxy in front of `stream` that injects the wire faults in `conf` into the bytes that are received on it,
+/// the other faults are ignored.
+/// The returned stream is used in place of `stream`, the proxy stops when either side is closed.
+pub fn fault_stream(stream: Stream, conf: FaultConfig) -> io::Result<Stream> {
+    let (inner, outer) = UnixStream::pair()?;
+    let (mut from_peer, mut to_peer) = (stream.try_clone()?, stream);
+    let (mut to_local, mut from_local) = (inner.try_clone()?, inner);
+    thread::spawn(move || {
+        let rng = &mut ChaCha20Rng::from_entropy();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = match from_peer.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            for b in &mut buf[..n] {
+                if happens(rng, conf.wire_corrupt_rate) {
+                    *b ^= 1 << rng.gen_range(0..8);
+                }
+            }
+            if to_local.write_all(&buf[..n]).is_err() {
+                break;
+            }
+        }
+        let _ = to_local.shutdown(Shutdown::Write);
+    });
+    thread::spawn(move || {
+        let _ = io::copy(&mut from_local, &mut to_peer);
+        // this also stops the other direction
+        let _ = to_peer.shutdown(Shutdown::Both);
+    });
+    Ok(Stream::Unix(outer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::ChanTransport;
+    use std::time::Instant;
+
+    const TEST_CAP: usize = 5;
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    fn single(conf: FaultConfig) -> FaultTransport<ChanTransport<u64, u64>> {
+        FaultTransport::new(ChanTransport::mesh(1, TEST_CAP).pop().unwrap(), conf)
+    }
+
+    #[test]
+    fn test_no_faults() {
+        let t = single(FaultConfig::default());
+        for i in 0..TEST_CAP as u64 {
+            t.send(0, i).unwrap();
+        }
+        for i in 0..TEST_CAP as u64 {
+            assert_eq!(t.recv_from(0, TIMEOUT).unwrap(), i);
+        }
+    }
+
+    #[test]
+    fn test_faults() {
+        let delay = Duration::from_millis(20);
+        let t = single(FaultConfig { delay, ..Default::default() });
+        let start = Instant::now();
+        t.send(0, 1).unwrap();
+        assert_eq!(t.recv_from(0, TIMEOUT).unwrap(), 1);
+        assert!(start.elapsed() >= delay);
+
+        let t = single(FaultConfig {
+            drop_rate: 1.0,
+            ..Default::default()
+        });
+        t.send(0, 1).unwrap();
+        assert_eq!(t.recv_from(0, TIMEOUT).unwrap_err(), TransportError::Timeout(0));
+
+        // a u64 deserializes from any 8 bytes, so a flipped bit gives a different value
+        let t = single(FaultConfig {
+            corrupt_rate: 1.0,
+            ..Default::default()
+        });
+        t.send(0, 1).unwrap();
+        assert_ne!(t.recv_from(0, TIMEOUT).unwrap(), 1);
+
+        let t = single(FaultConfig {
+            disconnect_after: Some(1),
+            ..Default::default()
+        });
+        t.send(0, 1).unwrap();
+        assert_eq!(t.send(0, 2).unwrap_err(), TransportError::Disconnected(0));
+        assert_eq!(t.recv_from(0, TIMEOUT).unwrap_err(), TransportError::Disconnected(0));
+    }
+
+    #[test]
+    fn test_reorder() {
+        let t = single(FaultConfig {
+            reorder_rate: 1.0,
+            ..Default::default()
+        });
+        for i in 0..4 {
+            t.send(0, i).unwrap();
+        }
+        let received: Vec<u64> = (0..4).map(|_| t.recv_from(0, TIMEOUT).unwrap()).collect();
+        assert_eq!(received, vec![1, 0, 3, 2]);
+
+        // the last message cannot be swapped, so it is delivered in order
+        t.send(0, 4).unwrap();
+        assert_eq!(t.recv_from(0, TIMEOUT).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_fault_stream() {
+        let (mut peer, local) = UnixStream::pair().unwrap();
+        let mut s = fault_stream(Stream::Unix(local), FaultConfig::default()).unwrap();
+        peer.write_all(&[1, 2, 3]).unwrap();
+        s.write_all(&[4, 5]).unwrap();
+        let mut buf = [0u8; 3];
+        s.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+        peer.read_exact(&mut buf[..2]).unwrap();
+        assert_eq!(buf[..2], [4, 5]);
+
+        // closing the stream closes the peer
+        s.shutdown(Shutdown::Both).unwrap();
+        assert_eq!(peer.read(&mut buf).unwrap(), 0);
+
+        // every received byte is corrupted, the sent bytes are not
+        let (mut peer, local) = UnixStream::pair().unwrap();
+        let conf = FaultConfig {
+            wire_corrupt_rate: 1.0,
+            ..Default::default()
+        };
+        let mut s = fault_stream(Stream::Unix(local), conf).unwrap();
+        peer.write_all(&[1, 2, 3]).unwrap();
+        s.write_all(&[4, 5]).unwrap();
+        s.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().zip([1u8, 2, 3]).all(|(x, y)| (x ^ y).count_ones() == 1));
+        peer.read_exact(&mut buf[..2]).unwrap();
+        assert_eq!(buf[..2], [4, 5]);
+    }
+
+    #[test]
+    fn test_corrupt_bad_message() {
+        // a bool only deserializes from 0 or 1, so most flipped bits give a malformed message
+        let t = FaultTransport::new(
+            ChanTransport::mesh(1, TEST_CAP).pop().unwrap(),
+            FaultConfig {
+                corrupt_rate: 1.0,
+                ..Default::default()
+            },
+        );
+        let results: Vec<Result<bool, TransportError>> = (0..TEST_CAP)
+            .map(|_| {
+                t.send(0, false).unwrap();
+                t.recv_from(0, TIMEOUT)
+            })
+            .collect();
+        assert!(results.iter().all(|res| matches!(res, Ok(true) | Err(TransportError::BadMessage(0)))));
+    }
+}
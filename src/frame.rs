@@ -0,0 +1,182 @@
+//! This module defines how messages are framed on a stream.
+//! Every frame starts with a header that holds the version of the framing,
+//! a tag for the type of the message and the length of the payload, followed by the payload.
+//! A frame that does not follow these rules is a protocol violation,
+//! it is reported as an `io::Error` of kind `InvalidData` that wraps a `FrameError`.
+
+use crate::error::FrameError;
+use crate::message::{PartyMsg, PrepBatch, PrepRequest, SyncMsg, SyncReplyMsg};
+
+use byteorder::{ByteOrder, LittleEndian};
+use serde::de::DeserializeOwned;
+use std::io;
+
+/// The version of the framing, it changes when the header or the encoding of the payload changes.
+pub const FRAME_VERSION: u8 = 1;
+/// The length of the header in bytes.
+pub const HEADER_LEN: usize = 10;
+/// The largest payload that we accept by default, it is large enough for a full batch of preprocessing data.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1 << 20;
+
+/// A message type that can be sent in a frame.
+pub trait Tagged {
+    /// The tag that identifies the message type on the wire.
+    const TAG: u8;
+}
+
+impl Tagged for SyncMsg {
+    const TAG: u8 = 1;
+}
+
+impl Tagged for SyncReplyMsg {
+    const TAG: u8 = 2;
+}
+
+impl Tagged for PartyMsg {
+    const TAG: u8 = 3;
+}
+
+impl Tagged for PrepRequest {
+    const TAG: u8 = 4;
+}
+
+impl Tagged for PrepBatch {
+    const TAG: u8 = 5;
+}
+
+/// The header of a frame.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Header {
+    pub version: u8,
+    pub tag: u8,
+    pub len: u64,
+}
+
+impl Header {
+    /// Create the header of a frame in the current version.
+    pub fn new(tag: u8, len: usize) -> Header {
+        Header {
+            version: FRAME_VERSION,
+            tag,
+            len: len as u64,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut buf = [0u8; HEADER_LEN];
+        buf[0] = self.version;
+        buf[1] = self.tag;
+        LittleEndian::write_u64(&mut buf[2..], self.len);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; HEADER_LEN]) -> Header {
+        Header {
+            version: buf[0],
+            tag: buf[1],
+            len: LittleEndian::read_u64(&buf[2..]),
+        }
+    }
+
+    /// Check that the frame carries a message with the tag `tag` and that it is not larger than `max_frame_size`,
+    /// the length of the payload is returned.
+    /// The header is checked before the payload is read, so a peer cannot make us allocate a large buffer.
+    pub fn check(&self, tag: u8, max_frame_size: usize) -> Result<usize, FrameError> {
+        if self.version != FRAME_VERSION {
+            return Err(FrameError::BadVersion(self.version));
+        }
+        if self.tag != tag {
+            return Err(FrameError::BadTag {
+                expected: tag,
+                got: self.tag,
+            });
+        }
+        if self.len > max_frame_size as u64 {
+            return Err(FrameError::TooLarge(self.len));
+        }
+        Ok(self.len as usize)
+    }
+}
+
+impl From<FrameError> for io::Error {
+    fn from(e: FrameError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Return the protocol violation in `e`, if there is one.
+pub fn violation(e: &io::Error) -> Option<FrameError> {
+    e.get_ref().and_then(|inner| inner.downcast_ref::<FrameError>()).copied()
+}
+
+/// Write `data` as the payload of a frame with the tag `tag`.
+pub fn write_frame<W: io::Write>(writer: &mut W, tag: u8, data: &[u8]) -> io::Result<()> {
+    writer.write_all(&Header::new(tag, data.len()).to_bytes())?;
+    writer.write_all(data)
+}
+
+/// Read the payload of the next frame, which must have the tag `tag` and must not be larger than `max_frame_size`.
+pub fn read_frame<R: io::Read>(reader: &mut R, tag: u8, max_frame_size: usize) -> io::Result<Vec<u8>> {
+    let mut header = [0u8; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    let n = Header::from_bytes(&header).check(tag, max_frame_size)?;
+    let mut data = vec![0u8; n];
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Deserialize the payload of a frame, a malformed payload is a protocol violation.
+pub fn deserialize<T: DeserializeOwned>(data: &[u8]) -> io::Result<T> {
+    bincode::deserialize(data).map_err(|_| FrameError::BadPayload.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const TAG: u8 = 42;
+    const MAX: usize = 16;
+
+    fn read(buf: Vec<u8>) -> io::Result<Vec<u8>> {
+        read_frame(&mut Cursor::new(buf), TAG, MAX)
+    }
+
+    #[test]
+    fn test_frame() {
+        let mut buf = vec![];
+        write_frame(&mut buf, TAG, b"hello").unwrap();
+        write_frame(&mut buf, TAG, b"").unwrap();
+        let mut reader = Cursor::new(buf);
+        assert_eq!(read_frame(&mut reader, TAG, MAX).unwrap(), b"hello");
+        assert_eq!(read_frame(&mut reader, TAG, MAX).unwrap(), b"");
+        assert_eq!(read_frame(&mut reader, TAG, MAX).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_bad_frame() {
+        let mut buf = vec![];
+        write_frame(&mut buf, TAG + 1, b"hello").unwrap();
+        let e = read(buf).unwrap_err();
+        assert_eq!(violation(&e), Some(FrameError::BadTag { expected: TAG, got: TAG + 1 }));
+
+        let mut buf = vec![];
+        write_frame(&mut buf, TAG, b"hello").unwrap();
+        buf[0] = FRAME_VERSION + 1;
+        assert_eq!(violation(&read(buf).unwrap_err()), Some(FrameError::BadVersion(FRAME_VERSION + 1)));
+
+        // the length is checked before anything is allocated
+        let buf = Header::new(TAG, usize::MAX).to_bytes().to_vec();
+        assert_eq!(violation(&read(buf).unwrap_err()), Some(FrameError::TooLarge(u64::MAX)));
+
+        // a truncated frame is not a violation, the stream is just closed
+        let mut buf = vec![];
+        write_frame(&mut buf, TAG, b"hello").unwrap();
+        buf.pop();
+        assert_eq!(violation(&read(buf).unwrap_err()), None);
+
+        // a bool is encoded as one byte that is 0 or 1
+        assert_eq!(violation(&deserialize::<bool>(&[2]).unwrap_err()), Some(FrameError::BadPayload));
+        assert!(!deserialize::<bool>(&[0]).unwrap());
+    }
+}
@@ -0,0 +1,182 @@
+//! This module implements the hello message that two nodes exchange when they connect.
+//! It follows the authentication, so that only an authenticated peer can stop the cluster formation.
+//! It holds the version of the software, the field modulus, the number of parties and the hash of the program,
+//! and the header of its frame holds the version of the wire protocol, so that nodes that cannot work together are rejected with a clear error
+//! instead of failing later when a message cannot be deserialized.
+
+use crate::algebra::FIELD_MODULUS;
+use crate::frame::{self, Header, Tagged, FRAME_VERSION, HEADER_LEN};
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use thiserror::Error;
+
+// the hello message is small, so we do not accept large frames from a peer that may not be authenticated
+const MAX_HELLO_SIZE: usize = 1024;
+
+/// The version of the software that runs on this node.
+pub const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// `HelloError` describes why the hello message of a peer is not compatible with ours.
+#[derive(Error, Clone, Eq, PartialEq, Debug)]
+pub enum HelloError {
+    #[error("the peer runs ezmpc {theirs} but we run ezmpc {ours}")]
+    CrateVersion { ours: String, theirs: String },
+    #[error("the peer uses framing version {theirs} but we use version {ours}")]
+    FrameVersion { ours: u8, theirs: u8 },
+    #[error("the peer uses the field modulus {theirs} but we use {ours}")]
+    FieldModulus { ours: String, theirs: String },
+    #[error("the peer expects {theirs} parties but we expect {ours}")]
+    PartyCount { ours: u32, theirs: u32 },
+    #[error("the peer runs a different program")]
+    ProgramHash,
+}
+
+impl From<HelloError> for io::Error {
+    fn from(e: HelloError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// The hello message.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct Hello {
+    pub crate_version: String,
+    pub field_modulus: String,
+    pub parties: u32,
+    /// The hash of the program, see `vm::prog_hash`.
+    /// It is missing if the node does not run a program, e.g., the synchronizer.
+    pub prog_hash: Option<[u8; 32]>,
+}
+
+impl Tagged for Hello {
+    const TAG: u8 = 6;
+}
+
+impl Hello {
+    /// Create the hello message of this node.
+    pub fn new(parties: usize, prog_hash: Option<[u8; 32]>) -> Hello {
+        Hello {
+            crate_version: CRATE_VERSION.to_string(),
+            field_modulus: FIELD_MODULUS.to_string(),
+            parties: parties as u32,
+            prog_hash,
+        }
+    }
+
+    /// Check that the hello message `theirs` of a peer is compatible with ours,
+    /// the program hashes are only compared if both nodes run a program.
+    pub fn check(&self, theirs: &Hello) -> Result<(), HelloError> {
+        if self.crate_version != theirs.crate_version {
+            return Err(HelloError::CrateVersion {
+                ours: self.crate_version.clone(),
+                theirs: theirs.crate_version.clone(),
+            });
+        }
+        if self.field_modulus != theirs.field_modulus {
+            return Err(HelloError::FieldModulus {
+                ours: self.field_modulus.clone(),
+                theirs: theirs.field_modulus.clone(),
+            });
+        }
+        if self.parties != theirs.parties {
+            return Err(HelloError::PartyCount {
+                ours: self.parties,
+                theirs: theirs.parties,
+            });
+        }
+        match (self.prog_hash, theirs.prog_hash) {
+            (Some(ours), Some(theirs)) if ours != theirs => Err(HelloError::ProgramHash),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Send our hello message `mine` to the peer on the other side of `stream` and check the one that it sends back.
+/// Both sides send their message first, so it does not matter which side made the connection.
+/// An error that wraps a `HelloError` is returned if the peer is not compatible.
+pub fn exchange<S: io::Read + io::Write>(stream: &mut S, mine: &Hello) -> io::Result<Hello> {
+    let data = bincode::serialize(mine).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    frame::write_frame(stream, Hello::TAG, &data)?;
+
+    // the framing version is in the header, which is checked before the payload is decoded
+    let mut header = [0u8; HEADER_LEN];
+    stream.read_exact(&mut header)?;
+    let header = Header::from_bytes(&header);
+    if header.version != FRAME_VERSION {
+        return Err(HelloError::FrameVersion {
+            ours: FRAME_VERSION,
+            theirs: header.version,
+        }
+        .into());
+    }
+    let mut payload = vec![0u8; header.check(Hello::TAG, MAX_HELLO_SIZE)?];
+    stream.read_exact(&mut payload)?;
+
+    let theirs: Hello = frame::deserialize(&payload)?;
+    mine.check(&theirs)?;
+    Ok(theirs)
+}
+
+/// Extract the `HelloError` from an error returned by `exchange`, if there is one.
+pub fn hello_error(e: &io::Error) -> Option<HelloError> {
+    e.get_ref().and_then(|inner| inner.downcast_ref::<HelloError>()).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    fn run(a: Hello, b: Hello) -> (io::Result<Hello>, io::Result<Hello>) {
+        let (mut sa, mut sb) = UnixStream::pair().unwrap();
+        let hdl = thread::spawn(move || exchange(&mut sb, &b));
+        (exchange(&mut sa, &a), hdl.join().unwrap())
+    }
+
+    #[test]
+    fn test_hello() {
+        let hello = Hello::new(3, Some([1u8; 32]));
+        let (ra, rb) = run(hello.clone(), hello.clone());
+        assert_eq!(ra.unwrap(), hello);
+        assert_eq!(rb.unwrap(), hello);
+
+        // a node without a program is compatible with any program
+        let (ra, rb) = run(hello.clone(), Hello::new(3, None));
+        assert!(ra.is_ok() && rb.is_ok());
+
+        // both sides reject a mismatch
+        let (ra, rb) = run(hello.clone(), Hello::new(3, Some([2u8; 32])));
+        assert_eq!(hello_error(&ra.unwrap_err()), Some(HelloError::ProgramHash));
+        assert_eq!(hello_error(&rb.unwrap_err()), Some(HelloError::ProgramHash));
+
+        let (ra, _) = run(hello.clone(), Hello::new(4, Some([1u8; 32])));
+        assert_eq!(hello_error(&ra.unwrap_err()), Some(HelloError::PartyCount { ours: 3, theirs: 4 }));
+
+        // the framing version is checked before the hello message is decoded
+        let (mut sa, mut sb) = UnixStream::pair().unwrap();
+        let mut header = Header::new(Hello::TAG, 0).to_bytes();
+        header[0] = FRAME_VERSION + 1;
+        sb.write_all(&header).unwrap();
+        assert_eq!(
+            hello_error(&exchange(&mut sa, &hello).unwrap_err()),
+            Some(HelloError::FrameVersion {
+                ours: FRAME_VERSION,
+                theirs: FRAME_VERSION + 1
+            })
+        );
+
+        let mut old = hello.clone();
+        old.crate_version = "0.0.1".to_string();
+        let (ra, _) = run(hello.clone(), old);
+        assert_eq!(
+            hello_error(&ra.unwrap_err()),
+            Some(HelloError::CrateVersion {
+                ours: CRATE_VERSION.to_string(),
+                theirs: "0.0.1".to_string()
+            })
+        );
+    }
+}
@@ -1,59 +1,25 @@
-use crossbeam::channel::{bounded, Receiver, Sender};
+use crossbeam::channel::bounded;
 use num_traits::{One, Zero};
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha20Rng;
-use std::thread::JoinHandle;
+use std::time::Duration;
 use test_env_log::test;
 
+use crate::adversary::{AdversaryTransport, Attack};
 use crate::algebra::Fp;
 use crate::crypto::*;
+use crate::error::{AbortReason, MACCheckError, MPCError, Timeouts};
 use crate::message::*;
-use crate::party::Party;
+use crate::optimizer;
+use crate::party::{BroadcastMode, Party};
+use crate::sim::{create_sync_chans, run_parties, spawn_parties, FakePrep, PartyResult};
 use crate::synchronizer::Synchronizer;
+use crate::transport::{ChanTransport, LoggingTransport, LoopbackTransport, Transport};
 use crate::vm::{self, tests::IO_PROG, tests::MUL_PROG};
 
 const TEST_SEED: [u8; 32] = [8u8; 32];
 const TEST_CAP: usize = 5;
 
-fn create_sync_chans(
-    n: usize,
-) -> (
-    (Vec<Sender<SyncMsg>>, Vec<Receiver<SyncReplyMsg>>),
-    (Vec<Sender<SyncReplyMsg>>, Vec<Receiver<SyncMsg>>),
-) {
-    let (from_sync, to_party) = (0..n).map(|_| bounded(TEST_CAP)).unzip();
-    let (from_party, to_sync) = (0..n).map(|_| bounded(TEST_CAP)).unzip();
-    ((from_sync, to_sync), (from_party, to_party))
-}
-
-fn create_party_chans(n: usize) -> Vec<Vec<(Sender<PartyMsg>, Receiver<PartyMsg>)>> {
-    let mut output = Vec::new();
-    for _ in 0..n {
-        let mut row = Vec::new();
-        for _ in 0..n {
-            row.push(bounded(TEST_CAP));
-        }
-        output.push(row);
-    }
-    output
-}
-
-fn create_chans<T>(n: usize, capacity: usize) -> Vec<(Sender<T>, Receiver<T>)> {
-    (0..n).map(|_| bounded(capacity)).collect()
-}
-
-fn get_row<T: Clone>(matrix: &Vec<Vec<T>>, row: usize) -> Vec<T> {
-    matrix[row].clone()
-}
-
-fn get_col<T: Clone>(matrix: &Vec<Vec<T>>, col: usize) -> Vec<T> {
-    let mut out = Vec::new();
-    for row in matrix {
-        out.push(row[col].clone());
-    }
-    out
-}
-
 #[test]
 fn integration_test_clear_add() {
     let (sync_chans_for_sync, sync_chans_for_party) = create_sync_chans(1);
@@ -62,17 +28,17 @@ fn integration_test_clear_add() {
 
     let two = Fp::one() + Fp::one();
     let fake_alpha_share = Fp::zero();
-    let sync_handle = Synchronizer::spawn(sync_chans_for_sync.0, sync_chans_for_sync.1);
+    let sync_handle = Synchronizer::spawn(sync_chans_for_sync, Timeouts::default().sync);
     let party_handle = Party::spawn(
         0,
         fake_alpha_share,
         vm::Reg::from_vec(&vec![Fp::one(), Fp::one()], &vec![]),
         prog,
-        sync_chans_for_party.0[0].clone(),
-        sync_chans_for_party.1[0].clone(),
+        BroadcastMode::Plain,
+        Timeouts::default(),
+        Some((sync_chans_for_party.0[0].clone(), sync_chans_for_party.1[0].clone())),
         preproc_receiver,
-        vec![],
-        vec![],
+        ChanTransport::new(vec![], vec![]),
         Some(TEST_SEED),
     );
 
@@ -104,20 +70,22 @@ fn integration_test_triple() {
     };
     let two = &one + &one;
 
-    preproc_sender.send(PrepMsg::new_triple(zero.clone(), one.clone(), two.clone())).unwrap();
+    preproc_sender
+        .send(PrepMsg::new_triple(0, zero.clone(), one.clone(), two.clone()))
+        .unwrap();
 
     let fake_alpha_share = Fp::zero();
-    let sync_handle = Synchronizer::spawn(sync_chans_for_sync.0, sync_chans_for_sync.1);
+    let sync_handle = Synchronizer::spawn(sync_chans_for_sync, Timeouts::default().sync);
     let party_handle = Party::spawn(
         0,
         fake_alpha_share,
         vm::Reg::empty(),
         prog,
-        sync_chans_for_party.0[0].clone(),
-        sync_chans_for_party.1[0].clone(),
+        BroadcastMode::Plain,
+        Timeouts::default(),
+        Some((sync_chans_for_party.0[0].clone(), sync_chans_for_party.1[0].clone())),
         preproc_receiver,
-        vec![],
-        vec![],
+        ChanTransport::new(vec![], vec![]),
         Some(TEST_SEED),
     );
 
@@ -137,60 +105,30 @@ fn transpose<T: Clone>(v: &Vec<Vec<T>>) -> Vec<Vec<T>> {
 }
 
 fn generic_integration_test(n: usize, prog: Vec<vm::Instruction>, regs: Vec<vm::Reg>, expected: Vec<Fp>, rng: &mut impl Rng) {
-    let (sync_chans_for_sync, sync_chans_for_party) = create_sync_chans(n);
-    let party_chans = create_party_chans(n);
-
-    let alpha: Fp = Fp::random(rng);
-    let alpha_shares = unauth_share(&alpha, n, rng);
-
-    // check how many triples and random shares we need and create a preprocessing channel for it
-    // TODO this is more rand shares than we need, since we're giving every party max_rand_count number of shares
-    let max_rand_count = prog.iter().filter(|i| matches!(i, vm::Instruction::Input(_, _, _))).count();
-    let triple_count = prog.iter().filter(|i| matches!(i, vm::Instruction::Triple(_, _, _))).count();
-    let preproc_chans = create_chans::<PrepMsg>(n, triple_count + max_rand_count * n);
-    let (rand_shares, triples) = gen_fake_prep(n, &alpha, max_rand_count, triple_count, rng);
-
-    for ss in rand_shares {
-        for ((chan, _), s) in preproc_chans.iter().zip(ss) {
-            chan.send(PrepMsg::RandShare(s)).unwrap();
-        }
-    }
-
-    for ss in triples {
-        for ((chan, _), s) in preproc_chans.iter().zip(ss) {
-            chan.send(PrepMsg::Triple(s)).unwrap();
-        }
-    }
-
-    let sync_handle = Synchronizer::spawn(sync_chans_for_sync.0, sync_chans_for_sync.1);
-    // TODO zip auth_shares and regs and iterate
-    let party_handles: Vec<JoinHandle<_>> = (0..n)
-        .map(|i| {
-            let party_handle = Party::spawn(
-                i as PartyID,
-                alpha_shares[i].clone(),
-                regs[i].clone(),
-                prog.clone(),
-                sync_chans_for_party.0[i].clone(),
-                sync_chans_for_party.1[i].clone(),
-                preproc_chans[i].1.clone(),
-                get_row(&party_chans, i).into_iter().map(|(s, _)| s).collect(),
-                get_col(&party_chans, i).into_iter().map(|(_, r)| r).collect(),
-                Some(TEST_SEED),
-            );
-            party_handle
-        })
-        .collect();
+    generic_transport_test(ChanTransport::mesh(n, TEST_CAP), true, BroadcastMode::Plain, prog, regs, expected, rng)
+}
 
-    let mut output_shares = Vec::new();
-    for h in party_handles {
-        output_shares.push(h.join().unwrap().unwrap());
-    }
+/// Run the program on every transport, the parties are driven by a synchronizer if `synchronized` is set.
+fn generic_transport_test<T>(
+    transports: Vec<T>,
+    synchronized: bool,
+    bcast_mode: BroadcastMode,
+    prog: Vec<vm::Instruction>,
+    regs: Vec<vm::Reg>,
+    expected: Vec<Fp>,
+    rng: &mut impl Rng,
+) where
+    T: 'static + Transport<PartyMsg, PartyMsg>,
+{
+    let (results, sync_result) = run_parties(transports, synchronized, bcast_mode, prog, regs, rng);
+    let output_shares: Vec<_> = results.into_iter().map(|res| res.unwrap()).collect();
     assert_eq!(
         expected,
         transpose(&output_shares).iter().map(|shares| unauth_combine(shares)).collect::<Vec<Fp>>()
     );
-    assert_eq!((), sync_handle.join().unwrap().unwrap());
+    if let Some(res) = sync_result {
+        assert_eq!((), res.unwrap());
+    }
 }
 
 #[test]
@@ -228,6 +166,25 @@ fn integration_test_mul() {
     generic_integration_test(n, MUL_PROG.to_vec(), regs, expected, rng);
 }
 
+#[test]
+fn integration_test_many_triples() {
+    // load more triples than the forwarding buffer of the party can hold before the multiplication
+    let n = 3;
+    let rng = &mut ChaCha20Rng::from_seed(TEST_SEED);
+    let input_0 = Fp::random(rng);
+    let input_1 = Fp::random(rng);
+    let expected = vec![&input_0 * &input_1];
+
+    let regs = vec![
+        vm::Reg::from_vec(&vec![input_0, Fp::zero()], &vec![]),
+        vm::Reg::from_vec(&vec![Fp::zero(), input_1], &vec![]),
+        vm::Reg::empty(),
+    ];
+    let mut prog = vec![vm::Instruction::Triple(20, 21, 22); 1100];
+    prog.extend_from_slice(&MUL_PROG);
+    generic_integration_test(n, prog, regs, expected, rng);
+}
+
 #[test]
 fn integration_test_input_output() {
     // TODO this test flaky when turning on RUST_LOG=debug and RUST_BACKTRACE=1
@@ -244,3 +201,323 @@ fn integration_test_input_output() {
     ];
     generic_integration_test(n, IO_PROG.to_vec(), regs, expected, rng);
 }
+
+#[test]
+fn integration_test_optimized() {
+    // the second multiplication by the opened value and the unused subtraction should be optimized away
+    let n = 3;
+    let prog = vec![
+        vm::Instruction::Input(0, 0, 0),
+        vm::Instruction::Input(1, 1, 1),
+        vm::Instruction::SAdd(2, 0, 1),
+        vm::Instruction::SSub(3, 0, 1),
+        vm::Instruction::Open(4, 0),
+        vm::Instruction::MMul(5, 2, 4),
+        vm::Instruction::MMul(6, 2, 4),
+        vm::Instruction::SAdd(7, 5, 6),
+        vm::Instruction::SOutput(7),
+        vm::Instruction::Stop,
+    ];
+    let optimized = optimizer::optimize(&prog);
+    assert_eq!(optimized.len(), prog.len() - 2);
+
+    let rng = &mut ChaCha20Rng::from_seed(TEST_SEED);
+    let input_0 = Fp::random(rng);
+    let input_1 = Fp::random(rng);
+    let two = Fp::one() + Fp::one();
+    let expected = vec![two * (&input_0 + &input_1) * &input_0];
+    let regs = vec![
+        vm::Reg::from_vec(&vec![input_0, Fp::zero()], &vec![]),
+        vm::Reg::from_vec(&vec![Fp::zero(), input_1], &vec![]),
+        vm::Reg::empty(),
+    ];
+    generic_integration_test(n, optimized, regs, expected, rng);
+}
+
+#[test]
+fn integration_test_loopback_transport() {
+    let n = 3;
+    let rng = &mut ChaCha20Rng::from_seed(TEST_SEED);
+    let input_0 = Fp::random(rng);
+    let input_1 = Fp::random(rng);
+    let expected = vec![&input_0 * &input_1];
+
+    let regs = vec![
+        vm::Reg::from_vec(&vec![input_0, Fp::zero()], &vec![]),
+        vm::Reg::from_vec(&vec![Fp::zero(), input_1], &vec![]),
+        vm::Reg::empty(),
+    ];
+    let transports = LoopbackTransport::mesh(n, Duration::from_millis(1))
+        .into_iter()
+        .enumerate()
+        .map(|(i, t)| LoggingTransport::new(t, &format!("party {}", i)))
+        .collect();
+    generic_transport_test(transports, true, BroadcastMode::Plain, MUL_PROG.to_vec(), regs, expected, rng);
+}
+
+#[test]
+fn integration_test_unsynchronized() {
+    let n = 3;
+    let rng = &mut ChaCha20Rng::from_seed(TEST_SEED);
+    let input_0 = Fp::random(rng);
+    let input_1 = Fp::random(rng);
+    let input_2 = Fp::random(rng);
+    let expected = vec![input_0.clone(), input_1.clone(), input_2.clone()];
+    let regs = vec![
+        vm::Reg::from_vec(&vec![input_0, Fp::zero(), Fp::zero()], &vec![]),
+        vm::Reg::from_vec(&vec![Fp::zero(), input_1, Fp::zero()], &vec![]),
+        vm::Reg::from_vec(&vec![Fp::zero(), Fp::zero(), input_2], &vec![]),
+    ];
+    generic_transport_test(
+        ChanTransport::mesh(n, TEST_CAP),
+        false,
+        BroadcastMode::Plain,
+        IO_PROG.to_vec(),
+        regs,
+        expected,
+        rng,
+    );
+
+    // the parties run at different speeds when the links have latency
+    let input_0 = Fp::random(rng);
+    let input_1 = Fp::random(rng);
+    let expected = vec![&input_0 * &input_1];
+    let regs = vec![
+        vm::Reg::from_vec(&vec![input_0, Fp::zero()], &vec![]),
+        vm::Reg::from_vec(&vec![Fp::zero(), input_1], &vec![]),
+        vm::Reg::empty(),
+    ];
+    generic_transport_test(
+        LoopbackTransport::mesh(n, Duration::from_millis(1)),
+        false,
+        BroadcastMode::Plain,
+        MUL_PROG.to_vec(),
+        regs,
+        expected,
+        rng,
+    );
+}
+
+#[test]
+fn integration_test_echo_broadcast() {
+    let n = 3;
+    let rng = &mut ChaCha20Rng::from_seed(TEST_SEED);
+    let input_0 = Fp::random(rng);
+    let input_1 = Fp::random(rng);
+    let expected = vec![&input_0 * &input_1];
+    let regs = vec![
+        vm::Reg::from_vec(&vec![input_0, Fp::zero()], &vec![]),
+        vm::Reg::from_vec(&vec![Fp::zero(), input_1], &vec![]),
+        vm::Reg::empty(),
+    ];
+    generic_transport_test(
+        ChanTransport::mesh(n, TEST_CAP),
+        true,
+        BroadcastMode::Echo,
+        MUL_PROG.to_vec(),
+        regs,
+        expected,
+        rng,
+    );
+}
+
+// like `run_parties`, but the party `cheater` is corrupted and runs `attack`
+fn run_corrupted_parties<T>(
+    transports: Vec<T>,
+    synchronized: bool,
+    bcast_mode: BroadcastMode,
+    prog: Vec<vm::Instruction>,
+    regs: Vec<vm::Reg>,
+    (cheater, attack): (PartyID, Attack),
+    rng: &mut impl Rng,
+) -> (Vec<PartyResult>, Option<Result<(), MPCError>>)
+where
+    T: 'static + Transport<PartyMsg, PartyMsg>,
+{
+    let mut prep = FakePrep::new(transports.len(), &prog, rng);
+    let i = cheater as usize;
+    prep.alpha_shares[i] = attack.alpha_share(&prep.alpha_shares[i]);
+    prep.msgs[i] = prep.msgs[i].drain(..).map(|m| attack.prep_msg(m)).collect();
+    let transports = transports
+        .into_iter()
+        .enumerate()
+        .map(|(j, t)| AdversaryTransport::new(t, Some(attack).filter(|_| j == i)))
+        .collect();
+    spawn_parties(transports, synchronized, bcast_mode, prog, regs, prep, rng)
+}
+
+#[test]
+fn integration_test_abort() {
+    // the MAC check fails for every party when one party uses a wrong MAC key share
+    let n = 3;
+    let rng = &mut ChaCha20Rng::from_seed(TEST_SEED);
+    for synchronized in [true, false] {
+        let regs = vec![vm::Reg::from_vec(&vec![Fp::random(rng)], &vec![]), vm::Reg::empty(), vm::Reg::empty()];
+        let prog = vec![vm::Instruction::Input(0, 0, 0), vm::Instruction::SOutput(0), vm::Instruction::Stop];
+        let (results, sync_result) = run_corrupted_parties(
+            ChanTransport::mesh(n, TEST_CAP),
+            synchronized,
+            BroadcastMode::Plain,
+            prog,
+            regs,
+            (1, Attack::WrongMacKey),
+            rng,
+        );
+
+        // every party runs the MAC check, the synchronizer only hears about it
+        let reason = AbortReason::MACCheck(MACCheckError::SumIsNotZero);
+        for res in results {
+            match res {
+                Err(MPCError::Aborted { reason: r, .. }) => assert_eq!(r, reason),
+                res => panic!("expected abort, got {:?}", res),
+            }
+        }
+        match sync_result {
+            Some(Err(MPCError::Aborted { reason: r, .. })) => assert_eq!(r, reason.relayed()),
+            None => assert!(!synchronized),
+            res => panic!("expected abort, got {:?}", res),
+        }
+    }
+}
+
+#[test]
+fn integration_test_adversary() {
+    // party 2 has no inputs, so all its `Elem` messages are shares of opened values
+    let n = 3;
+    let cheater = 2;
+    let rng = &mut ChaCha20Rng::from_seed(TEST_SEED);
+    let cases = [
+        (Attack::WrongMacKey, MACCheckError::SumIsNotZero),
+        (Attack::FlipElem, MACCheckError::SumIsNotZero),
+        (Attack::CorruptTripleMac, MACCheckError::SumIsNotZero),
+        (Attack::BadOpening, MACCheckError::BadCommitment(vec![cheater])),
+    ];
+    for (attack, err) in cases {
+        let regs = vec![
+            vm::Reg::from_vec(&vec![Fp::random(rng), Fp::zero()], &vec![]),
+            vm::Reg::from_vec(&vec![Fp::zero(), Fp::random(rng)], &vec![]),
+            vm::Reg::empty(),
+        ];
+        let (results, sync_result) = run_corrupted_parties(
+            ChanTransport::mesh(n, TEST_CAP),
+            true,
+            BroadcastMode::Plain,
+            MUL_PROG.to_vec(),
+            regs,
+            (cheater, attack),
+            rng,
+        );
+
+        let reason = AbortReason::MACCheck(err);
+        let honest = results.into_iter().take(cheater as usize);
+        for res in honest {
+            match res {
+                Err(MPCError::Aborted { reason: r, .. }) => assert_eq!(r, reason, "{:?}", attack),
+                res => panic!("expected abort for {:?}, got {:?}", attack, res),
+            }
+        }
+        match sync_result.unwrap() {
+            Err(MPCError::Aborted { reason: r, .. }) => assert_eq!(r, reason.relayed(), "{:?}", attack),
+            res => panic!("expected abort for {:?}, got {:?}", attack, res),
+        }
+    }
+}
+
+#[test]
+fn integration_test_program_mismatch() {
+    // party 2 runs a different program, every party finds out before the computation starts
+    let n = 3;
+    let odd = 2;
+    let (sync_chans_for_sync, sync_chans_for_party) = create_sync_chans(n);
+    let sync_handle = Synchronizer::spawn(sync_chans_for_sync, Timeouts::default().sync);
+    let party_handles: Vec<_> = ChanTransport::mesh(n, TEST_CAP)
+        .into_iter()
+        .enumerate()
+        .map(|(i, transport)| {
+            let prog = if i == odd { MUL_PROG.to_vec() } else { IO_PROG.to_vec() };
+            let (_preproc_sender, preproc_receiver) = bounded(TEST_CAP);
+            Party::spawn(
+                i as PartyID,
+                Fp::zero(),
+                vm::Reg::empty(),
+                prog,
+                BroadcastMode::Plain,
+                Timeouts::default(),
+                Some((sync_chans_for_party.0[i].clone(), sync_chans_for_party.1[i].clone())),
+                preproc_receiver,
+                transport,
+                Some(TEST_SEED),
+            )
+        })
+        .collect();
+
+    for (i, h) in party_handles.into_iter().enumerate() {
+        let expected = if i == odd { vec![0, 1] } else { vec![odd as PartyID] };
+        match h.join().unwrap() {
+            Err(MPCError::Aborted { by, reason }) => {
+                assert_eq!(by, i as PartyID);
+                assert_eq!(reason, AbortReason::ProgramMismatch(expected));
+            }
+            res => panic!("expected abort, got {:?}", res),
+        }
+    }
+    match sync_handle.join().unwrap() {
+        Err(MPCError::Aborted {
+            reason: AbortReason::Relayed(r),
+            ..
+        }) if matches!(*r, AbortReason::ProgramMismatch(_)) => (),
+        res => panic!("expected abort, got {:?}", res),
+    }
+}
+
+#[test]
+fn integration_test_prep_mismatch() {
+    // party 2 skips the first triple, it cannot tell by itself but the others find out before the MAC check
+    let n = 3;
+    let odd = 2;
+    let rng = &mut ChaCha20Rng::from_seed(TEST_SEED);
+    let alpha = Fp::random(rng);
+    let (_, triples) = gen_fake_prep(n, &alpha, 0, 2, rng);
+    let prog = vec![vm::Instruction::Triple(0, 1, 2), vm::Instruction::SOutput(0), vm::Instruction::Stop];
+    let (sync_chans_for_sync, sync_chans_for_party) = create_sync_chans(n);
+    let sync_handle = Synchronizer::spawn(sync_chans_for_sync, Timeouts::default().sync);
+    let party_handles: Vec<_> = ChanTransport::mesh(n, TEST_CAP)
+        .into_iter()
+        .enumerate()
+        .map(|(i, transport)| {
+            let (preproc_sender, preproc_receiver) = bounded(TEST_CAP);
+            let triple = if i == odd { &triples[1] } else { &triples[0] };
+            preproc_sender.send(PrepMsg::Triple(triple[i].clone())).unwrap();
+            Party::spawn(
+                i as PartyID,
+                Fp::zero(),
+                vm::Reg::empty(),
+                prog.clone(),
+                BroadcastMode::Plain,
+                Timeouts::default(),
+                Some((sync_chans_for_party.0[i].clone(), sync_chans_for_party.1[i].clone())),
+                preproc_receiver,
+                transport,
+                Some(TEST_SEED),
+            )
+        })
+        .collect();
+
+    for (i, h) in party_handles.into_iter().enumerate() {
+        let expected = if i == odd { vec![0, 1] } else { vec![odd as PartyID] };
+        match h.join().unwrap() {
+            Err(MPCError::Aborted { by, reason }) => {
+                assert_eq!(by, i as PartyID);
+                assert_eq!(reason, AbortReason::PrepMismatch(expected));
+            }
+            res => panic!("expected abort, got {:?}", res),
+        }
+    }
+    match sync_handle.join().unwrap() {
+        Err(MPCError::Aborted {
+            reason: AbortReason::Relayed(r),
+            ..
+        }) if matches!(*r, AbortReason::PrepMismatch(_)) => (),
+        res => panic!("expected abort, got {:?}", res),
+    }
+}
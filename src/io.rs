@@ -1,63 +1,141 @@
 use bincode;
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ReadBytesExt, WriteBytesExt};
 use crossbeam::channel::{bounded, select, Receiver, Sender};
 use log::{debug, error, info};
 use num_traits::Zero;
-use rand::SeedableRng;
+use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha20Rng;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs::read_to_string;
+use std::collections::{HashMap, HashSet};
+use std::fs::{read_to_string, File, OpenOptions};
 use std::io;
-use std::io::{Read, Write};
-use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::io::{BufReader, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
 use std::time::Duration;
 
 use crate::algebra::Fp;
-use crate::crypto::gen_fake_prep;
-use crate::error::ApplicationError;
+use crate::auth;
+use crate::crypto::{commit, gen_fake_prep};
+use crate::error::{ApplicationError, FrameError, Timeouts, TransportError};
+use crate::frame::{self, Tagged, DEFAULT_MAX_FRAME_SIZE};
+use crate::hello::{self, Hello};
+use crate::keygen;
 use crate::message::*;
-use crate::party::Party;
+use crate::net::{Addr, Listener, Stream};
+use crate::noise;
+use crate::party::{BroadcastMode, Party};
+use crate::prep;
+use crate::resume::{self, NewStream, Redial};
 use crate::synchronizer;
+use crate::transport::{ChanTransport, Transport};
 use crate::vm;
 use std::str::FromStr;
 
-const TCPSTREAM_CAP: usize = 1000;
+pub(crate) const TCPSTREAM_CAP: usize = 1000;
 const FORM_CLUSTER: u8 = 42;
 const FORM_CLUSTER_ACK: u8 = 41;
+const SYNC_CONTEXT: &[u8] = b"synchronizer";
+const PREP_CONTEXT: &[u8] = b"preprocessing";
+// every read and write of the handshakes on an accepted connection must finish in this time,
+// so that a peer that stalls cannot block the listener
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+// how often the acceptor of the peers that connect again checks whether it should stop
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+// how many peers that connect again may be in the handshake at the same time
+const MAX_PENDING_RECONNECTIONS: usize = 16;
+// the synchronizer and the preprocessing server are not parties,
+// they use these IDs in the Noise handshakes with the parties
+const SYNC_ID: PartyID = PartyID::MAX;
+const PREP_ID: PartyID = PartyID::MAX - 1;
+// the permissions of the files that we write, the files with secret keys or shares are only readable by the owner
+pub(crate) const PUBLIC_FILE_MODE: u32 = 0o644;
+const SECRET_FILE_MODE: u32 = 0o600;
+const SCRIPT_FILE_MODE: u32 = 0o755;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct NodeConf {
-    pub addr: SocketAddr,
+    pub addr: Addr,
     pub id: PartyID,
+    pub static_key: noise::PublicKey,
+    pub verify_key: auth::VerifyKey,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PublicConf {
-    pub sync_addr: SocketAddr,
+    /// The address of the synchronizer,
+    /// if it is missing then the nodes form the cluster and run the program without one.
+    #[serde(default)]
+    pub sync_addr: Option<Addr>,
+    /// The static public key of the synchronizer, it is required if there is a synchronizer.
+    #[serde(default)]
+    pub sync_static_key: Option<noise::PublicKey>,
+    /// How the nodes broadcast the inputs and the commitments in the MAC check.
+    #[serde(default)]
+    pub broadcast: BroadcastMode,
+    /// The timeouts that the nodes and the synchronizer use, a node may override them in its private config.
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    /// The largest frame in bytes that a node accepts from another node or from the synchronizer.
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: usize,
     pub nodes: Vec<NodeConf>,
 }
 
+fn default_max_frame_size() -> usize {
+    DEFAULT_MAX_FRAME_SIZE
+}
+
 impl PublicConf {
     pub fn arg_name() -> &'static str {
         "PUBLIC_CONFIG"
     }
 
+    /// Read the config from the file `f`, it fails if the timeouts are not ordered, see `Timeouts::check`.
     pub fn from_file(f: &str) -> Result<PublicConf, io::Error> {
         let s = read_to_string(f)?;
-        ron::from_str(&s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        let conf: PublicConf = ron::from_str(&s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        conf.timeouts.check()?;
+        Ok(conf)
     }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PrivateConf {
     pub id: PartyID,
-    pub listen_addr: SocketAddr,
-    pub prep_addr: SocketAddr,
-    #[serde(with = "fp_serde")]
-    pub alpha_share: Fp,
+    pub listen_addr: Addr,
+    pub prep_addr: Addr,
+    /// The static public key of the preprocessing server at `prep_addr`.
+    pub prep_static_key: noise::PublicKey,
+    /// The share of the global MAC key, it is written by `keygen_main`.
+    /// It is only missing before the key generation, see `PrivateConf::alpha_share`.
+    #[serde(with = "fp_option_serde", default)]
+    pub alpha_share: Option<Fp>,
+    pub static_secret: noise::SecretKey,
+    pub signing_key: auth::SigningKey,
+    /// The timeouts of this node, if they are different from the ones in the public config.
+    #[serde(default)]
+    pub timeouts: Option<Timeouts>,
+    /// Read the preprocessing data from this file instead of requesting it from `prep_addr`.
+    /// The data in the file can only be used once, see `consumption_log`.
+    #[serde(default)]
+    pub prep_file: Option<PathBuf>,
+    /// The file that records the preprocessing data that this node used, it is required to use `prep_file`.
+    /// The data is identified by its content and not by the name of its file,
+    /// so one log must be kept at the same place for all the preprocessing files of the node.
+    #[serde(default)]
+    pub consumption_log: Option<PathBuf>,
+    /// The opening of the commitment to `alpha_share` from the key generation.
+    #[serde(default)]
+    pub alpha_opening: Option<commit::Opening>,
+    /// The commitments to the MAC key shares of all the parties from the key generation, indexed by the party ID.
+    #[serde(default)]
+    pub alpha_commitments: Vec<commit::Commitment>,
 }
 
 mod fp_serde {
@@ -98,20 +176,124 @@ mod fp_serde {
     }
 }
 
+mod fp_option_serde {
+    use crate::algebra::Fp;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(transparent)]
+    struct Wrapper(#[serde(with = "super::fp_serde")] Fp);
+
+    pub(crate) fn serialize<S>(fp: &Option<Fp>, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        fp.clone().map(Wrapper).serialize(s)
+    }
+
+    pub(crate) fn deserialize<'de, D>(d: D) -> Result<Option<Fp>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Option::<Wrapper>::deserialize(d)?.map(|w| w.0))
+    }
+}
+
 impl PrivateConf {
     pub fn arg_name() -> &'static str {
         "PRIVATE_CONFIG"
     }
 
+    /// Read the config from the file `f`, it fails if the timeouts are not ordered, see `Timeouts::check`.
     pub fn from_file(f: &str) -> Result<PrivateConf, io::Error> {
         let s = read_to_string(f)?;
-        ron::from_str(&s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        let conf: PrivateConf = ron::from_str(&s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        conf.timeouts.as_ref().map_or(Ok(()), Timeouts::check)?;
+        Ok(conf)
     }
+
+    /// Write the config to the file `f`, the file is replaced atomically and only the owner can read it.
+    pub fn to_file(&self, f: &str) -> Result<(), io::Error> {
+        write_ron(Path::new(f), self, SECRET_FILE_MODE)
+    }
+
+    /// The timeouts of this node, i.e., its own or the ones in `public_conf`.
+    /// The synchronizer uses the sync timeout of `public_conf`, so the open timeout of this node
+    /// must be smaller than that one too, otherwise it fails like `Timeouts::check`.
+    pub fn effective_timeouts(&self, public_conf: &PublicConf) -> Result<Timeouts, io::Error> {
+        let timeouts = self.timeouts.unwrap_or(public_conf.timeouts);
+        if timeouts.open >= public_conf.timeouts.sync {
+            let msg = format!(
+                "the open timeout of party {} must be smaller than the sync timeout of the public config",
+                self.id
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        Ok(timeouts)
+    }
+
+    /// The share of the global MAC key, it fails if there is none because the key generation did not run.
+    pub fn alpha_share(&self) -> Result<&Fp, io::Error> {
+        self.alpha_share.as_ref().ok_or_else(|| {
+            let msg = format!("the private config of party {} has no MAC key share", self.id);
+            io::Error::new(io::ErrorKind::InvalidInput, msg)
+        })
+    }
+}
+
+fn write_ron<T: Serialize>(path: &Path, x: &T, mode: u32) -> Result<(), io::Error> {
+    let s = ron::ser::to_string_pretty(x, ron::ser::PrettyConfig::default()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    write_atomic(path, s.as_bytes(), mode)
+}
+
+/// Replace the file at `path` with `data` so that it is never half written,
+/// the data is written to a temporary file with a unique name next to `path` first and then renamed.
+/// The new file is created with the permissions `mode`.
+pub(crate) fn write_atomic(path: &Path, data: &[u8], mode: u32) -> Result<(), io::Error> {
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(format!(".{}.{:016x}.tmp", std::process::id(), ChaCha20Rng::from_entropy().gen::<u64>()));
+    let tmp = PathBuf::from(tmp);
+    let write_tmp = || -> io::Result<()> {
+        let mut f = OpenOptions::new().write(true).create_new(true).mode(mode).open(&tmp)?;
+        f.write_all(data)?;
+        f.sync_all()?;
+        std::fs::rename(&tmp, path)
+    };
+    if let Err(e) = write_tmp() {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e);
+    }
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// Compute the global MAC key from the shares of all the parties,
+// the shares from the key generation must match the commitments that every party received.
+fn global_alpha(private_confs: &[PrivateConf]) -> io::Result<Fp> {
+    let mut alpha = Fp::zero();
+    for conf in private_confs {
+        let alpha_share = conf.alpha_share()?;
+        if let Some(opening) = &conf.alpha_opening {
+            if !private_confs
+                .iter()
+                .all(|other| keygen::verify_share(conf.id, alpha_share, opening, &other.alpha_commitments))
+            {
+                let msg = format!("the MAC key share of party {} does not match its commitment", conf.id);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            }
+        }
+        alpha += alpha_share;
+    }
+    Ok(alpha)
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SynchronizerConfig {
-    pub listen_addr: SocketAddr,
+    pub listen_addr: Addr,
+    pub static_secret: noise::SecretKey,
 }
 
 impl SynchronizerConfig {
@@ -125,14 +307,60 @@ impl SynchronizerConfig {
     }
 }
 
-fn pp(x: &io::Result<SocketAddr>) -> String {
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PrepServerConfig {
+    pub listen_addr: Addr,
+    pub static_secret: noise::SecretKey,
+}
+
+impl PrepServerConfig {
+    pub fn arg_name() -> &'static str {
+        "PREP_SERVER_CONFIG"
+    }
+
+    pub fn from_file(f: &str) -> Result<PrepServerConfig, io::Error> {
+        let s = read_to_string(f)?;
+        ron::from_str(&s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+fn pp(x: &io::Result<Addr>) -> String {
     match x {
         Ok(addr) => addr.to_string(),
         Err(_) => "xxxx:xxxx".to_string(),
     }
 }
 
-fn try_shutdown(stream: &TcpStream) {
+// the context for authenticating to the party `id`
+fn party_context(id: PartyID) -> Vec<u8> {
+    let mut out = b"party".to_vec();
+    out.extend_from_slice(&id.to_le_bytes());
+    out
+}
+
+/// Find the verification key of `id` if it is in `expected_ids` and it is not already `connected`.
+fn lookup_verify_key<F>(nodes: &[NodeConf], expected_ids: &[PartyID], connected: F, id: PartyID) -> Result<auth::VerifyKey, auth::AuthError>
+where
+    F: Fn(&PartyID) -> bool,
+{
+    if !expected_ids.contains(&id) {
+        return Err(auth::AuthError::UnknownParty(id));
+    }
+    if connected(&id) {
+        return Err(auth::AuthError::DuplicateParty(id));
+    }
+    match nodes.iter().find(|x| x.id == id) {
+        Some(node) => Ok(node.verify_key.clone()),
+        None => Err(auth::AuthError::UnknownParty(id)),
+    }
+}
+
+fn set_handshake_timeout(stream: &Stream, timeout: Option<Duration>) -> io::Result<()> {
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)
+}
+
+fn try_shutdown(stream: &Stream) {
     match stream.shutdown(Shutdown::Both) {
         Ok(()) => info!("[{}] shutdown ok", pp(&stream.local_addr())),
         Err(e) => info!("[{}] attempted to shutdown stream but failed: {:?}", pp(&stream.local_addr()), e),
@@ -144,20 +372,41 @@ fn try_shutdown(stream: &TcpStream) {
 /// The synchronizer should start as the first node.
 /// Every other node connects to the synchronizer.
 /// When all the nodes are online, the synchronizer sends a "form cluster" command to all other nodes.
-/// TODO: use TLS
-fn start_discovery(listen_addr: SocketAddr, target_ids: &Vec<PartyID>) -> Result<HashMap<PartyID, TcpStream>, io::Error> {
-    let mut out: HashMap<PartyID, TcpStream> = HashMap::new();
-    let listener = TcpListener::bind(listen_addr)?;
+/// Every node must first prove its identity using the signing key that corresponds to its `verify_key` in `nodes`,
+/// then it must send a hello message that is compatible with `hello`, see the `hello` module.
+/// Unknown, duplicate, unauthenticated or incompatible nodes are rejected, and so are nodes that stall during these steps.
+/// Then the link is encrypted using `my_key` and the static key of the node in `nodes`,
+/// only the "form cluster" signal and its ACK are sent outside of the encrypted session.
+fn start_discovery(
+    listen_addr: &Addr,
+    my_key: &noise::SecretKey,
+    nodes: &Vec<NodeConf>,
+    hello: &Hello,
+) -> Result<HashMap<PartyID, (Stream, noise::Session)>, io::Error> {
+    let target_ids: Vec<PartyID> = nodes.iter().map(|x| x.id).collect();
+    let mut rng = ChaCha20Rng::from_entropy();
+    let mut out: HashMap<PartyID, (Stream, noise::Session)> = HashMap::new();
+    let listener = Listener::bind(listen_addr)?;
     for stream_res in listener.incoming() {
         let mut stream = stream_res?;
         info!("[{}] found peer {}", pp(&listener.local_addr()), pp(&stream.peer_addr()));
-
-        let candidate_id = read_party_id(&mut stream)?;
-        if !out.contains_key(&candidate_id) && target_ids.contains(&candidate_id) {
-            out.insert(candidate_id, stream);
-        } else {
-            info!("[{}] shutting down bad peer with id {}", pp(&listener.local_addr()), candidate_id);
-            stream.shutdown(Shutdown::Both)?;
+        let lookup = |id| lookup_verify_key(nodes, &target_ids, |x| out.contains_key(x), id);
+        let challenge =
+            set_handshake_timeout(&stream, Some(HANDSHAKE_TIMEOUT)).and_then(|()| auth::challenge(&mut stream, SYNC_CONTEXT, lookup, &mut rng));
+        let handshake = challenge.and_then(|id| {
+            hello::exchange(&mut stream, hello)?;
+            let peer_key = &nodes.iter().find(|x| x.id == id).unwrap().static_key;
+            let session = noise::handshake_responder(&mut stream, SYNC_ID, my_key, id, peer_key)?;
+            Ok((id, session))
+        });
+        match handshake {
+            Ok((id, session)) => {
+                out.insert(id, (stream, session));
+            }
+            Err(e) => {
+                error!("[{}] rejected peer {}: {}", pp(&listener.local_addr()), pp(&stream.peer_addr()), e);
+                try_shutdown(&stream);
+            }
         }
 
         if out.len() == target_ids.len() {
@@ -166,30 +415,41 @@ fn start_discovery(listen_addr: SocketAddr, target_ids: &Vec<PartyID>) -> Result
         }
     }
 
-    for stream in out.values_mut() {
+    for (stream, _) in out.values_mut() {
         stream.write_u8(FORM_CLUSTER)?;
     }
 
-    // and we expect an 'ACK'
-    for stream in out.values_mut() {
+    // and we expect an 'ACK', the handshake timeout stays until then so that a stalling node cannot block us
+    for (stream, _) in out.values_mut() {
         let x = stream.read_u8()?;
         if x != FORM_CLUSTER_ACK {
             error!("[{}] ACK is wrong from {}", pp(&listener.local_addr()), pp(&stream.peer_addr()))
         }
+        set_handshake_timeout(stream, None)?;
     }
     info!("[{:?}] 'form cluster' message sent", listener.local_addr());
     Ok(out)
 }
 
-/// Connect to the discovery and wait for the 'form cluster' message.
-/// Retruns a TcpStream that is connected to the synchronizer.
-fn wait_start(sync_addr: SocketAddr, my_id: PartyID) -> Result<TcpStream, io::Error> {
+/// Connect to the discovery, prove our identity, exchange the hello messages, start an encrypted session
+/// with the synchronizer that holds the secret key of `sync_key` and wait for the 'form cluster' message.
+/// Retruns a stream that is connected to the synchronizer and the session.
+fn wait_start(
+    sync_addr: &Addr,
+    my_id: PartyID,
+    my_signing_key: &auth::SigningKey,
+    my_key: &noise::SecretKey,
+    sync_key: &noise::PublicKey,
+    hello: &Hello,
+) -> Result<(Stream, noise::Session), io::Error> {
     let mut stream = retry_connection(sync_addr, 1000, Duration::from_millis(500))?;
-    write_party_id(&mut stream, my_id)?;
+    auth::prove(&mut stream, my_id, my_signing_key, SYNC_CONTEXT)?;
+    hello::exchange(&mut stream, hello)?;
+    let session = noise::handshake_initiator(&mut stream, my_id, my_key, SYNC_ID, sync_key)?;
     let signal = stream.read_u8()?;
     if signal == FORM_CLUSTER {
         stream.write_u8(FORM_CLUSTER_ACK)?;
-        Ok(stream)
+        Ok((stream, session))
     } else {
         Err(io::Error::new(io::ErrorKind::InvalidData, "invalid 'form cluster' signal"))
     }
@@ -197,9 +457,22 @@ fn wait_start(sync_addr: SocketAddr, my_id: PartyID) -> Result<TcpStream, io::Er
 
 /// Listen for new connections but do not accept until `wait_start` unblocks.
 /// Then, accept connections from IDs that are lower than `my_id`.
-/// Make TCP connections to IDs that are higher than mine.
-/// If there are none, do not make TCP connections.
-fn form_cluster(listener: TcpListener, my_id: PartyID, all_nodes: &Vec<NodeConf>) -> Result<HashMap<PartyID, TcpStream>, io::Error> {
+/// Make connections to IDs that are higher than mine, trying at most `retries` times per peer.
+/// If there are none, do not make connections.
+/// Every peer must prove its identity using the signing key that corresponds to its `verify_key` in `all_nodes`,
+/// then it must send a hello message that is compatible with `hello`,
+/// and then the connection is encrypted using the static keys in `all_nodes`.
+/// A peer that fails a step is not added to the cluster,
+/// and the cluster formation fails if an authenticated peer runs a different version or program.
+fn form_cluster(
+    listener: Arc<Listener>,
+    my_id: PartyID,
+    my_signing_key: &auth::SigningKey,
+    my_key: &noise::SecretKey,
+    all_nodes: &Vec<NodeConf>,
+    hello: &Hello,
+    retries: usize,
+) -> Result<HashMap<PartyID, (Stream, noise::Session)>, io::Error> {
     // spawn a thread to accept valid connections
     let all_ids: Vec<PartyID> = all_nodes.iter().map(|x| x.id).collect();
     let ids_to_connect: Vec<PartyID> = all_ids.clone().into_iter().filter(|id| *id < my_id).collect();
@@ -207,25 +480,58 @@ fn form_cluster(listener: TcpListener, my_id: PartyID, all_nodes: &Vec<NodeConf>
     debug!("[{:?}] node {} waiting for ids {:?}", listener.local_addr(), my_id, ids_to_receive);
     debug!("[{:?}] node {} connecting to ids {:?}", listener.local_addr(), my_id, ids_to_connect);
 
+    let receiver_key = my_key.clone();
+    let receiver_nodes = all_nodes.clone();
+    let receiver_hello = hello.clone();
     let handler = thread::spawn(move || {
-        let mut out: HashMap<PartyID, TcpStream> = HashMap::new();
+        let mut out: HashMap<PartyID, (Stream, noise::Session)> = HashMap::new();
         if ids_to_receive.is_empty() {
-            return out;
+            return Ok(out);
         }
 
+        let mut rng = ChaCha20Rng::from_entropy();
         for stream_res in listener.incoming() {
             match stream_res {
                 Ok(mut stream) => {
-                    let candidate_id = read_party_id(&mut stream).expect("cannot read u32");
-                    if ids_to_receive.contains(&candidate_id) && !out.contains_key(&candidate_id) {
-                        #[rustfmt::skip]
-                        debug!("[{}] received candidate {} from {}", 
-                               pp(&listener.local_addr()), candidate_id, pp(&stream.peer_addr()));
-                        out.insert(candidate_id, stream);
-                    } else {
+                    let lookup = |id| lookup_verify_key(&receiver_nodes, &ids_to_receive, |x| out.contains_key(x), id);
+                    let challenge = set_handshake_timeout(&stream, Some(HANDSHAKE_TIMEOUT))
+                        .and_then(|()| auth::challenge(&mut stream, &party_context(my_id), lookup, &mut rng));
+                    let candidate_id = match challenge {
+                        Ok(id) => id,
+                        Err(e) => {
+                            #[rustfmt::skip]
+                            error!("[{}] rejected peer {}: {}",
+                                   pp(&listener.local_addr()), pp(&stream.peer_addr()), e);
+                            try_shutdown(&stream);
+                            continue;
+                        }
+                    };
+                    #[rustfmt::skip]
+                    debug!("[{}] received candidate {} from {}", 
+                           pp(&listener.local_addr()), candidate_id, pp(&stream.peer_addr()));
+                    if let Err(e) = hello::exchange(&mut stream, &receiver_hello) {
                         #[rustfmt::skip]
-                        error!("[{}] received invalid id {:?} from {}", 
-                               pp(&listener.local_addr()), candidate_id, pp(&stream.peer_addr()));
+                        error!("[{}] incompatible peer {}: {}",
+                               pp(&listener.local_addr()), candidate_id, e);
+                        try_shutdown(&stream);
+                        // the peer is authenticated, and if it runs a different version or program
+                        // then it cannot become compatible by retrying
+                        if hello::hello_error(&e).is_some() {
+                            return Err(e);
+                        }
+                        continue;
+                    }
+                    let peer_key = &receiver_nodes.iter().find(|x| x.id == candidate_id).unwrap().static_key;
+                    let handshake = noise::handshake_responder(&mut stream, my_id, &receiver_key, candidate_id, peer_key);
+                    match handshake.and_then(|session| set_handshake_timeout(&stream, None).map(|()| session)) {
+                        Ok(session) => {
+                            out.insert(candidate_id, (stream, session));
+                        }
+                        Err(e) => {
+                            #[rustfmt::skip]
+                            error!("[{}] handshake with candidate {} failed: {:?}",
+                                   pp(&listener.local_addr()), candidate_id, e);
+                        }
                     }
                 }
                 Err(e) => {
@@ -238,65 +544,163 @@ fn form_cluster(listener: TcpListener, my_id: PartyID, all_nodes: &Vec<NodeConf>
                 break;
             }
         }
-        out
+        Ok(out)
     });
 
     // make connections to the IDs that are higher than mine
-    let mut out: HashMap<PartyID, TcpStream> = HashMap::new();
+    let mut out: HashMap<PartyID, (Stream, noise::Session)> = HashMap::new();
     for node in all_nodes {
         if ids_to_connect.contains(&node.id) && !out.contains_key(&node.id) {
-            let mut stream = retry_connection(node.addr, 20, Duration::from_millis(200))?;
-            write_party_id(&mut stream, my_id)?;
-            out.insert(node.id, stream);
+            let stream = retry_connection(&node.addr, retries, Duration::from_millis(200))?;
+            out.insert(node.id, connect_peer(stream, my_id, my_signing_key, my_key, node, hello)?);
         }
     }
 
     // combine the two
-    let others = handler.join().expect("form cluster thread panicked");
+    let others = handler.join().expect("form cluster thread panicked")?;
     out.extend(others);
     std::assert_eq!(out.len(), all_nodes.len() - 1);
     debug!("[xxxx:xxxx] {} cluster formation ok", my_id);
     Ok(out)
 }
 
-fn read_length<R: io::Read>(reader: &mut R) -> io::Result<usize> {
-    reader.read_u64::<LittleEndian>().map(|x| x as usize)
+/// Prove our identity to `node` on a new connection, exchange the hello messages and start an encrypted session.
+fn connect_peer(
+    mut stream: Stream,
+    my_id: PartyID,
+    my_signing_key: &auth::SigningKey,
+    my_key: &noise::SecretKey,
+    node: &NodeConf,
+    hello: &Hello,
+) -> io::Result<(Stream, noise::Session)> {
+    auth::prove(&mut stream, my_id, my_signing_key, &party_context(node.id))?;
+    hello::exchange(&mut stream, hello)?;
+    let session = noise::handshake_initiator(&mut stream, my_id, my_key, node.id, &node.static_key)?;
+    Ok((stream, session))
 }
 
-fn read_party_id<R: io::Read>(reader: &mut R) -> io::Result<PartyID> {
-    reader.read_u32::<LittleEndian>().map(|x| x as PartyID)
-}
-
-fn write_length<W: io::Write>(writer: &mut W, len: usize) -> io::Result<()> {
-    writer.write_u64::<LittleEndian>(len as u64)
+/// Accept the peers that connect again after their link broke and hand the new streams to the links on `chans`,
+/// until `stop` is set, the listener must be nonblocking so that `stop` is checked regularly.
+/// A peer must pass the same checks as in `form_cluster`, but it may connect more than once.
+/// Every connection is checked in its own thread, so a peer that stalls does not hold up the others.
+fn accept_reconnections(
+    listener: Arc<Listener>,
+    my_id: PartyID,
+    my_key: noise::SecretKey,
+    all_nodes: Vec<NodeConf>,
+    hello: Hello,
+    chans: HashMap<PartyID, Sender<NewStream>>,
+    stop: Arc<AtomicBool>,
+) {
+    let local_addr = pp(&listener.local_addr());
+    let shared = Arc::new((my_key, all_nodes, hello, chans));
+    let pending = Arc::new(AtomicUsize::new(0));
+    while !stop.load(Ordering::SeqCst) {
+        let stream = match listener.accept().and_then(|stream| stream.set_nonblocking(false).map(|()| stream)) {
+            Ok(stream) => stream,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::WouldBlock {
+                    error!("[{}] connection issue: {:?}", local_addr, e);
+                }
+                thread::sleep(ACCEPT_INTERVAL);
+                continue;
+            }
+        };
+        if pending.fetch_add(1, Ordering::SeqCst) >= MAX_PENDING_RECONNECTIONS {
+            pending.fetch_sub(1, Ordering::SeqCst);
+            error!("[{}] too many pending connections, rejected peer {}", local_addr, pp(&stream.peer_addr()));
+            try_shutdown(&stream);
+            continue;
+        }
+        let (shared, pending) = (shared.clone(), pending.clone());
+        thread::spawn(move || {
+            let (my_key, all_nodes, hello, chans) = &*shared;
+            accept_reconnection(stream, my_id, my_key, all_nodes, hello, chans);
+            pending.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
 }
 
-fn write_party_id<W: io::Write>(writer: &mut W, id: PartyID) -> io::Result<()> {
-    writer.write_u32::<LittleEndian>(id)
+fn accept_reconnection(
+    mut stream: Stream,
+    my_id: PartyID,
+    my_key: &noise::SecretKey,
+    all_nodes: &[NodeConf],
+    hello: &Hello,
+    chans: &HashMap<PartyID, Sender<NewStream>>,
+) {
+    let ids: Vec<PartyID> = chans.keys().copied().collect();
+    let mut accept = || -> io::Result<(PartyID, noise::Session)> {
+        set_handshake_timeout(&stream, Some(HANDSHAKE_TIMEOUT))?;
+        let lookup = |id| lookup_verify_key(all_nodes, &ids, |_| false, id);
+        let id = auth::challenge(&mut stream, &party_context(my_id), lookup, &mut ChaCha20Rng::from_entropy())?;
+        hello::exchange(&mut stream, hello)?;
+        let peer_key = &all_nodes.iter().find(|x| x.id == id).unwrap().static_key;
+        let session = noise::handshake_responder(&mut stream, my_id, my_key, id, peer_key)?;
+        set_handshake_timeout(&stream, None)?;
+        Ok((id, session))
+    };
+    match accept() {
+        Ok((id, session)) => {
+            info!("[{}] party {} connected again", pp(&stream.local_addr()), id);
+            // the link only takes one new stream at a time
+            let _ = chans[&id].try_send((stream, Some(session)));
+        }
+        Err(e) => {
+            error!("[{}] rejected peer {}: {}", pp(&stream.local_addr()), pp(&stream.peer_addr()), e);
+            try_shutdown(&stream);
+        }
+    }
 }
 
-/// Wrap a TcpStream into channels
-fn wrap_tcpstream<S, R>(stream: TcpStream) -> (Sender<S>, Receiver<R>, Sender<()>, JoinHandle<()>)
+/// A wrapped stream: the channel for the messages to send, the channel of the received messages,
+/// the channel of the protocol violations of the peer, the shutdown channel and the handle of the stream.
+pub(crate) type WrappedStream<S, R, H> = (Sender<S>, Receiver<R>, Receiver<FrameError>, Sender<()>, H);
+
+#[cfg(not(feature = "async-net"))]
+pub(crate) use self::wrap_stream as wrap_link;
+#[cfg(feature = "async-net")]
+pub(crate) use crate::async_net::{wrap_stream as wrap_link, JoinHandle as StreamHandle};
+#[cfg(not(feature = "async-net"))]
+pub(crate) type StreamHandle = JoinHandle<()>;
+
+// waits for a wrapped stream of either backend or for a resumable link to close
+type LinkHandle = Box<dyn FnOnce() + Send>;
+
+/// Wrap a Stream into channels.
+/// If a `session` is given, every message is encrypted and authenticated using it.
+/// A frame from the peer that is larger than `max_frame_size` or that is malformed closes the stream,
+/// the protocol violation is then sent on the error channel.
+#[cfg_attr(feature = "async-net", allow(dead_code))]
+pub(crate) fn wrap_stream<S, R>(stream: Stream, session: Option<noise::Session>, max_frame_size: usize) -> WrappedStream<S, R, JoinHandle<()>>
 where
-    S: 'static + Sync + Send + Clone + Serialize,
-    R: 'static + Sync + Send + Clone + DeserializeOwned,
+    S: 'static + Sync + Send + Clone + Serialize + Tagged,
+    R: 'static + Sync + Send + Clone + DeserializeOwned + Tagged,
 {
     let (reader_s, reader_r) = bounded(TCPSTREAM_CAP);
     let (writer_s, writer_r) = bounded(TCPSTREAM_CAP);
+    let (error_s, error_r) = bounded(1);
     let (shutdown_s, shutdown_r) = bounded(1);
     let mut reader = stream.try_clone().unwrap();
     let mut writer = stream.try_clone().unwrap();
+    let (mut encryptor, mut decryptor) = match session {
+        Some(session) => {
+            let (enc, dec) = session.split();
+            (Some(enc), Some(dec))
+        }
+        None => (None, None),
+    };
 
     let hdl = thread::spawn(move || {
         // read data from a stream and then forward it to a channel
         let read_hdl = thread::spawn(move || loop {
             let mut f = || -> Result<(), std::io::Error> {
-                let n = read_length(&mut reader)?;
-                let mut value_buf = vec![0u8; n];
-                reader.read_exact(&mut value_buf)?;
+                let mut value_buf = frame::read_frame(&mut reader, R::TAG, max_frame_size)?;
+                if let Some(dec) = decryptor.as_mut() {
+                    value_buf = dec.decrypt(&value_buf)?;
+                }
 
-                // we use expect here because we cannot recover from deserialzation failure
-                let msg: R = bincode::deserialize(&value_buf).expect("deserialization failed");
+                let msg: R = frame::deserialize(&value_buf)?;
                 match reader_s.send(msg) {
                     Ok(()) => Ok(()),
                     Err(e) => {
@@ -309,7 +713,13 @@ where
             match f() {
                 Ok(()) => {}
                 Err(e) => {
-                    info!("[{}] read failed but probably not an issue: {:?}", pp(&reader.local_addr()), e);
+                    match frame::violation(&e) {
+                        Some(v) => {
+                            error!("[{}] protocol violation by {}: {}", pp(&reader.local_addr()), pp(&reader.peer_addr()), v);
+                            let _ = error_s.try_send(v);
+                        }
+                        None => info!("[{}] read failed but probably not an issue: {:?}", pp(&reader.local_addr()), e),
+                    }
                     // try to shutdown because the writer might've closed the stream too
                     try_shutdown(&reader);
                     break;
@@ -318,19 +728,26 @@ where
         });
 
         // read data from a channel and then send it into a stream
+        let mut write = |msg: S| -> io::Result<()> {
+            let mut data = bincode::serialize(&msg).expect("serialization failed");
+            if let Some(enc) = encryptor.as_mut() {
+                data = enc.encrypt(&data)?;
+            }
+            frame::write_frame(&mut writer, S::TAG, &data)
+        };
         loop {
             select! {
                 recv(writer_r) -> msg_res => {
-                    let msg = msg_res.unwrap(); // TODO check unwrap
-                    let data = bincode::serialize(&msg).expect("serialization failed");
-
-                    let mut f = || -> io::Result<()> {
-                        write_length(&mut writer, data.len())?;
-                        (&mut writer).write_all(&data)?;
-                        Ok(())
+                    let msg = match msg_res {
+                        Ok(msg) => msg,
+                        Err(_) => {
+                            // nobody can send on this stream anymore
+                            info!("[{}] closing stream with peer {}", pp(&writer.local_addr()), pp(&writer.peer_addr()));
+                            try_shutdown(&writer);
+                            break;
+                        }
                     };
-
-                    match f() {
+                    match write(msg) {
                         Ok(()) => {},
                         Err(e) => {
                             error!("[{}] write error: {:?}", pp(&writer.local_addr()), e);
@@ -339,8 +756,13 @@ where
                         }
                     }
                 }
-                recv(shutdown_r) -> msg_res => {
-                    msg_res.unwrap(); // TODO check unwrap
+                // dropping the shutdown sender also closes the stream
+                recv(shutdown_r) -> _ => {
+                    // the peer may still be waiting for the messages that are queued,
+                    // which is the case when the parties are not synchronized
+                    if let Err(e) = writer_r.try_iter().try_for_each(&mut write) {
+                        error!("[{}] write error: {:?}", pp(&writer.local_addr()), e);
+                    }
                     info!("[{}] closing stream with peer {}", pp(&writer.local_addr()), pp(&writer.peer_addr()));
                     // try to shutdown because the reader might've closed the stream too
                     try_shutdown(&writer);
@@ -351,13 +773,161 @@ where
         read_hdl.join().expect("reader thread panicked")
     });
 
-    (writer_s, reader_r, shutdown_s, hdl)
+    (writer_s, reader_r, error_r, shutdown_s, hdl)
 }
 
-fn retry_connection(addr: SocketAddr, tries: usize, interval: Duration) -> Result<TcpStream, io::Error> {
+/// A transport where every peer is connected with a stream, either over TCP or over a Unix domain socket.
+/// Messages that a party sends to itself do not go through the network.
+/// If a peer violates the framing, receiving from it fails with `TransportError::BadMessage`.
+pub struct StreamTransport<S, R> {
+    inner: ChanTransport<S, R>,
+    error_chans: Vec<Option<Receiver<FrameError>>>,
+    shutdown_chans: Vec<Sender<()>>,
+    handles: Mutex<Vec<LinkHandle>>,
+}
+
+impl<S, R> StreamTransport<S, R>
+where
+    S: 'static + Sync + Send + Clone + Serialize + Tagged,
+    R: 'static + Sync + Send + Clone + DeserializeOwned + Tagged,
+{
+    /// Wrap the streams of all the peers, the peers must have the IDs `0..streams.len()`.
+    /// If a session is given, the messages on that stream are encrypted and authenticated.
+    /// Frames larger than `max_frame_size` are rejected.
+    pub fn new(streams: HashMap<PartyID, (Stream, Option<noise::Session>)>, max_frame_size: usize) -> io::Result<StreamTransport<S, R>> {
+        StreamTransport::from_links(streams.len(), wrap_all(streams, max_frame_size), None)
+    }
+
+    fn from_links(
+        n: usize,
+        wrapped: HashMap<PartyID, WrappedStream<S, R, LinkHandle>>,
+        loopback: Option<(PartyID, Sender<S>, Receiver<R>)>,
+    ) -> io::Result<StreamTransport<S, R>> {
+        let mut links: Vec<Option<(Sender<S>, Receiver<R>)>> = (0..n).map(|_| None).collect();
+        let mut error_chans: Vec<Option<Receiver<FrameError>>> = (0..n).map(|_| None).collect();
+        let mut shutdown_chans = vec![];
+        let mut handles = vec![];
+        if let Some((id, s, r)) = loopback {
+            *links
+                .get_mut(id as usize)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("unexpected party {}", id)))? = Some((s, r));
+        }
+        for (id, (s, r, error_r, shutdown_s, h)) in wrapped {
+            let link = links
+                .get_mut(id as usize)
+                .filter(|link| link.is_none())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("unexpected stream for party {}", id)))?;
+            *link = Some((s, r));
+            error_chans[id as usize] = Some(error_r);
+            shutdown_chans.push(shutdown_s);
+            handles.push(h);
+        }
+
+        let (senders, receivers) = links
+            .into_iter()
+            .enumerate()
+            .map(|(id, link)| link.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("missing stream for party {}", id))))
+            .collect::<io::Result<Vec<_>>>()?
+            .into_iter()
+            .unzip();
+        Ok(StreamTransport {
+            inner: ChanTransport::new(senders, receivers),
+            error_chans,
+            shutdown_chans,
+            handles: Mutex::new(handles),
+        })
+    }
+
+    /// Close all the streams and wait for them to finish.
+    pub fn close(&self) {
+        for chan in &self.shutdown_chans {
+            // the stream might be closed already
+            let _ = chan.send(());
+        }
+        for h in self.handles.lock().unwrap().drain(..) {
+            h();
+        }
+    }
+}
+
+fn wrap_all<S, R>(
+    streams: HashMap<PartyID, (Stream, Option<noise::Session>)>,
+    max_frame_size: usize,
+) -> HashMap<PartyID, WrappedStream<S, R, LinkHandle>>
+where
+    S: 'static + Sync + Send + Clone + Serialize + Tagged,
+    R: 'static + Sync + Send + Clone + DeserializeOwned + Tagged,
+{
+    streams
+        .into_iter()
+        .map(|(id, (stream, session))| {
+            let (s, r, error_r, shutdown_s, h) = wrap_link::<S, R>(stream, session, max_frame_size);
+            let h: LinkHandle = Box::new(move || h.join().expect("stream thread panicked"));
+            (id, (s, r, error_r, shutdown_s, h))
+        })
+        .collect()
+}
+
+impl<T> StreamTransport<T, T>
+where
+    T: 'static + Sync + Send + Clone + Serialize + DeserializeOwned + Tagged,
+{
+    /// Wrap the streams of all the other parties, `my_id` together with the IDs of the streams must be `0..streams.len()+1`.
+    pub fn with_loopback(
+        my_id: PartyID,
+        streams: HashMap<PartyID, (Stream, Option<noise::Session>)>,
+        max_frame_size: usize,
+    ) -> io::Result<StreamTransport<T, T>> {
+        let (s, r) = bounded(TCPSTREAM_CAP);
+        StreamTransport::from_links(streams.len() + 1, wrap_all(streams, max_frame_size), Some((my_id, s, r)))
+    }
+
+    /// Like `with_loopback`, but every link connects again with its `Redial` when its stream breaks
+    /// and the messages that were lost are sent again, see the `resume` module.
+    /// A link that has no new stream within `grace` is closed.
+    pub fn resumable(
+        my_id: PartyID,
+        streams: HashMap<PartyID, (Stream, Option<noise::Session>, Redial)>,
+        max_frame_size: usize,
+        grace: Duration,
+    ) -> io::Result<StreamTransport<T, T>> {
+        let (s, r) = bounded(TCPSTREAM_CAP);
+        let wrapped: HashMap<_, _> = streams
+            .into_iter()
+            .map(|(id, (stream, session, redial))| {
+                let (s, r, error_r, shutdown_s, h) = resume::resumable_link::<T>(stream, session, max_frame_size, redial, grace);
+                let h: LinkHandle = Box::new(move || h.join().expect("link thread panicked"));
+                (id, (s, r, error_r, shutdown_s, h))
+            })
+            .collect();
+        StreamTransport::from_links(wrapped.len() + 1, wrapped, Some((my_id, s, r)))
+    }
+}
+
+impl<S: Send, R: Send> Transport<S, R> for StreamTransport<S, R> {
+    fn peers(&self) -> usize {
+        self.inner.peers()
+    }
+
+    fn send(&self, to: PartyID, m: S) -> Result<(), TransportError> {
+        self.inner.send(to, m)
+    }
+
+    fn recv_from(&self, from: PartyID, timeout: Duration) -> Result<R, TransportError> {
+        // the stream is closed after a protocol violation, so the violation is reported before the disconnection
+        self.inner.recv_from(from, timeout).map_err(
+            |e| match self.error_chans.get(from as usize).and_then(|chan| chan.as_ref()?.try_recv().ok()) {
+                Some(_) => TransportError::BadMessage(from),
+                None => e,
+            },
+        )
+    }
+}
+
+fn retry_connection(addr: &Addr, tries: usize, interval: Duration) -> Result<Stream, io::Error> {
     let mut last_error = io::Error::new(io::ErrorKind::Other, "dummy error");
     for _ in 0..tries {
-        match TcpStream::connect(addr.clone()) {
+        match Stream::connect(addr) {
             Ok(stream) => {
                 return Ok(stream);
             }
@@ -385,30 +955,21 @@ pub fn create_register(id: PartyID, prog: &Vec<vm::Instruction>, inputs: Vec<&st
 }
 
 pub fn synchronizer_main(public_conf: PublicConf, synchronizer_conf: SynchronizerConfig) -> Result<(), ApplicationError> {
-    let ids: Vec<PartyID> = public_conf.nodes.clone().iter().map(|x| x.id).collect();
-    let stream_map = start_discovery(synchronizer_conf.listen_addr, &ids)?;
-
-    let mut peer_handlers = vec![];
-    let mut peer_sender_chans = vec![];
-    let mut peer_receiver_chans = vec![];
-    let mut peer_shutdown_chans = vec![];
-
-    for (_id, stream) in stream_map {
-        let (s, r, shutdown_s, h) = wrap_tcpstream::<SyncMsg, SyncReplyMsg>(stream);
-        peer_sender_chans.push(s);
-        peer_receiver_chans.push(r);
-        peer_shutdown_chans.push(shutdown_s);
-        peer_handlers.push(h);
-    }
-
-    let sync_handle = synchronizer::Synchronizer::spawn(peer_sender_chans, peer_receiver_chans);
-    sync_handle.join().expect("synchronizer thread panicked")?;
-    for chan in peer_shutdown_chans {
-        chan.send(())?;
-    }
-    for h in peer_handlers {
-        h.join().unwrap();
-    }
+    let hello = Hello::new(public_conf.nodes.len(), None);
+    #[rustfmt::skip]
+    let stream_map = start_discovery(&synchronizer_conf.listen_addr, &synchronizer_conf.static_secret, &public_conf.nodes, &hello)?;
+    let transport = Arc::new(StreamTransport::<SyncMsg, SyncReplyMsg>::new(
+        stream_map
+            .into_iter()
+            .map(|(id, (stream, session))| (id, (stream, Some(session))))
+            .collect(),
+        public_conf.max_frame_size,
+    )?);
+
+    let sync_handle = synchronizer::Synchronizer::spawn(transport.clone(), public_conf.timeouts.sync);
+    let res = sync_handle.join().expect("synchronizer thread panicked");
+    transport.close();
+    res?;
     Ok(())
 }
 
@@ -419,123 +980,441 @@ pub fn online_node_main(
     prog: Vec<vm::Instruction>,
     seed: Option<[u8; 32]>,
 ) -> Result<Vec<Fp>, ApplicationError> {
-    let listener = TcpListener::bind(private_conf.listen_addr)?;
-    let sync_stream = wait_start(public_conf.sync_addr, private_conf.id)?;
-    let (sync_s, sync_r, sync_shutdown, sync_h) = wrap_tcpstream::<SyncReplyMsg, SyncMsg>(sync_stream);
-
-    let stream_map = form_cluster(listener, private_conf.id, &public_conf.nodes)?;
-
-    let mut peer_handlers = vec![];
-    let mut peer_sender_chans = vec![];
-    let mut peer_receiver_chans = vec![];
-    let mut peer_shutdown_chans = vec![];
-
-    for (_id, stream) in stream_map {
-        let (s, r, shutdown_s, h) = wrap_tcpstream::<PartyMsg, PartyMsg>(stream);
-        peer_sender_chans.push(s);
-        peer_receiver_chans.push(r);
-        peer_shutdown_chans.push(shutdown_s);
-        peer_handlers.push(h);
+    let alpha_share = private_conf.alpha_share()?;
+    let timeouts = private_conf.effective_timeouts(&public_conf)?;
+    // check the preprocessing file and mark the data we need as used
+    // before the other nodes start waiting for us
+    let requests = prep::plan_requests(&prog);
+    let store = match &private_conf.prep_file {
+        Some(f) => {
+            let store = prep::PrepStore::read(BufReader::new(File::open(f)?))?;
+            store.check(private_conf.id, alpha_share, &requests)?;
+            let log = private_conf.consumption_log.as_ref().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the private config has no consumption log for the preprocessing file",
+                )
+            })?;
+            prep::ConsumptionLog::open(log)?.reserve(&store, &requests)?;
+            Some(store)
+        }
+        None => None,
+    };
+
+    let listener = Arc::new(Listener::bind(&private_conf.listen_addr)?);
+    let hello = Hello::new(public_conf.nodes.len(), Some(vm::prog_hash(&prog)));
+    // without a synchronizer the other nodes may start much later, so we keep trying for longer,
+    // unlike the links between the nodes the link to the synchronizer is not connected again when it breaks
+    let (sync_link, retries) = match &public_conf.sync_addr {
+        Some(sync_addr) => {
+            let sync_key = public_conf
+                .sync_static_key
+                .as_ref()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the public config has no static key for the synchronizer"))?;
+            #[rustfmt::skip]
+            let (sync_stream, session) = wait_start(sync_addr, private_conf.id, &private_conf.signing_key, &private_conf.static_secret, sync_key, &hello)?;
+            (
+                Some(wrap_link::<SyncReplyMsg, SyncMsg>(sync_stream, Some(session), public_conf.max_frame_size)),
+                20,
+            )
+        }
+        None => (None, 1000),
+    };
+
+    #[rustfmt::skip]
+    let stream_map = form_cluster(listener.clone(), private_conf.id, &private_conf.signing_key, &private_conf.static_secret, &public_conf.nodes, &hello, retries)?;
+
+    // a broken link is connected again by the party that made the connection,
+    // the other party accepts the new connection on the same listener
+    let mut accept_chans = HashMap::new();
+    let mut streams = HashMap::new();
+    for (id, (stream, session)) in stream_map {
+        let redial = if id < private_conf.id {
+            let node = public_conf.nodes.iter().find(|x| x.id == id).expect("unknown peer").clone();
+            let (my_id, signing_key, secret, hello) = (
+                private_conf.id,
+                private_conf.signing_key.clone(),
+                private_conf.static_secret.clone(),
+                hello.clone(),
+            );
+            // a peer that does not answer must not hold up the link for longer than its grace period
+            let grace = timeouts.reconnect;
+            Redial::Connect(Box::new(move || {
+                let stream = Stream::connect_timeout(&node.addr, grace)?;
+                set_handshake_timeout(&stream, Some(grace))?;
+                let (stream, session) = connect_peer(stream, my_id, &signing_key, &secret, &node, &hello)?;
+                set_handshake_timeout(&stream, None)?;
+                Ok((stream, Some(session)))
+            }))
+        } else {
+            let (s, r) = bounded(1);
+            accept_chans.insert(id, s);
+            Redial::Accept(r)
+        };
+        streams.insert(id, (stream, Some(session), redial));
     }
-
-    let mut prep_stream = TcpStream::connect(private_conf.prep_addr)?;
-    write_party_id(&mut prep_stream, private_conf.id)?;
-    let (_prep_s, prep_r, prep_shutdown, prep_h) = wrap_tcpstream::<PrepMsg, PrepMsg>(prep_stream);
+    let acceptor = if accept_chans.is_empty() {
+        None
+    } else {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (listener, my_id, secret, nodes, hello) = (
+            listener,
+            private_conf.id,
+            private_conf.static_secret.clone(),
+            public_conf.nodes.clone(),
+            hello.clone(),
+        );
+        let acceptor_stop = stop.clone();
+        listener.set_nonblocking(true)?;
+        let h = thread::spawn(move || accept_reconnections(listener, my_id, secret, nodes, hello, accept_chans, acceptor_stop));
+        Some((stop, h))
+    };
+    let transport = Arc::new(StreamTransport::<PartyMsg, PartyMsg>::resumable(
+        private_conf.id,
+        streams,
+        public_conf.max_frame_size,
+        timeouts.reconnect,
+    )?);
+
+    // request the preprocessing data or read it from the file in the background,
+    // the link to the preprocessing server is not connected again when it breaks either
+    let (prep_s, prep_r) = bounded(prep::MAX_BATCH_SIZE);
+    let (prep_client, prep_link) = match store {
+        Some(store) => (thread::spawn(move || prep::run_store(requests, store, &prep_s)), None),
+        None => {
+            let (prep_stream, session) = connect_prep(&private_conf)?;
+            let (prep_req_s, prep_batch_r, _, prep_shutdown, prep_h) =
+                wrap_link::<PrepRequest, PrepBatch>(prep_stream, Some(session), public_conf.max_frame_size);
+            let prep_client = thread::spawn(move || prep::run_client(requests, &prep_req_s, &prep_batch_r, &prep_s));
+            (prep_client, Some((prep_shutdown, prep_h)))
+        }
+    };
 
     let party_handle = Party::spawn(
         private_conf.id,
-        private_conf.alpha_share.clone(),
+        alpha_share.clone(),
         reg,
         prog,
-        sync_s,
-        sync_r,
+        public_conf.broadcast,
+        timeouts,
+        sync_link.as_ref().map(|(s, r, _, _, _)| (s.clone(), r.clone())),
         prep_r,
-        peer_sender_chans,
-        peer_receiver_chans,
+        transport.clone(),
         seed,
     );
 
-    // shutdown the parties
-    let res = party_handle.join().expect("party thread panicked")?;
-    for chan in peer_shutdown_chans {
-        chan.send(())?;
-    }
-    for h in peer_handlers {
-        h.join().unwrap();
+    // shutdown all the streams even if the party failed or aborted,
+    // the streams might be closed by the other side already so we ignore the send errors
+    let res = party_handle.join().expect("party thread panicked");
+    transport.close();
+
+    // stop accepting new connections, the acceptor notices it within `ACCEPT_INTERVAL`
+    // and the handshakes that are still running time out on their own
+    if let Some((stop, h)) = acceptor {
+        stop.store(true, Ordering::SeqCst);
+        h.join().expect("acceptor thread panicked");
     }
 
     // shutdown the prep
-    prep_shutdown.send(())?;
-    prep_h.join().expect("prep thread panicked");
+    if let Some((prep_shutdown, prep_h)) = prep_link {
+        let _ = prep_shutdown.send(());
+        prep_h.join().expect("prep thread panicked");
+    }
+    if let Err(e) = prep_client.join().expect("prep client thread panicked") {
+        error!("[{}] preprocessing failed: {}", private_conf.id, e);
+    }
 
     // shutdown the sync
-    sync_shutdown.send(())?;
-    sync_h.join().expect("synchronizer thread panicked");
-    Ok(res)
+    if let Some((_, _, _, sync_shutdown, sync_h)) = sync_link {
+        let _ = sync_shutdown.send(());
+        sync_h.join().expect("synchronizer thread panicked");
+    }
+    Ok(res?)
 }
 
-/// Wait for the command from the synchronizer and then start.
-pub fn fake_prep_main(
-    listen_addr: SocketAddr,
-    private_confs: Vec<PrivateConf>,
-    rand_count_per_party: usize,
-    triple_count: usize,
-) -> Result<(), ApplicationError> {
-    let mut alpha = Fp::zero();
+/// Write the configs of a cluster of `n` nodes, the synchronizer and the preprocessing server
+/// on the local host with fresh keys to `out_dir`, together with `launch.sh` that starts all the processes of the cluster.
+/// The synchronizer listens on `base_port`, the preprocessing server on `base_port + 1`
+/// and node `i` on `base_port + 2 + i`.
+/// The MAC key shares are random, `keygen_main` can replace them.
+pub fn setup_main(n: usize, base_port: u16, out_dir: &Path) -> Result<(), ApplicationError> {
+    let addr = |offset: usize| -> Result<Addr, ApplicationError> {
+        let port = usize::from(base_port) + offset;
+        if port > usize::from(u16::MAX) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("port {} is too large", port)).into());
+        }
+        Ok(format!("[::1]:{}", port).parse()?)
+    };
+    let sync_addr = addr(0)?;
+    let prep_addr = addr(1)?;
+
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let (sync_static_key, sync_static_secret) = noise::generate_keypair();
+    let (prep_static_key, prep_static_secret) = noise::generate_keypair();
+    let mut nodes = vec![];
+    let mut private_confs = vec![];
+    for i in 0..n {
+        let listen_addr = addr(2 + i)?;
+        let signing_key = auth::SigningKey::generate(rng);
+        let (static_key, static_secret) = noise::generate_keypair();
+        nodes.push(NodeConf {
+            addr: listen_addr.clone(),
+            id: i as PartyID,
+            static_key,
+            verify_key: signing_key.verify_key(),
+        });
+        private_confs.push(PrivateConf {
+            id: i as PartyID,
+            listen_addr,
+            prep_addr: prep_addr.clone(),
+            prep_static_key: prep_static_key.clone(),
+            alpha_share: Some(Fp::random(rng)),
+            static_secret,
+            signing_key,
+            timeouts: None,
+            prep_file: None,
+            consumption_log: Some(out_dir.join(format!("consumed_{}.bin", i))),
+            alpha_opening: None,
+            alpha_commitments: vec![],
+        });
+    }
+
+    let public_conf = PublicConf {
+        sync_addr: Some(sync_addr.clone()),
+        sync_static_key: Some(sync_static_key),
+        broadcast: BroadcastMode::default(),
+        timeouts: Timeouts::default(),
+        max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        nodes,
+    };
+    write_ron(&out_dir.join("public.ron"), &public_conf, PUBLIC_FILE_MODE)?;
+    let sync_conf = SynchronizerConfig {
+        listen_addr: sync_addr,
+        static_secret: sync_static_secret,
+    };
+    write_ron(&out_dir.join("synchronizer.ron"), &sync_conf, SECRET_FILE_MODE)?;
+    let prep_conf = PrepServerConfig {
+        listen_addr: prep_addr,
+        static_secret: prep_static_secret,
+    };
+    write_ron(&out_dir.join("prep_server.ron"), &prep_conf, SECRET_FILE_MODE)?;
     for conf in &private_confs {
-        alpha += &conf.alpha_share;
+        write_ron(&out_dir.join(format!("private_{}.ron", conf.id)), conf, SECRET_FILE_MODE)?;
     }
+    write_atomic(&out_dir.join("launch.sh"), launch_script(n).as_bytes(), SCRIPT_FILE_MODE)?;
+    info!("wrote the configs of {} nodes to {}", n, out_dir.display());
+    Ok(())
+}
 
-    let mut rng = ChaCha20Rng::from_entropy();
-    let n = private_confs.len();
-    let (rand_shares, triples) = gen_fake_prep(n, &alpha, rand_count_per_party, triple_count, &mut rng);
+// the script starts the processes in the background and waits for them,
+// the inputs of node `i` are taken from the variable `INPUTS_i`
+fn launch_script(n: usize) -> String {
+    let private_files: Vec<_> = (0..n).map(|i| format!("\"$DIR/private_{}.ron\"", i)).collect();
+    let mut s = String::new();
+    s += "#!/bin/sh\n";
+    s += "# usage: launch.sh PROGRAM, the inputs of node i are in the variable INPUTS_i\n";
+    s += "# the binaries are taken from $BIN, which is target/debug by default\n";
+    s += "DIR=$(dirname \"$0\")\n";
+    s += "BIN=${BIN:-target/debug}\n";
+    s += "PROG=${1:?missing program}\n";
+    s += "\"$BIN/synchronizer\" \"$DIR/public.ron\" \"$DIR/synchronizer.ron\" &\n";
+    s += &format!("\"$BIN/fake_prep\" --serve \"$DIR/prep_server.ron\" {} &\n", private_files.join(" "));
+    s += "PREP=$!\n";
+    for (i, f) in private_files.iter().enumerate() {
+        s += &format!("\"$BIN/online_node\" \"$DIR/public.ron\" {} \"$PROG\" $INPUTS_{} &\n", f, i);
+        s += &format!("NODE_{}=$!\n", i);
+    }
+    // the preprocessing server never stops by itself
+    let node_pids: Vec<_> = (0..n).map(|i| format!("$NODE_{}", i)).collect();
+    s += &format!("wait {}\n", node_pids.join(" "));
+    s += "kill $PREP\n";
+    s
+}
 
-    // listen and then wait for all nodes to join
-    let ids: Vec<PartyID> = private_confs.clone().iter().map(|x| x.id).collect();
-    let mut stream_map: HashMap<PartyID, TcpStream> = HashMap::new();
-    let listener = TcpListener::bind(listen_addr)?;
-    for stream_res in listener.incoming() {
-        let mut stream = stream_res?;
-        let candidate_id = read_party_id(&mut stream)?;
-        if ids.contains(&candidate_id) && !stream_map.contains_key(&candidate_id) {
-            info!("[{}] fake prep found party {}", pp(&listener.local_addr()), candidate_id);
-            stream_map.insert(candidate_id, stream);
-        }
+/// Generate the MAC key share of the node with the private config in the file `private_f`
+/// together with the other nodes in `public_conf`, and write the result to the same file.
+pub fn keygen_main(public_conf: PublicConf, private_f: &str) -> Result<(), ApplicationError> {
+    let mut private_conf = PrivateConf::from_file(private_f)?;
+    let listener = Listener::bind(&private_conf.listen_addr)?;
+    let hello = Hello::new(public_conf.nodes.len(), None);
+    // there is no synchronizer, so the other nodes may start much later
+    #[rustfmt::skip]
+    let stream_map = form_cluster(Arc::new(listener), private_conf.id, &private_conf.signing_key, &private_conf.static_secret, &public_conf.nodes, &hello, 1000)?;
+    let transport = StreamTransport::<PartyMsg, PartyMsg>::with_loopback(
+        private_conf.id,
+        stream_map
+            .into_iter()
+            .map(|(id, (stream, session))| (id, (stream, Some(session))))
+            .collect(),
+        public_conf.max_frame_size,
+    )?;
+
+    let timeout = private_conf.effective_timeouts(&public_conf)?.open;
+    let res = keygen::keygen(private_conf.id, &transport, timeout, &mut ChaCha20Rng::from_entropy());
+    transport.close();
+    let share = res?;
+
+    private_conf.alpha_share = Some(share.alpha_share);
+    private_conf.alpha_opening = Some(share.opening);
+    private_conf.alpha_commitments = share.commitments;
+    private_conf.to_file(private_f)?;
+    info!("[{}] wrote the MAC key share to {}", private_conf.id, private_f);
+    Ok(())
+}
 
-        if ids.len() == stream_map.len() {
-            break;
-        }
+/// Run a preprocessing server for the parties in `private_confs`,
+/// it answers the requests of every party that connects and only returns on error.
+/// A party must prove its identity using its signing key and it can only have one connection at a time.
+/// Every link is encrypted using the static key in `conf` and the static key of the party,
+/// so the parties must expect the static key in `conf` in their `prep_static_key`.
+/// A party that is too far ahead of the others waits for them, see `prep::MAX_POOL_SIZE`.
+/// The global MAC key is computed from the MAC key shares of the parties, hence "fake".
+pub fn fake_prep_main(conf: PrepServerConfig, private_confs: Vec<PrivateConf>) -> Result<(), ApplicationError> {
+    let alpha = global_alpha(&private_confs)?;
+    let my_public_key = conf.static_secret.public_key();
+    if let Some(c) = private_confs.iter().find(|c| c.prep_static_key != my_public_key) {
+        let msg = format!("party {} expects a different static key for the preprocessing server", c.id);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg).into());
     }
-
-    let mut stream_vec: Vec<(PartyID, TcpStream)> = stream_map.into_iter().collect();
-    stream_vec.sort_by_key(|x| x.0);
-    // send the rand share
-    for ss in rand_shares {
-        assert_eq!(ss.len(), stream_vec.len());
-        for ((_, stream), s) in stream_vec.iter_mut().zip(ss) {
-            let buf = bincode::serialize(&PrepMsg::RandShare(s)).expect("cannot serialize using bincode");
-            write_length(stream, buf.len())?;
-            stream.write_all(&buf)?;
-        }
+    let nodes: Arc<Vec<NodeConf>> = Arc::new(
+        private_confs
+            .iter()
+            .map(|c| NodeConf {
+                addr: c.listen_addr.clone(),
+                id: c.id,
+                static_key: c.static_secret.public_key(),
+                verify_key: c.signing_key.verify_key(),
+            })
+            .collect(),
+    );
+    let connected = Arc::new(Mutex::new(HashSet::new()));
+
+    let dealer = prep::Dealer::new(private_confs.len(), alpha, ChaCha20Rng::from_entropy());
+    let dealer = Arc::new((Mutex::new(dealer), Condvar::new()));
+    let listener = Listener::bind(&conf.listen_addr)?;
+    loop {
+        let stream = listener.accept()?;
+        let my_key = conf.static_secret.clone();
+        let (nodes, connected, dealer) = (nodes.clone(), connected.clone(), dealer.clone());
+        thread::spawn(move || {
+            if let Err(e) = serve_prep(stream, &my_key, &nodes, &connected, &dealer) {
+                error!("preprocessing connection failed: {}", e);
+            }
+        });
     }
-    // send the triples
-    for ss in triples {
-        assert_eq!(ss.len(), stream_vec.len());
-        for ((_, stream), s) in stream_vec.iter_mut().zip(ss) {
-            let buf = bincode::serialize(&PrepMsg::Triple(s)).expect("cannot serialize using bincode");
-            write_length(stream, buf.len())?;
-            stream.write_all(&buf)?;
+}
+
+/// Generate `triple_count` triples and `rand_share_count` random sharings for every party in `private_confs`
+/// and write the preprocessing data of party `i` to `prep_i.bin` in `out_dir`.
+/// Like `fake_prep_main`, the global MAC key is computed from the MAC key shares of the parties.
+pub fn fake_prep_store_main(
+    out_dir: &Path,
+    private_confs: Vec<PrivateConf>,
+    triple_count: usize,
+    rand_share_count: usize,
+) -> Result<(), ApplicationError> {
+    let n = private_confs.len();
+    let alpha = global_alpha(&private_confs)?;
+
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let (rand_shares, triples) = gen_fake_prep(n, &alpha, rand_share_count, triple_count, rng);
+    for conf in private_confs {
+        let i = conf.id as usize;
+        if i >= n {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("party ID {} is not below {}", i, n)).into());
         }
+        let party_triples = triples.iter().map(|t| t[i].clone()).collect();
+        // the random sharings are ordered by the party that knows the random value
+        let party_rand_shares = (0..n)
+            .map(|owner| {
+                rand_shares[owner * rand_share_count..(owner + 1) * rand_share_count]
+                    .iter()
+                    .map(|r| r[i].clone())
+                    .collect()
+            })
+            .collect();
+        let store = prep::PrepStore::new(conf.id, conf.alpha_share()?, party_triples, party_rand_shares);
+
+        // the file holds the secret shares of the party, so it is replaced atomically and only the owner can read it
+        let path = out_dir.join(format!("prep_{}.bin", conf.id));
+        let mut data = vec![];
+        store.write(&mut data)?;
+        write_atomic(&path, &data, SECRET_FILE_MODE)?;
+        info!("wrote the preprocessing data of party {} to {}", conf.id, path.display());
     }
-    // TODO maybe send periodic preprocessing messages?
     Ok(())
 }
 
+/// Connect to the preprocessing server of `conf`, prove our identity and start an encrypted session,
+/// the server must hold the secret key of `prep_static_key`.
+fn connect_prep(conf: &PrivateConf) -> io::Result<(Stream, noise::Session)> {
+    let mut stream = Stream::connect(&conf.prep_addr)?;
+    set_handshake_timeout(&stream, Some(HANDSHAKE_TIMEOUT))?;
+    auth::prove(&mut stream, conf.id, &conf.signing_key, PREP_CONTEXT)?;
+    let session = noise::handshake_initiator(&mut stream, conf.id, &conf.static_secret, PREP_ID, &conf.prep_static_key)?;
+    set_handshake_timeout(&stream, None)?;
+    Ok((stream, session))
+}
+
+// authenticate the party that connected, start an encrypted session with it
+// and answer its requests until it closes the stream
+fn serve_prep<R: rand::Rng>(
+    mut stream: Stream,
+    my_key: &noise::SecretKey,
+    nodes: &[NodeConf],
+    connected: &Mutex<HashSet<PartyID>>,
+    dealer: &(Mutex<prep::Dealer<R>>, Condvar),
+) -> io::Result<()> {
+    set_handshake_timeout(&stream, Some(HANDSHAKE_TIMEOUT))?;
+    let ids: Vec<PartyID> = nodes.iter().map(|x| x.id).collect();
+    let lookup = |id| lookup_verify_key(nodes, &ids, |x| connected.lock().unwrap().contains(x), id);
+    let id = auth::challenge(&mut stream, PREP_CONTEXT, lookup, &mut ChaCha20Rng::from_entropy())?;
+    // another connection of the same party might have been accepted in the meantime
+    if !connected.lock().unwrap().insert(id) {
+        return Err(auth::AuthError::DuplicateParty(id).into());
+    }
+    let res = serve_party(stream, id, my_key, nodes, dealer);
+    connected.lock().unwrap().remove(&id);
+    res
+}
+
+fn serve_party<R: rand::Rng>(
+    mut stream: Stream,
+    id: PartyID,
+    my_key: &noise::SecretKey,
+    nodes: &[NodeConf],
+    dealer: &(Mutex<prep::Dealer<R>>, Condvar),
+) -> io::Result<()> {
+    let peer_key = &nodes.iter().find(|x| x.id == id).unwrap().static_key;
+    let session = noise::handshake_responder(&mut stream, PREP_ID, my_key, id, peer_key)?;
+    set_handshake_timeout(&stream, None)?;
+    info!("[{}] preprocessing server found party {}", pp(&stream.local_addr()), id);
+    let (batch_s, req_r, _, shutdown, h) = wrap_link::<PrepBatch, PrepRequest>(stream, Some(session), DEFAULT_MAX_FRAME_SIZE);
+    let (lock, cvar) = dealer;
+    let res = req_r.iter().try_for_each(|req| {
+        let batch = cvar.wait_while(lock.lock().unwrap(), |d| !d.ready(id, &req)).unwrap().handle(id, &req)?;
+        // the parties that wait for this one might be able to continue
+        cvar.notify_all();
+        // the stream might be closed already, then the requests stop too
+        let _ = batch_s.send(batch);
+        Ok(())
+    });
+    let _ = shutdown.send(());
+    h.join().expect("prep thread panicked");
+    res
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fault::{self, FaultConfig, FaultTransport};
+    use crate::frame::Header;
     use crossbeam;
     use ron;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+    use std::time::Instant;
     use test_env_log::test;
 
     #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
@@ -543,6 +1422,10 @@ mod tests {
         a: usize,
     }
 
+    impl Tagged for Msg {
+        const TAG: u8 = 42;
+    }
+
     #[test]
     fn test_tcpstream_wrapper() {
         const ADDR: &str = "127.0.0.1:36794"; // consider using port 0 as wildcard
@@ -557,14 +1440,10 @@ mod tests {
             let (mut stream, _) = listener.accept().unwrap();
 
             // write a message
-            let mut msg1_buf = bincode::serialized_size(&MSG1).unwrap().to_le_bytes().to_vec();
-            msg1_buf.extend(bincode::serialize(&MSG1).unwrap());
-            stream.write_all(&msg1_buf).unwrap();
+            frame::write_frame(&mut stream, Msg::TAG, &bincode::serialize(&MSG1).unwrap()).unwrap();
 
             // read a message
-            let read_len = read_length(&mut stream).unwrap();
-            let mut read_buf = vec![0u8; read_len];
-            stream.read_exact(&mut read_buf).unwrap();
+            let read_buf = frame::read_frame(&mut stream, Msg::TAG, DEFAULT_MAX_FRAME_SIZE).unwrap();
             s.send(()).unwrap();
             bincode::deserialize(&read_buf).unwrap()
         });
@@ -574,7 +1453,7 @@ mod tests {
         let stream = TcpStream::connect(ADDR).unwrap();
 
         // test the wrapper, first receive the first message from server
-        let (sender, receiver, shutdown_sender, handle) = wrap_tcpstream::<Msg, Msg>(stream);
+        let (sender, receiver, _, shutdown_sender, handle) = wrap_stream::<Msg, Msg>(Stream::Tcp(stream), None, DEFAULT_MAX_FRAME_SIZE);
         let msg1: Msg = receiver.recv().unwrap();
         assert_eq!(msg1, MSG1);
 
@@ -587,22 +1466,253 @@ mod tests {
         handle.join().unwrap()
     }
 
+    #[test]
+    fn test_encrypted_tcpstream_wrapper() {
+        const ADDR: &str = "127.0.0.1:36795";
+        const MSG1: Msg = Msg { a: 1 };
+        const MSG2: Msg = Msg { a: 2 };
+        let (public0, secret0) = noise::generate_keypair();
+        let (public1, secret1) = noise::generate_keypair();
+
+        let listener = TcpListener::bind(ADDR).unwrap();
+        let server_hdl = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let session = noise::handshake_responder(&mut stream, 1, &secret1, 0, &public0).unwrap();
+            wrap_stream::<Msg, Msg>(Stream::Tcp(stream), Some(session), DEFAULT_MAX_FRAME_SIZE)
+        });
+
+        let mut stream = TcpStream::connect(ADDR).unwrap();
+        let session = noise::handshake_initiator(&mut stream, 0, &secret0, 1, &public1).unwrap();
+        let (sender0, receiver0, _, shutdown_sender0, handle0) = wrap_stream::<Msg, Msg>(Stream::Tcp(stream), Some(session), DEFAULT_MAX_FRAME_SIZE);
+        let (sender1, receiver1, _, shutdown_sender1, handle1) = server_hdl.join().unwrap();
+
+        // send messages in both directions
+        sender0.send(MSG1).unwrap();
+        assert_eq!(receiver1.recv().unwrap(), MSG1);
+        sender1.send(MSG2).unwrap();
+        assert_eq!(receiver0.recv().unwrap(), MSG2);
+
+        shutdown_sender0.send(()).unwrap();
+        shutdown_sender1.send(()).unwrap();
+        handle0.join().unwrap();
+        handle1.join().unwrap();
+    }
+
+    #[test]
+    fn test_stream_transport() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let (client, server) = (Stream::Unix(client), Stream::Unix(server));
+
+        let t0 = StreamTransport::<Msg, Msg>::with_loopback(0, vec![(1, (client, None))].into_iter().collect(), DEFAULT_MAX_FRAME_SIZE).unwrap();
+        let t1 = StreamTransport::<Msg, Msg>::with_loopback(1, vec![(0, (server, None))].into_iter().collect(), DEFAULT_MAX_FRAME_SIZE).unwrap();
+
+        // every party receives the broadcast of both parties, including its own
+        t0.broadcast(Msg { a: 0 }).unwrap();
+        t1.broadcast(Msg { a: 1 }).unwrap();
+        assert_eq!(t0.recv_all(Duration::from_secs(1)).unwrap(), vec![Msg { a: 0 }, Msg { a: 1 }]);
+        assert_eq!(t1.recv_all(Duration::from_secs(1)).unwrap(), vec![Msg { a: 0 }, Msg { a: 1 }]);
+        assert_eq!(t0.send(2, Msg { a: 2 }).unwrap_err(), TransportError::UnknownParty(2));
+
+        t0.close();
+        t1.close();
+        assert_eq!(t0.send(1, Msg { a: 3 }).unwrap_err(), TransportError::Disconnected(1));
+
+        // the streams must cover all the other parties
+        assert!(StreamTransport::<Msg, Msg>::with_loopback(0, HashMap::new(), DEFAULT_MAX_FRAME_SIZE).is_ok());
+        assert!(StreamTransport::<Msg, Msg>::new(HashMap::new(), DEFAULT_MAX_FRAME_SIZE).is_ok());
+        assert!(StreamTransport::<Msg, Msg>::with_loopback(1, HashMap::new(), DEFAULT_MAX_FRAME_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_malformed_stream() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        // dropping the sender would close the stream, so keep it until the violation is seen
+        let (_sender, receiver, errors, shutdown_sender, handle) = wrap_stream::<Msg, Msg>(Stream::Unix(server), None, DEFAULT_MAX_FRAME_SIZE);
+
+        // a `Msg` is encoded in 8 bytes, a shorter payload closes the stream without a panic
+        frame::write_frame(&mut client, Msg::TAG, &[1]).unwrap();
+        assert!(receiver.recv().is_err());
+        assert_eq!(errors.recv().unwrap(), FrameError::BadPayload);
+        shutdown_sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_tampered_stream() {
+        let (public0, secret0) = noise::generate_keypair();
+        let (public1, secret1) = noise::generate_keypair();
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let hdl = thread::spawn(move || noise::handshake_responder(&mut server, 1, &secret1, 0, &public0).map(|s| (server, s)));
+        let (mut enc, _) = noise::handshake_initiator(&mut client, 0, &secret0, 1, &public1).unwrap().split();
+        let (server, session) = hdl.join().unwrap().unwrap();
+        let (_sender, receiver, errors, shutdown_sender, handle) =
+            wrap_stream::<Msg, Msg>(Stream::Unix(server), Some(session), DEFAULT_MAX_FRAME_SIZE);
+
+        // flip a bit of an encrypted message, the peer is reported instead of the stream just closing
+        let mut data = enc.encrypt(&bincode::serialize(&Msg { a: 1 }).unwrap()).unwrap();
+        data[0] ^= 1;
+        frame::write_frame(&mut client, Msg::TAG, &data).unwrap();
+        assert!(receiver.recv().is_err());
+        assert_eq!(errors.recv().unwrap(), FrameError::Unauthenticated);
+        shutdown_sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_stream_transport_violation() {
+        const MAX: usize = 16;
+        for header in [Header::new(Msg::TAG, MAX + 1), Header::new(Msg::TAG + 1, 8)] {
+            let (mut client, server) = UnixStream::pair().unwrap();
+            let t = StreamTransport::<Msg, Msg>::with_loopback(1, vec![(0, (Stream::Unix(server), None))].into_iter().collect(), MAX).unwrap();
+
+            // the peer can neither make us allocate a large buffer nor send a different message type
+            client.write_all(&header.to_bytes()).unwrap();
+            assert_eq!(t.recv_from(0, Duration::from_secs(1)).unwrap_err(), TransportError::BadMessage(0));
+            t.close();
+        }
+    }
+
+    #[test]
+    fn test_fault_stream_transport() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let (client, server) = (Stream::Unix(client), Stream::Unix(server));
+        let conf = FaultConfig {
+            delay: Duration::from_millis(10),
+            disconnect_after: Some(2),
+            ..Default::default()
+        };
+
+        let t0 = FaultTransport::new(
+            StreamTransport::<Msg, Msg>::with_loopback(0, vec![(1, (client, None))].into_iter().collect(), DEFAULT_MAX_FRAME_SIZE).unwrap(),
+            conf,
+        );
+        let t1 = StreamTransport::<Msg, Msg>::with_loopback(1, vec![(0, (server, None))].into_iter().collect(), DEFAULT_MAX_FRAME_SIZE).unwrap();
+
+        t0.broadcast(Msg { a: 0 }).unwrap();
+        assert_eq!(t1.recv_from(0, Duration::from_secs(1)).unwrap(), Msg { a: 0 });
+        assert_eq!(t0.send(1, Msg { a: 1 }).unwrap_err(), TransportError::Disconnected(1));
+        t1.close();
+    }
+
+    #[test]
+    fn test_wire_corruption() {
+        const COUNT: usize = 200;
+        const MAX: usize = 256;
+        let recv_all = |t: &StreamTransport<Msg, Msg>| {
+            let mut received = vec![];
+            loop {
+                match t.recv_from(0, Duration::from_secs(1)) {
+                    Ok(m) => received.push(m),
+                    Err(e) => return (received, e),
+                }
+            }
+        };
+
+        // the header of every frame is corrupted
+        let (client, server) = UnixStream::pair().unwrap();
+        let conf = FaultConfig {
+            wire_corrupt_rate: 1.0,
+            ..Default::default()
+        };
+        let server = fault::fault_stream(Stream::Unix(server), conf).unwrap();
+        let t0 = StreamTransport::<Msg, Msg>::with_loopback(0, vec![(1, (Stream::Unix(client), None))].into_iter().collect(), MAX).unwrap();
+        let t1 = StreamTransport::<Msg, Msg>::with_loopback(1, vec![(0, (server, None))].into_iter().collect(), MAX).unwrap();
+        t0.send(1, Msg { a: 0 }).unwrap();
+        assert_eq!(recv_all(&t1), (vec![], TransportError::BadMessage(0)));
+        t0.close();
+        t1.close();
+
+        // the messages that are delivered on an encrypted link are never corrupted
+        let (public0, secret0) = noise::generate_keypair();
+        let (public1, secret1) = noise::generate_keypair();
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let hdl = thread::spawn(move || noise::handshake_responder(&mut server, 1, &secret1, 0, &public0).map(|s| (server, s)));
+        let client_session = noise::handshake_initiator(&mut client, 0, &secret0, 1, &public1).unwrap();
+        let (server, server_session) = hdl.join().unwrap().unwrap();
+        let conf = FaultConfig {
+            wire_corrupt_rate: 0.01,
+            ..Default::default()
+        };
+        let server = fault::fault_stream(Stream::Unix(server), conf).unwrap();
+        let streams = vec![(1, (Stream::Unix(client), Some(client_session)))];
+        let t0 = StreamTransport::<Msg, Msg>::with_loopback(0, streams.into_iter().collect(), MAX).unwrap();
+        let t1 = StreamTransport::<Msg, Msg>::with_loopback(1, vec![(0, (server, Some(server_session)))].into_iter().collect(), MAX).unwrap();
+        // the link is closed when the corruption is found, so the later messages might not be sent
+        for a in 0..COUNT {
+            let _ = t0.send(1, Msg { a });
+        }
+        let (received, e) = recv_all(&t1);
+        assert_eq!(e, TransportError::BadMessage(0));
+        assert!(received.len() < COUNT);
+        assert!(received.iter().enumerate().all(|(a, m)| *m == Msg { a }));
+        t0.close();
+        t1.close();
+    }
+
     #[test]
     fn test_public_conf() -> Result<(), io::Error> {
         let ron_str = read_to_string("conf/public.ron")?;
         let public_conf: PublicConf = ron::from_str(&ron_str).unwrap();
-        assert_eq!(public_conf.sync_addr, "[::1]:12345".parse().unwrap());
+        assert_eq!(public_conf.sync_addr, Some("[::1]:12345".parse().unwrap()));
+        assert_eq!(public_conf.timeouts, Timeouts::default());
+        assert_eq!(public_conf.max_frame_size, DEFAULT_MAX_FRAME_SIZE);
         assert_eq!(public_conf.nodes.len(), 3);
         assert_eq!(public_conf.nodes[0].addr, "[::1]:14270".parse().unwrap());
         assert_eq!(public_conf.nodes[0].id, 0);
+        assert_eq!(
+            ron::to_string(&public_conf.nodes[0].static_key).unwrap(),
+            "\"oI8VBT5FKSHY3inqm/PTWivRy8x7cb5T7MytLn5b9jc=\""
+        );
         Ok(())
     }
 
+    #[test]
+    fn test_timeouts_conf() {
+        // the missing timeouts take the default value
+        let timeouts: Timeouts = ron::from_str("(open: 5000)").unwrap();
+        assert_eq!(timeouts.open, Duration::from_secs(5));
+        assert_eq!(timeouts.sync, Timeouts::default().sync);
+        assert_eq!(ron::from_str::<Timeouts>(&ron::to_string(&timeouts).unwrap()).unwrap(), timeouts);
+
+        // a broken link must be connected again before opening a value times out,
+        // and opening a value must time out before the synchronizer gives up
+        assert!(Timeouts::default().check().is_ok());
+        assert_eq!(timeouts.check().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let timeouts: Timeouts = ron::from_str("(open: 1000, reconnect: 1000)").unwrap();
+        assert_eq!(timeouts.check().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        // a node may override its timeouts, but the synchronizer uses the sync timeout of the public config
+        let public_conf = PublicConf::from_file("conf/public.ron").unwrap();
+        let mut private_conf = PrivateConf::from_file("conf/private_0.ron").unwrap();
+        assert_eq!(private_conf.effective_timeouts(&public_conf).unwrap(), public_conf.timeouts);
+        let timeouts: Timeouts = ron::from_str("(sync: 20000, open: 10000)").unwrap();
+        assert!(timeouts.check().is_ok());
+        private_conf.timeouts = Some(timeouts);
+        let err = private_conf.effective_timeouts(&public_conf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let timeouts: Timeouts = ron::from_str("(open: 3000, reconnect: 500)").unwrap();
+        private_conf.timeouts = Some(timeouts);
+        assert_eq!(private_conf.effective_timeouts(&public_conf).unwrap(), timeouts);
+    }
+
     #[test]
     fn test_synchronizer_conf() -> Result<(), io::Error> {
         let ron_str = read_to_string("conf/synchronizer.ron")?;
-        let public_conf: SynchronizerConfig = ron::from_str(&ron_str).unwrap();
-        assert_eq!(public_conf.listen_addr, "[::1]:12345".parse().unwrap());
+        let sync_conf: SynchronizerConfig = ron::from_str(&ron_str).unwrap();
+        assert_eq!(sync_conf.listen_addr, "[::1]:12345".parse().unwrap());
+        let public_conf = PublicConf::from_file("conf/public.ron")?;
+        assert_eq!(public_conf.sync_static_key, Some(sync_conf.static_secret.public_key()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_prep_server_conf() -> Result<(), io::Error> {
+        let prep_conf = PrepServerConfig::from_file("conf/prep_server.ron")?;
+        for i in 0..3 {
+            let private_conf = PrivateConf::from_file(&format!("conf/private_{}.ron", i))?;
+            assert_eq!(private_conf.prep_addr, prep_conf.listen_addr);
+            assert_eq!(private_conf.prep_static_key, prep_conf.static_secret.public_key());
+        }
         Ok(())
     }
 
@@ -614,7 +1724,7 @@ mod tests {
             assert_eq!(private_conf.id, 0);
             assert_eq!(private_conf.listen_addr, "[::1]:14270".parse().unwrap());
             assert_eq!(private_conf.prep_addr, "[::1]:44444".parse().unwrap());
-            assert_eq!(private_conf.alpha_share.to_string(), "pv///1kAAABaqJMA8lZUjyAOGp0sDar9nfShXsMscz4=");
+            assert_eq!(private_conf.alpha_share()?.to_string(), "pv///1kAAABaqJMA8lZUjyAOGp0sDar9nfShXsMscz4=");
         }
         {
             let ron_str = read_to_string("conf/private_1.ron")?;
@@ -622,7 +1732,7 @@ mod tests {
             assert_eq!(private_conf.id, 1);
             assert_eq!(private_conf.listen_addr, "[::1]:14271".parse().unwrap());
             assert_eq!(private_conf.prep_addr, "[::1]:44444".parse().unwrap());
-            assert_eq!(private_conf.alpha_share.to_string(), "ngS++JPaVER19LutvDJ9jvbvY2jAD3034Ql2d4InXz8=");
+            assert_eq!(private_conf.alpha_share()?.to_string(), "ngS++JPaVER19LutvDJ9jvbvY2jAD3034Ql2d4InXz8=");
         }
         {
             let ron_str = read_to_string("conf/private_2.ron")?;
@@ -630,26 +1740,207 @@ mod tests {
             assert_eq!(private_conf.id, 2);
             assert_eq!(private_conf.listen_addr, "[::1]:14272".parse().unwrap());
             assert_eq!(private_conf.prep_addr, "[::1]:44444".parse().unwrap());
-            assert_eq!(private_conf.alpha_share.to_string(), "3gvur5vUxXSXFe+R0cd8QWhmN6rUMgDhIPWmvXnU714=");
+            assert_eq!(private_conf.alpha_share()?.to_string(), "3gvur5vUxXSXFe+R0cd8QWhmN6rUMgDhIPWmvXnU714=");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_private_conf_alpha_share() -> Result<(), ApplicationError> {
+        let dir = std::env::temp_dir().join(format!("ezmpc_test_private_conf_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let f = dir.join("private_0.ron");
+        let f = f.to_str().unwrap();
+
+        // the config is replaced without leaving temporary files behind
+        let mut conf = PrivateConf::from_file("conf/private_0.ron")?;
+        conf.alpha_share = None;
+        conf.to_file(f)?;
+        conf.to_file(f)?;
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+        assert_eq!(std::fs::metadata(f)?.permissions().mode() & 0o777, SECRET_FILE_MODE);
+
+        // a missing MAC key share is not taken as zero
+        let conf = PrivateConf::from_file(f)?;
+        assert!(conf.alpha_share.is_none());
+        assert_eq!(conf.alpha_share().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let public_conf = PublicConf::from_file("conf/public.ron")?;
+        let prog = vec![vm::Instruction::Stop];
+        let reg = create_register(0, &prog, vec![])?;
+        assert!(online_node_main(public_conf, conf, reg, prog, None).is_err());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_global_alpha() -> Result<(), io::Error> {
+        let rng = &mut ChaCha20Rng::from_entropy();
+        let mut confs = vec![];
+        for i in 0..3 {
+            confs.push(PrivateConf::from_file(&format!("conf/private_{}.ron", i))?);
+        }
+        let shares: Vec<Fp> = confs.iter().map(|c| c.alpha_share().unwrap().clone()).collect();
+        let expected = &(&shares[0] + &shares[1]) + &shares[2];
+        assert_eq!(global_alpha(&confs)?, expected);
+
+        // commit to the shares like the key generation does, the result must survive the private config
+        let scheme = commit::Scheme {};
+        let (commitments, openings): (Vec<_>, Vec<_>) = shares.iter().map(|x| scheme.commit(x.clone(), rng)).unzip();
+        for (conf, opening) in confs.iter_mut().zip(openings) {
+            conf.alpha_opening = Some(opening);
+            conf.alpha_commitments = commitments.clone();
+            *conf = ron::from_str(&ron::to_string(conf).unwrap()).unwrap();
+        }
+        assert_eq!(global_alpha(&confs)?, expected);
+
+        // a share that is changed after the key generation is found
+        confs[1].alpha_share = Some(&shares[1] + &Fp::random(rng));
+        assert_eq!(global_alpha(&confs).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // a missing share is not taken as zero
+        confs[1].alpha_share = None;
+        assert_eq!(global_alpha(&confs).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn test_setup() -> Result<(), ApplicationError> {
+        let dir = std::env::temp_dir().join(format!("ezmpc_test_setup_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        setup_main(3, 24000, &dir)?;
+
+        let public_conf = PublicConf::from_file(dir.join("public.ron").to_str().unwrap())?;
+        let sync_conf = SynchronizerConfig::from_file(dir.join("synchronizer.ron").to_str().unwrap())?;
+        let prep_conf = PrepServerConfig::from_file(dir.join("prep_server.ron").to_str().unwrap())?;
+        assert_eq!(public_conf.sync_addr, Some(sync_conf.listen_addr));
+        assert_eq!(public_conf.sync_static_key, Some(sync_conf.static_secret.public_key()));
+        assert_eq!(public_conf.nodes.len(), 3);
+        for (i, node) in public_conf.nodes.iter().enumerate() {
+            let private_conf = PrivateConf::from_file(dir.join(format!("private_{}.ron", i)).to_str().unwrap())?;
+            assert_eq!(private_conf.id, node.id);
+            assert_eq!(private_conf.listen_addr, node.addr);
+            assert_eq!(private_conf.prep_addr, prep_conf.listen_addr);
+            assert_eq!(private_conf.prep_static_key, prep_conf.static_secret.public_key());
+            assert_eq!(private_conf.static_secret.public_key(), node.static_key);
+            assert_eq!(private_conf.consumption_log, Some(dir.join(format!("consumed_{}.bin", i))));
+            assert_eq!(node.addr, format!("[::1]:{}", 24002 + i).parse().unwrap());
+            assert!(private_conf.signing_key.verify_key() == node.verify_key);
+        }
+        assert_eq!(prep_conf.listen_addr, "[::1]:24001".parse().unwrap());
+        assert!(read_to_string(dir.join("launch.sh"))?.contains("private_2.ron"));
+
+        // only the owner can read the files with secrets, and the launch script can be executed
+        let mode = |name: &str| std::fs::metadata(dir.join(name)).map(|m| m.permissions().mode() & 0o777);
+        for name in ["private_0.ron", "synchronizer.ron", "prep_server.ron"] {
+            assert_eq!(mode(name)?, SECRET_FILE_MODE);
+        }
+        assert_ne!(mode("launch.sh")? & 0o100, 0);
+
+        // the ports must fit
+        assert!(setup_main(3, u16::MAX - 3, &dir).is_err());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_fake_prep_store() -> Result<(), ApplicationError> {
+        let dir = std::env::temp_dir().join(format!("ezmpc_test_fake_prep_store_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let mut private_confs = vec![];
+        for i in 0..3 {
+            private_confs.push(PrivateConf::from_file(&format!("conf/private_{}.ron", i))?);
+        }
+
+        // a file left behind by an earlier run that everybody can read is replaced by one that only the owner can read
+        let old = dir.join("prep_0.bin");
+        std::fs::write(&old, b"old")?;
+        std::fs::set_permissions(&old, std::fs::Permissions::from_mode(0o644))?;
+        fake_prep_store_main(&dir, private_confs, 2, 1)?;
+        for i in 0..3 {
+            let path = dir.join(format!("prep_{}.bin", i));
+            assert_eq!(std::fs::metadata(&path)?.permissions().mode() & 0o777, SECRET_FILE_MODE);
+            let store = prep::PrepStore::read(BufReader::new(File::open(&path)?))?;
+            assert_eq!(store.header().party_id, i as PartyID);
         }
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 3);
+
+        std::fs::remove_dir_all(&dir)?;
         Ok(())
     }
 
+    fn test_nodes(addrs: &[&str]) -> (Vec<NodeConf>, Vec<(auth::SigningKey, noise::SecretKey)>) {
+        let rng = &mut ChaCha20Rng::from_entropy();
+        let mut nodes = vec![];
+        let mut keys = vec![];
+        for (i, addr) in addrs.iter().enumerate() {
+            let signing_key = auth::SigningKey::generate(rng);
+            let (static_key, static_secret) = noise::generate_keypair();
+            nodes.push(NodeConf {
+                addr: addr.parse().unwrap(),
+                id: i as PartyID,
+                static_key,
+                verify_key: signing_key.verify_key(),
+            });
+            keys.push((signing_key, static_secret));
+        }
+        (nodes, keys)
+    }
+
     #[test]
     fn test_discovery() -> Result<(), io::Error> {
         let listen_addr: SocketAddr = "[::1]:12345".parse().unwrap();
-        let target_ids: Vec<PartyID> = vec![0, 1];
-        let sync_handler = thread::spawn(move || start_discovery(listen_addr, &target_ids));
-
-        let mut client_bad = retry_connection(listen_addr, 10, Duration::from_millis(100))?;
-        write_party_id(&mut client_bad, 2)?;
+        let (nodes, keys) = test_nodes(&["[::1]:0", "[::1]:0"]);
+        let hello = Hello::new(nodes.len(), None);
+        let sync_hello = hello.clone();
+        let (sync_public, sync_secret) = noise::generate_keypair();
+        let sync_handler = thread::spawn(move || start_discovery(&listen_addr.into(), &sync_secret, &nodes, &sync_hello));
+
+        // a party that never proves its identity does not block the others
+        let _client_stalled = retry_connection(&listen_addr.into(), 10, Duration::from_millis(100))?;
+
+        // unknown party
+        let mut client_bad = TcpStream::connect(listen_addr)?;
+        let e = auth::prove(&mut client_bad, 2, &keys[0].0, SYNC_CONTEXT).expect_err("remote should reject bad party ID");
+        assert_eq!(auth::auth_error(&e), Some(auth::AuthError::UnknownParty(2)));
         client_bad.read_u8().expect_err("remote should close connection with bad party ID");
 
+        // impostor
+        let mut client_impostor = TcpStream::connect(listen_addr)?;
+        let e = auth::prove(&mut client_impostor, 1, &keys[0].0, SYNC_CONTEXT).expect_err("remote should reject impostor");
+        assert_eq!(auth::auth_error(&e), Some(auth::AuthError::BadSignature(1)));
+
+        // a party that does not hold its static key cannot start the encrypted session
+        // an authenticated party with an incompatible hello is rejected, and it may connect again
+        let mut client_incompatible = TcpStream::connect(listen_addr)?;
+        auth::prove(&mut client_incompatible, 0, &keys[0].0, SYNC_CONTEXT)?;
+        let e = hello::exchange(&mut client_incompatible, &Hello::new(3, None)).expect_err("remote should reject party count");
+        assert_eq!(hello::hello_error(&e), Some(hello::HelloError::PartyCount { ours: 3, theirs: 2 }));
+        client_incompatible
+            .read_u8()
+            .expect_err("remote should close connection with incompatible party");
+
+        let mut client_no_static_key = TcpStream::connect(listen_addr)?;
+        auth::prove(&mut client_no_static_key, 0, &keys[0].0, SYNC_CONTEXT)?;
+        hello::exchange(&mut client_no_static_key, &hello)?;
+        let (_, bad_secret) = noise::generate_keypair();
+        let handshake = noise::handshake_initiator(&mut client_no_static_key, 0, &bad_secret, SYNC_ID, &sync_public);
+        assert!(handshake.is_err(), "remote should reject the wrong static key");
+
         let mut client0 = TcpStream::connect(listen_addr)?;
-        let mut client1 = TcpStream::connect(listen_addr)?;
+        auth::prove(&mut client0, 0, &keys[0].0, SYNC_CONTEXT)?;
+        hello::exchange(&mut client0, &hello)?;
+        noise::handshake_initiator(&mut client0, 0, &keys[0].1, SYNC_ID, &sync_public)?;
+
+        // duplicate
+        let mut client_dup = TcpStream::connect(listen_addr)?;
+        let e = auth::prove(&mut client_dup, 0, &keys[0].0, SYNC_CONTEXT).expect_err("remote should reject duplicate");
+        assert_eq!(auth::auth_error(&e), Some(auth::AuthError::DuplicateParty(0)));
 
-        write_party_id(&mut client0, 0)?;
-        write_party_id(&mut client1, 1)?;
+        let mut client1 = TcpStream::connect(listen_addr)?;
+        auth::prove(&mut client1, 1, &keys[1].0, SYNC_CONTEXT)?;
+        hello::exchange(&mut client1, &hello)?;
+        noise::handshake_initiator(&mut client1, 1, &keys[1].1, SYNC_ID, &sync_public)?;
 
         let v0 = client0.read_u8()?;
         let v1 = client1.read_u8()?;
@@ -660,7 +1951,7 @@ mod tests {
         client1.write_u8(FORM_CLUSTER_ACK)?;
 
         let mut res = sync_handler.join().expect("discovery thread panicked")?;
-        for stream in res.values_mut() {
+        for (stream, _) in res.values_mut() {
             stream.write_u8(88)?;
         }
 
@@ -672,42 +1963,81 @@ mod tests {
     }
 
     #[test]
-    fn test_cluster_formation() -> Result<(), io::Error> {
-        #[rustfmt::skip]
-            let nodes = vec![
-            NodeConf { addr: "[::1]:9000".parse().unwrap(), id: 0 },
-            NodeConf { addr: "[::1]:9111".parse().unwrap(), id: 1 },
-            NodeConf { addr: "[::1]:9222".parse().unwrap(), id: 2 },
-        ];
-        let ids: Vec<PartyID> = nodes.clone().iter().map(|x| x.id).collect();
+    fn test_discovery_stalled_ack() -> Result<(), io::Error> {
+        let listen_addr: SocketAddr = "[::1]:12346".parse().unwrap();
+        let (nodes, keys) = test_nodes(&["[::1]:0", "[::1]:0"]);
+        let hello = Hello::new(nodes.len(), None);
+        let sync_hello = hello.clone();
+        let (sync_public, sync_secret) = noise::generate_keypair();
+        let sync_handler = thread::spawn(move || start_discovery(&listen_addr.into(), &sync_secret, &nodes, &sync_hello));
+
+        let mut clients = vec![];
+        for (id, (signing_key, secret)) in keys.iter().enumerate() {
+            let mut client = retry_connection(&listen_addr.into(), 10, Duration::from_millis(100))?;
+            auth::prove(&mut client, id as PartyID, signing_key, SYNC_CONTEXT)?;
+            hello::exchange(&mut client, &hello)?;
+            noise::handshake_initiator(&mut client, id as PartyID, secret, SYNC_ID, &sync_public)?;
+            clients.push(client);
+        }
 
-        // NOTE socket address must not be reused in test otherwise it'll conflict with other tests
-        // since cargo test runs them in parallel
-        let sync_addr: SocketAddr = "[::1]:12347".parse().unwrap();
-        let synchronizer_handler = thread::spawn(move || start_discovery(sync_addr, &ids));
+        // party 1 receives the 'form cluster' command but never acknowledges it
+        for client in clients.iter_mut() {
+            assert_eq!(client.read_u8()?, FORM_CLUSTER);
+        }
+        clients[0].write_u8(FORM_CLUSTER_ACK)?;
+        let start = Instant::now();
+        let e = match sync_handler.join().expect("discovery thread panicked") {
+            Ok(_) => panic!("discovery should give up"),
+            Err(e) => e,
+        };
+        assert!(matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut));
+        assert!(start.elapsed() < 2 * HANDSHAKE_TIMEOUT);
+        Ok(())
+    }
+
+    fn check_cluster_formation(addrs: &[&str], sync_addr: &str) -> Result<(), io::Error> {
+        let (nodes, keys) = test_nodes(addrs);
+        let hello = Hello::new(nodes.len(), Some(vm::prog_hash(&[vm::Instruction::Stop])));
+        let sync_addr: Addr = sync_addr.parse()?;
+        let sync_nodes = nodes.clone();
+        let discovery_addr = sync_addr.clone();
+        let sync_hello = Hello::new(nodes.len(), None);
+        let (sync_public, sync_secret) = noise::generate_keypair();
+        let synchronizer_handler = thread::spawn(move || start_discovery(&discovery_addr, &sync_secret, &sync_nodes, &sync_hello));
 
         // use a waitgroup to wait for the synchronizer to announce 'form cluster'
         let wg = crossbeam::sync::WaitGroup::new();
         let mut listeners = vec![];
-        for node in &nodes {
-            listeners.push(TcpListener::bind(node.addr)?);
+        for (node, (signing_key, secret)) in nodes.iter().zip(&keys) {
+            listeners.push(Listener::bind(&node.addr)?);
             let wg = wg.clone();
             let id = node.id;
             let sync_addr = sync_addr.clone();
+            let (signing_key, secret, sync_public) = (signing_key.clone(), secret.clone(), sync_public.clone());
+            let hello = hello.clone();
             thread::spawn(move || {
-                let _ = wait_start(sync_addr, id).unwrap();
+                let _ = wait_start(&sync_addr, id, &signing_key, &secret, &sync_public, &hello).unwrap();
                 drop(wg);
             });
         }
         wg.wait();
 
+        // a peer that stalls does not block the cluster formation,
+        // and an unauthenticated peer with an incompatible hello does not make it fail
+        let _stalled = Stream::connect(&nodes[0].addr)?;
+        let mut rogue = Stream::connect(&nodes[0].addr)?;
+        thread::spawn(move || hello::exchange(&mut rogue, &Hello::new(99, None)));
+
         // the nodes start to form cluster
         let mut handlers = vec![];
         let nodes_copy = nodes.clone();
-        for (node, listener) in nodes.iter().zip(listeners) {
+        for ((node, listener), (signing_key, secret)) in nodes.iter().zip(listeners).zip(keys) {
             let id = node.id;
             let nodes_copy = nodes_copy.clone(); // is there a way to avoid multiple clone?
-            let h = thread::spawn(move || form_cluster(listener, id, &nodes_copy).expect("form cluster thread panicked"));
+            let hello = hello.clone();
+            let h = thread::spawn(move || {
+                form_cluster(Arc::new(listener), id, &signing_key, &secret, &nodes_copy, &hello, 20).expect("form cluster thread panicked")
+            });
             handlers.push(h);
         }
 
@@ -720,58 +2050,266 @@ mod tests {
 
         // sending a message from one node should be received by another
         let x = 66u8;
-        stream_maps.get_mut(0).unwrap().get_mut(&1).unwrap().write_u8(x)?;
-        let xx = stream_maps.get_mut(1).unwrap().get_mut(&0).unwrap().read_u8()?;
+        stream_maps.get_mut(0).unwrap().get_mut(&1).unwrap().0.write_u8(x)?;
+        let xx = stream_maps.get_mut(1).unwrap().get_mut(&0).unwrap().0.read_u8()?;
         assert_eq!(x, xx);
 
         let y = 77u8;
-        stream_maps.get_mut(1).unwrap().get_mut(&2).unwrap().write_u8(y)?;
-        let yy = stream_maps.get_mut(2).unwrap().get_mut(&1).unwrap().read_u8()?;
+        stream_maps.get_mut(1).unwrap().get_mut(&2).unwrap().0.write_u8(y)?;
+        let yy = stream_maps.get_mut(2).unwrap().get_mut(&1).unwrap().0.read_u8()?;
         assert_eq!(y, yy);
 
+        // the sessions should be able to talk to each other
+        let (mut enc, _) = stream_maps.get_mut(0).unwrap().remove(&2).unwrap().1.split();
+        let (_, mut dec) = stream_maps.get_mut(2).unwrap().remove(&0).unwrap().1.split();
+        assert_eq!(dec.decrypt(&enc.encrypt(&[x, y])?)?, vec![x, y]);
+
         // the synchronizer should not be listening anymore
-        TcpStream::connect(sync_addr).expect_err("synchronizer should not be listening");
+        Stream::connect(&sync_addr).expect_err("synchronizer should not be listening");
         Ok(())
     }
 
     #[test]
-    fn test_fake_prep() -> Result<(), ApplicationError> {
-        let rand_count_per_party = 1;
-        let triple_count = 2;
-
-        let listen_addr = "127.0.0.1:26889".parse().unwrap();
-        let ron_str = read_to_string("conf/private_0.ron")?;
-        let private_conf: PrivateConf = ron::from_str(&ron_str).unwrap();
-        let my_id = private_conf.id;
-
-        let handler = thread::spawn(move || fake_prep_main(listen_addr, vec![private_conf], rand_count_per_party, triple_count));
-
-        let mut prep_stream = retry_connection(listen_addr, 20, Duration::from_millis(200))?;
-        write_party_id(&mut prep_stream, my_id)?;
-
-        for _i in 0..rand_count_per_party {
-            let len = read_length(&mut prep_stream)?;
-            let mut buf = vec![0u8; len];
-            prep_stream.read_exact(&mut buf)?;
-            let received_rand_share: PrepMsg = bincode::deserialize(&buf)?;
-            match received_rand_share {
-                PrepMsg::Triple(_) => assert!(false, "expected random share"),
-                PrepMsg::RandShare(_) => {}
+    fn test_cluster_formation() -> Result<(), io::Error> {
+        // NOTE socket address must not be reused in test otherwise it'll conflict with other tests
+        // since cargo test runs them in parallel
+        check_cluster_formation(&["[::1]:9000", "[::1]:9111", "[::1]:9222"], "[::1]:12347")
+    }
+
+    #[test]
+    fn test_unix_cluster_formation() -> Result<(), io::Error> {
+        let dir = std::env::temp_dir().join(format!("ezmpc-io-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let addr = |name: &str| format!("unix:{}", dir.join(name).display());
+        let res = check_cluster_formation(&[&addr("0.sock"), &addr("1.sock"), &addr("2.sock")], &addr("sync.sock"));
+        std::fs::remove_dir_all(&dir)?;
+        res
+    }
+
+    #[test]
+    fn test_cluster_formation_program_mismatch() -> Result<(), io::Error> {
+        let (nodes, keys) = test_nodes(&["[::1]:9333", "[::1]:9444"]);
+        let progs = [vec![vm::Instruction::Stop], vec![vm::Instruction::COutput(0), vm::Instruction::Stop]];
+        let mut handlers = vec![];
+        for ((node, (signing_key, secret)), prog) in nodes.iter().zip(keys).zip(progs) {
+            let listener = Listener::bind(&node.addr)?;
+            let (id, nodes) = (node.id, nodes.clone());
+            let hello = Hello::new(nodes.len(), Some(vm::prog_hash(&prog)));
+            handlers.push(thread::spawn(move || {
+                form_cluster(Arc::new(listener), id, &signing_key, &secret, &nodes, &hello, 20)
+            }));
+        }
+
+        // both nodes fail instead of waiting for a compatible peer
+        for h in handlers {
+            match h.join().unwrap() {
+                Err(e) => assert_eq!(hello::hello_error(&e), Some(hello::HelloError::ProgramHash)),
+                Ok(_) => panic!("cluster formation should fail"),
             }
         }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fake_prep() -> Result<(), ApplicationError> {
+        let listen_addr: Addr = "127.0.0.1:26889".parse().unwrap();
+        let (prep_public, prep_secret) = noise::generate_keypair();
+        let mut private_confs = vec![];
+        for i in 0..2 {
+            let ron_str = read_to_string(format!("conf/private_{}.ron", i))?;
+            let mut conf = ron::from_str::<PrivateConf>(&ron_str).unwrap();
+            conf.prep_addr = listen_addr.clone();
+            conf.prep_static_key = prep_public.clone();
+            private_confs.push(conf);
+        }
+
+        // the server never stops, so we do not join it
+        let prep_conf = PrepServerConfig {
+            listen_addr: listen_addr.clone(),
+            static_secret: prep_secret,
+        };
+        let server_confs = private_confs.clone();
+        thread::spawn(move || fake_prep_main(prep_conf, server_confs));
+        retry_connection(&listen_addr, 20, Duration::from_millis(200))?;
+
+        // a party that does not expect the static key of the server cannot connect
+        let mut wrong_conf = private_confs[0].clone();
+        wrong_conf.prep_static_key = noise::generate_keypair().0;
+        assert!(connect_prep(&wrong_conf).is_err(), "the handshake should fail with the wrong server key");
+
+        // a party that does not hold the signing key of the party it claims to be cannot connect
+        let mut impostor_conf = private_confs[1].clone();
+        impostor_conf.signing_key = private_confs[0].signing_key.clone();
+        let e = connect_prep(&impostor_conf).err().expect("remote should reject impostor");
+        assert_eq!(auth::auth_error(&e), Some(auth::AuthError::BadSignature(1)));
+
+        let (prep_stream, session) = connect_prep(&private_confs[1])?;
+
+        // a party can only connect once
+        let e = connect_prep(&private_confs[1]).err().expect("remote should reject duplicate");
+        assert_eq!(auth::auth_error(&e), Some(auth::AuthError::DuplicateParty(1)));
+        let (req_s, batch_r, _, shutdown, h) = wrap_link::<PrepRequest, PrepBatch>(prep_stream, Some(session), DEFAULT_MAX_FRAME_SIZE);
+
+        req_s.send(PrepRequest::RandShares(0, 1)).unwrap();
+        let batch = batch_r.recv().unwrap();
+        assert_eq!(batch.seq, 0);
+        assert!(matches!(&batch.msgs[..], [PrepMsg::RandShare(r)] if r.party_id == 0 && r.clear.is_none()));
+
+        req_s.send(PrepRequest::Triples(2)).unwrap();
+        req_s.send(PrepRequest::Triples(1)).unwrap();
+        let batch = batch_r.recv().unwrap();
+        assert_eq!((batch.seq, batch.msgs.len()), (0, 2));
+        let batch = batch_r.recv().unwrap();
+        assert_eq!((batch.seq, batch.msgs.len()), (2, 1));
+
+        // a bad request closes the stream
+        req_s.send(PrepRequest::RandShares(5, 1)).unwrap();
+        assert!(batch_r.recv().is_err());
+
+        shutdown.send(()).unwrap();
+        h.join().unwrap();
+        Ok(())
+    }
+
+    // forward the connections on a new listener to `target`, the first connection is cut
+    // after it carried `cut_after` bytes in both directions, it returns the address of the proxy
+    // and the number of connections that it accepted
+    fn cutting_proxy(target: Addr, cut_after: usize) -> Result<(Addr, Arc<AtomicUsize>), io::Error> {
+        let listener = Listener::bind(&"[::1]:0".parse()?)?;
+        let addr = listener.local_addr()?;
+        let connections = Arc::new(AtomicUsize::new(0));
+        let count = connections.clone();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let client = match client {
+                    Ok(client) => client,
+                    Err(_) => break,
+                };
+                let limit = if count.fetch_add(1, Ordering::SeqCst) == 0 {
+                    cut_after
+                } else {
+                    usize::MAX
+                };
+                let server = match retry_connection(&target, 20, Duration::from_millis(100)) {
+                    Ok(server) => server,
+                    Err(_) => {
+                        try_shutdown(&client);
+                        continue;
+                    }
+                };
+                let total = Arc::new(AtomicUsize::new(0));
+                for (from, to) in [(client.try_clone().unwrap(), server.try_clone().unwrap()), (server, client)] {
+                    let total = total.clone();
+                    thread::spawn(move || pipe(from, to, &total, limit));
+                }
+            }
+        });
+        Ok((addr, connections))
+    }
 
-        for _i in 0..triple_count {
-            let len = read_length(&mut prep_stream)?;
-            let mut buf = vec![0u8; len];
-            prep_stream.read_exact(&mut buf)?;
-            let received_triple: PrepMsg = bincode::deserialize(&buf)?;
-            match received_triple {
-                PrepMsg::Triple(_) => {}
-                PrepMsg::RandShare(_) => assert!(false, "expected triple"),
+    fn pipe(mut from: Stream, mut to: Stream, total: &AtomicUsize, limit: usize) {
+        let mut buf = [0u8; 4096];
+        while let Ok(n) = io::Read::read(&mut from, &mut buf) {
+            if n == 0 || to.write_all(&buf[..n]).is_err() || total.fetch_add(n, Ordering::SeqCst) + n >= limit {
+                break;
             }
         }
+        try_shutdown(&from);
+        try_shutdown(&to);
+    }
+
+    enum Cut {
+        Party,
+        Sync,
+        Prep,
+    }
+
+    // a multiplication, followed by many openings and triples so that every link carries data for a while
+    fn cut_prog() -> Vec<vm::Instruction> {
+        let mul = &vm::tests::MUL_PROG[..vm::tests::MUL_PROG.len() - 2];
+        let mut prog = mul.to_vec();
+        prog.extend(std::iter::repeat_n(vm::Instruction::Open(11, 10), 30));
+        prog.extend([vm::Instruction::COutput(11), vm::Instruction::SOutput(10), vm::Instruction::Stop]);
+        prog
+    }
+
+    type NodeResults = Vec<Result<Vec<Fp>, ApplicationError>>;
+
+    // run `cut_prog` on a local cluster of three nodes where one link of node 0 goes through `cutting_proxy`,
+    // it returns the results of the nodes and the number of connections of the proxy
+    fn run_cut_cluster(cut: Cut, base_port: u16, cut_after: usize, inputs: [Fp; 2]) -> Result<(NodeResults, usize), ApplicationError> {
+        let dir = std::env::temp_dir().join(format!("ezmpc_test_cut_{}_{}", std::process::id(), base_port));
+        std::fs::create_dir_all(&dir)?;
+        setup_main(3, base_port, &dir)?;
+        let mut public_conf = PublicConf::from_file(dir.join("public.ron").to_str().unwrap())?;
+        let sync_conf = SynchronizerConfig::from_file(dir.join("synchronizer.ron").to_str().unwrap())?;
+        let prep_conf = PrepServerConfig::from_file(dir.join("prep_server.ron").to_str().unwrap())?;
+        let mut private_confs = vec![];
+        for i in 0..3 {
+            private_confs.push(PrivateConf::from_file(dir.join(format!("private_{}.ron", i)).to_str().unwrap())?);
+        }
+        std::fs::remove_dir_all(&dir)?;
+
+        let (proxy_addr, connections) = match cut {
+            Cut::Party => cutting_proxy(public_conf.nodes[0].addr.clone(), cut_after)?,
+            Cut::Sync => cutting_proxy(sync_conf.listen_addr.clone(), cut_after)?,
+            Cut::Prep => cutting_proxy(prep_conf.listen_addr.clone(), cut_after)?,
+        };
+        match cut {
+            Cut::Party => public_conf.nodes[0].addr = proxy_addr,
+            Cut::Sync => public_conf.sync_addr = Some(proxy_addr),
+            Cut::Prep => private_confs[0].prep_addr = proxy_addr,
+        }
 
-        handler.join().unwrap()
+        // the preprocessing server never stops, so we do not join it
+        let server_confs = private_confs.clone();
+        thread::spawn(move || fake_prep_main(prep_conf, server_confs));
+        let sync_public_conf = public_conf.clone();
+        let sync_handle = thread::spawn(move || synchronizer_main(sync_public_conf, sync_conf));
+
+        let prog = cut_prog();
+        let mut handles = vec![];
+        for private_conf in private_confs {
+            let id = private_conf.id;
+            let reg = vm::Reg::from_prog(id, &prog, inputs.get(id as usize).cloned().into_iter().collect())?;
+            let (public_conf, prog) = (public_conf.clone(), prog.clone());
+            handles.push(thread::spawn(move || online_node_main(public_conf, private_conf, reg, prog, None)));
+        }
+        let results = handles.into_iter().map(|h| h.join().expect("node thread panicked")).collect();
+        let _ = sync_handle.join().expect("synchronizer thread panicked");
+        Ok((results, connections.load(Ordering::SeqCst)))
+    }
+
+    #[test]
+    fn test_cluster_resumes_party_link() -> Result<(), ApplicationError> {
+        // the link between node 0 and another node breaks in the middle of the computation,
+        // it is connected again and the computation carries on to the correct outputs
+        let rng = &mut ChaCha20Rng::from_entropy();
+        let inputs = [Fp::random(rng), Fp::random(rng)];
+        let (results, connections) = run_cut_cluster(Cut::Party, 27100, 6000, inputs.clone())?;
+        let outputs = results.into_iter().collect::<Result<Vec<_>, _>>()?;
+        let expected = &inputs[0] * &inputs[1];
+        assert_eq!(
+            crate::sim::combine_outputs(&cut_prog(), &outputs),
+            vec![Some(expected.clone()), Some(expected)]
+        );
+        // nodes 1 and 2 connect to node 0, and one of them connects again
+        assert!(connections > 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cluster_sync_and_prep_links_are_not_resumable() -> Result<(), ApplicationError> {
+        // the links to the synchronizer and to the preprocessing server are not connected again,
+        // the computation fails instead of waiting for them
+        let rng = &mut ChaCha20Rng::from_entropy();
+        // every node connects to the synchronizer, and only node 0 goes through the proxy to the preprocessing server
+        for (cut, base_port, cut_after, expected_connections) in [(Cut::Sync, 27200, 1500, 3), (Cut::Prep, 27300, 500, 1)] {
+            let (results, connections) = run_cut_cluster(cut, base_port, cut_after, [Fp::random(rng), Fp::random(rng)])?;
+            assert!(results.iter().all(|r| r.is_err()));
+            assert_eq!(connections, expected_connections);
+        }
+        Ok(())
     }
 
     #[test]
@@ -0,0 +1,145 @@
+//! This module contains the generation of the MAC key shares.
+//! Every party samples its share of the global MAC key locally
+//! and commits to it, so that the shares can be checked later without revealing them.
+//! All the parties must receive the same commitments, this is checked with an echo broadcast.
+
+use crate::algebra::Fp;
+use crate::crypto::commit::{Commitment, Opening, Scheme};
+use crate::error::{AbortReason, MPCError};
+use crate::message::{PartyID, PartyMsg};
+use crate::transport::Transport;
+
+use log::debug;
+use rand::Rng;
+use sha3::{Digest, Sha3_256};
+use std::time::Duration;
+
+/// The output of the key generation for one party.
+#[derive(Clone, Debug)]
+pub struct KeyShare {
+    pub alpha_share: Fp,
+    /// The opening of the commitment to `alpha_share`.
+    pub opening: Opening,
+    /// The commitments to the MAC key shares of all the parties, indexed by the party ID.
+    pub commitments: Vec<Commitment>,
+}
+
+fn expect<U>(id: PartyID, from: usize, res: Result<U, PartyMsg>) -> Result<U, MPCError> {
+    match res {
+        Ok(x) => Ok(x),
+        Err(PartyMsg::Abort(by, reason)) => Err(MPCError::Aborted { by, reason }),
+        Err(_) => Err(MPCError::Aborted {
+            by: id,
+            reason: AbortReason::UnexpectedMessage(from as PartyID),
+        }),
+    }
+}
+
+/// Run the key generation as the party `id` with the other parties that are connected by `transport`,
+/// which must include a loopback to this party.
+/// Every message is waited for at most `timeout`.
+pub fn keygen<T: Transport<PartyMsg, PartyMsg>>(id: PartyID, transport: &T, timeout: Duration, rng: &mut impl Rng) -> Result<KeyShare, MPCError> {
+    let alpha_share = Fp::random(rng);
+    let (commitment, opening) = Scheme {}.commit(alpha_share.clone(), rng);
+
+    transport.broadcast(PartyMsg::Com(commitment))?;
+    let commitments = transport
+        .recv_all(timeout)?
+        .into_iter()
+        .enumerate()
+        .map(|(from, m)| expect(id, from, m.into_com()))
+        .collect::<Result<Vec<_>, _>>()?;
+    debug!("[{}] received commitments {:?}", id, commitments);
+
+    let h: [u8; 32] = Sha3_256::digest(&bincode::serialize(&commitments).expect("serialization failed")).into();
+    transport.broadcast(PartyMsg::Echo(h))?;
+    for (from, m) in transport.recv_all(timeout)?.into_iter().enumerate() {
+        if expect(id, from, m.into_echo())? != h {
+            return Err(MPCError::Aborted {
+                by: id,
+                reason: AbortReason::Equivocation,
+            });
+        }
+    }
+
+    Ok(KeyShare {
+        alpha_share,
+        opening,
+        commitments,
+    })
+}
+
+/// Check that `alpha_share` is the share of the party `id` that the `commitments` commit to.
+pub fn verify_share(id: PartyID, alpha_share: &Fp, opening: &Opening, commitments: &[Commitment]) -> bool {
+    opening.get_v() == *alpha_share && commitments.get(id as usize).is_some_and(|c| Scheme {}.verify(opening, c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::ChanTransport;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+    use std::thread;
+
+    const TEST_CAP: usize = 10;
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn test_keygen() {
+        let n = 3;
+        let handles: Vec<_> = ChanTransport::mesh(n, TEST_CAP)
+            .into_iter()
+            .enumerate()
+            .map(|(i, t)| {
+                thread::spawn(move || {
+                    let rng = &mut ChaCha20Rng::from_seed([i as u8; 32]);
+                    keygen(i as PartyID, &t, TIMEOUT, rng)
+                })
+            })
+            .collect();
+        let shares: Vec<KeyShare> = handles.into_iter().map(|h| h.join().unwrap().unwrap()).collect();
+
+        for (i, share) in shares.iter().enumerate() {
+            assert_eq!(share.commitments, shares[0].commitments);
+            assert!(verify_share(i as PartyID, &share.alpha_share, &share.opening, &share.commitments));
+            assert!(!verify_share(
+                ((i + 1) % n) as PartyID,
+                &share.alpha_share,
+                &share.opening,
+                &share.commitments
+            ));
+        }
+        assert_ne!(shares[0].alpha_share, shares[1].alpha_share);
+    }
+
+    #[test]
+    fn test_keygen_equivocation() {
+        let mut transports = ChanTransport::mesh(2, TEST_CAP);
+        let cheater = transports.pop().unwrap();
+        let honest = transports.pop().unwrap();
+        let handle = thread::spawn(move || {
+            let rng = &mut ChaCha20Rng::from_seed([0u8; 32]);
+            keygen(0, &honest, TIMEOUT, rng)
+        });
+
+        // the cheater sends a different commitment to itself than to the honest party
+        let rng = &mut ChaCha20Rng::from_seed([1u8; 32]);
+        for to in 0..2 {
+            let (c, _) = Scheme {}.commit(Fp::random(rng), rng);
+            cheater.send(to, PartyMsg::Com(c)).unwrap();
+        }
+        let commitments: Vec<_> = cheater.recv_all(TIMEOUT).unwrap().into_iter().map(|m| m.into_com().unwrap()).collect();
+        let h: [u8; 32] = Sha3_256::digest(&bincode::serialize(&commitments).unwrap()).into();
+        cheater.broadcast(PartyMsg::Echo(h)).unwrap();
+
+        let err = handle.join().unwrap().unwrap_err();
+        assert!(matches!(
+            err,
+            MPCError::Aborted {
+                by: 0,
+                reason: AbortReason::Equivocation
+            }
+        ));
+    }
+}
@@ -1,11 +1,27 @@
 pub mod algebra;
+#[cfg(feature = "async-net")]
+pub mod async_net;
+pub mod auth;
 pub mod crypto;
 pub mod error;
+pub mod fault;
+pub mod frame;
+pub mod hello;
 pub mod io;
+pub mod keygen;
 pub mod message;
+pub mod net;
+pub mod noise;
+pub mod optimizer;
 pub mod party;
+pub mod prep;
+pub mod resume;
+pub mod sim;
 pub mod synchronizer;
+pub mod transport;
 pub mod vm;
 
+#[cfg(test)]
+mod adversary;
 #[cfg(test)]
 mod integration_test;
@@ -3,42 +3,22 @@
 use crate::algebra::Fp;
 use crate::crypto;
 use crate::crypto::commit;
+use crate::error::AbortReason;
 
-use crossbeam::channel::{Receiver, RecvTimeoutError, SendError, Sender};
-use log::debug;
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::ops::Range;
 
 pub type PartyID = u32;
 
-/// Broadcast a message of type `T` to all the channels in `s_chans`.
-pub(crate) fn broadcast<T: Clone + Debug>(s_chans: &Vec<Sender<T>>, m: T) -> Result<(), SendError<T>> {
-    debug!("Broadcasting {:?}", m);
-    for c in s_chans {
-        c.send(m.clone())?;
-    }
-    Ok(())
-}
-
-/// Wait for one message of type `T` from every channel in `r_chans`.
-pub(crate) fn receive<T: Clone + Debug>(r_chans: &Vec<Receiver<T>>, dur: Duration) -> Result<Vec<T>, RecvTimeoutError> {
-    let mut out: Vec<T> = Vec::new();
-    for c in r_chans {
-        let m = c.recv_timeout(dur)?;
-        out.push(m);
-    }
-    debug!("All received {:?}", out);
-    Ok(out)
-}
-
 /// This is the message sent, usually using broadcast,
 /// by the synchronizer to the individual parties.
 #[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
 pub enum SyncMsg {
     Start,
     Next,
-    Abort,
+    /// Stop the computation because the party with the given ID aborted.
+    Abort(PartyID, AbortReason),
 }
 
 /// This is the message send from the parties to the synchronizer.
@@ -46,7 +26,9 @@ pub enum SyncMsg {
 pub enum SyncReplyMsg {
     Ok,
     Done,
-    Abort,
+    /// The computation is aborted by the party with the given ID,
+    /// which is not necessarily the party that sends this message.
+    Abort(PartyID, AbortReason),
 }
 
 /// This is the message sent between the parties themselves.
@@ -55,35 +37,79 @@ pub enum PartyMsg {
     Elem(Fp),
     Com(commit::Commitment),
     Opening(commit::Opening),
+    /// The hash of the messages that a party received in an echo broadcast.
+    Echo([u8; 32]),
+    /// The hash of the program that a party runs, see `vm::prog_hash`.
+    ProgHash([u8; 32]),
+    /// The preprocessing data that a party used so far, it is sent before the MAC check.
+    PrepSeqs(PrepSeqs),
+    /// The computation is aborted by the party with the given ID, the receiver should stop too.
+    Abort(PartyID, AbortReason),
 }
 
 impl PartyMsg {
-    pub(crate) fn unwrap_elem(self) -> Fp {
+    // the following functions return the message itself if it has a different type
+
+    pub(crate) fn into_elem(self) -> Result<Fp, PartyMsg> {
         match self {
-            PartyMsg::Elem(x) => x,
-            e => panic!("expected elem, got {:?}", e),
+            PartyMsg::Elem(x) => Ok(x),
+            e => Err(e),
         }
     }
 
-    pub(crate) fn unwrap_com(self) -> commit::Commitment {
+    pub(crate) fn into_com(self) -> Result<commit::Commitment, PartyMsg> {
         match self {
-            PartyMsg::Com(x) => x,
-            e => panic!("expected com, got {:?}", e),
+            PartyMsg::Com(x) => Ok(x),
+            e => Err(e),
         }
     }
 
-    pub(crate) fn unwrap_opening(self) -> commit::Opening {
+    pub(crate) fn into_echo(self) -> Result<[u8; 32], PartyMsg> {
         match self {
-            PartyMsg::Opening(x) => x,
-            e => panic!("expected opening, got {:?}", e),
+            PartyMsg::Echo(x) => Ok(x),
+            e => Err(e),
         }
     }
+
+    pub(crate) fn into_prog_hash(self) -> Result<[u8; 32], PartyMsg> {
+        match self {
+            PartyMsg::ProgHash(x) => Ok(x),
+            e => Err(e),
+        }
+    }
+
+    pub(crate) fn into_prep_seqs(self) -> Result<PrepSeqs, PartyMsg> {
+        match self {
+            PartyMsg::PrepSeqs(x) => Ok(x),
+            e => Err(e),
+        }
+    }
+
+    pub(crate) fn into_opening(self) -> Result<commit::Opening, PartyMsg> {
+        match self {
+            PartyMsg::Opening(x) => Ok(x),
+            e => Err(e),
+        }
+    }
+}
+
+/// `PrepSeqs` holds the sequence numbers of the triples and of the random sharings of every party
+/// that a VM used, the range of a sequence is empty if none of it is used.
+/// Every VM checks that its own sequences have no gaps,
+/// and the parties compare their `PrepSeqs` to know that they all used the same elements.
+#[derive(Serialize, Deserialize, Clone, Default, Eq, PartialEq, Debug)]
+pub struct PrepSeqs {
+    pub triples: Range<u64>,
+    pub rand_shares: BTreeMap<PartyID, Range<u64>>,
 }
 
 /// This is a share of a Beaver triple where `a * b = c`,
 /// used for computing multiplication.
+/// `seq` is the position of the triple in the sequence of triples,
+/// all the parties must use the triples in this order.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TripleMsg {
+    pub seq: u64,
     pub a: crypto::AuthShare,
     pub b: crypto::AuthShare,
     pub c: crypto::AuthShare,
@@ -92,15 +118,18 @@ pub struct TripleMsg {
 impl TripleMsg {
     /// This function constructs a new triple message,
     /// the shares are assumed to be correct, i.e., `a*b = c`.
-    pub fn new(a: crypto::AuthShare, b: crypto::AuthShare, c: crypto::AuthShare) -> TripleMsg {
-        TripleMsg { a, b, c }
+    pub fn new(seq: u64, a: crypto::AuthShare, b: crypto::AuthShare, c: crypto::AuthShare) -> TripleMsg {
+        TripleMsg { seq, a, b, c }
     }
 }
 
 /// This is a random sharing where only one party knows the random share,
 /// used for inputting a secret value into the MPC.
+/// `seq` is the position of the sharing in the sequence of random sharings known by `party_id`,
+/// all the parties must use the random sharings of a party in this order.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct RandShareMsg {
+    pub seq: u64,
     pub share: crypto::AuthShare,
     pub clear: Option<Fp>,
     pub party_id: PartyID,
@@ -113,14 +142,33 @@ pub enum PrepMsg {
     RandShare(RandShareMsg),
 }
 
+/// This is a request from a party to the preprocessing server.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub enum PrepRequest {
+    /// Ask for the next `k` triples.
+    Triples(usize),
+    /// Ask for the next `k` random sharings where the party with the given ID knows the random value.
+    RandShares(PartyID, usize),
+}
+
+/// This is the reply of the preprocessing server to a `PrepRequest`.
+/// The triples form a sequence, and so do the random sharings of every party,
+/// `seq` is the position of the first message in its sequence.
+/// All the parties receive their shares of the same element at the same position.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PrepBatch {
+    pub seq: u64,
+    pub msgs: Vec<PrepMsg>,
+}
+
 impl PrepMsg {
     /// Create a new preprocessing message containing a triple.
-    pub fn new_triple(a: crypto::AuthShare, b: crypto::AuthShare, c: crypto::AuthShare) -> PrepMsg {
-        PrepMsg::Triple(TripleMsg::new(a, b, c))
+    pub fn new_triple(seq: u64, a: crypto::AuthShare, b: crypto::AuthShare, c: crypto::AuthShare) -> PrepMsg {
+        PrepMsg::Triple(TripleMsg::new(seq, a, b, c))
     }
 
     /// Create a new preprocessing message containing a random sharing.
-    pub fn new_rand_share(share: crypto::AuthShare, clear: Option<Fp>, party_id: PartyID) -> PrepMsg {
-        PrepMsg::RandShare(RandShareMsg { share, clear, party_id })
+    pub fn new_rand_share(seq: u64, share: crypto::AuthShare, clear: Option<Fp>, party_id: PartyID) -> PrepMsg {
+        PrepMsg::RandShare(RandShareMsg { seq, share, clear, party_id })
     }
 }
@@ -0,0 +1,301 @@
+//! This module contains the addresses, listeners and streams that the nodes use to talk to each other.
+//! An address is either a TCP socket address such as `[::1]:14270`
+//! or a Unix domain socket path with the `unix:` prefix such as `unix:/tmp/ezmpc/party0.sock`.
+//! Unix domain sockets are useful when all the parties run on the same host.
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// The address of a node, it is serialized as a string.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Addr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Addr {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            Some("") => Err(io::Error::new(io::ErrorKind::InvalidInput, "empty unix socket path")),
+            Some(path) => Ok(Addr::Unix(PathBuf::from(path))),
+            None => s.parse().map(Addr::Tcp).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e)),
+        }
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Addr::Tcp(addr) => write!(f, "{}", addr),
+            Addr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+impl From<SocketAddr> for Addr {
+    fn from(addr: SocketAddr) -> Self {
+        Addr::Tcp(addr)
+    }
+}
+
+impl Serialize for Addr {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Addr {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        Addr::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
+fn unix_addr(addr: io::Result<std::os::unix::net::SocketAddr>) -> io::Result<Addr> {
+    // the connecting end of a unix socket is usually unnamed
+    addr.map(|x| Addr::Unix(x.as_pathname().map(|p| p.to_path_buf()).unwrap_or_default()))
+}
+
+/// A listener that accepts connections on an `Addr`.
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Start listening on `addr`.
+    /// A stale Unix domain socket left behind by a previous run is removed first,
+    /// but if another process still listens on it then the error is `AddrInUse`.
+    pub fn bind(addr: &Addr) -> io::Result<Listener> {
+        match addr {
+            Addr::Tcp(addr) => TcpListener::bind(addr).map(Listener::Tcp),
+            Addr::Unix(path) => {
+                if let Ok(meta) = fs::symlink_metadata(path) {
+                    if meta.file_type().is_socket() {
+                        match UnixStream::connect(path) {
+                            Ok(_) => return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use", path.display()))),
+                            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+                UnixListener::bind(path).map(|x| Listener::Unix(x, path.clone()))
+            }
+        }
+    }
+
+    /// Accept a new connection.
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(l) => l.accept().map(|(s, _)| Stream::Tcp(s)),
+            Listener::Unix(l, _) => l.accept().map(|(s, _)| Stream::Unix(s)),
+        }
+    }
+
+    /// Return an iterator over the incoming connections, it never returns `None`.
+    pub fn incoming(&self) -> impl Iterator<Item = io::Result<Stream>> + '_ {
+        std::iter::repeat_with(move || self.accept())
+    }
+
+    /// Make `accept` return `WouldBlock` instead of waiting for a connection.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(l) => l.set_nonblocking(nonblocking),
+            Listener::Unix(l, _) => l.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<Addr> {
+        match self {
+            Listener::Tcp(l) => l.local_addr().map(Addr::Tcp),
+            Listener::Unix(_, path) => Ok(Addr::Unix(path.clone())),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// A connected stream, either over TCP or over a Unix domain socket.
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Connect to `addr`.
+    pub fn connect(addr: &Addr) -> io::Result<Stream> {
+        match addr {
+            Addr::Tcp(addr) => TcpStream::connect(addr).map(Stream::Tcp),
+            Addr::Unix(path) => UnixStream::connect(path).map(Stream::Unix),
+        }
+    }
+
+    /// Connect to `addr` like `connect`, but give up on a TCP connection after `timeout`.
+    pub fn connect_timeout(addr: &Addr, timeout: Duration) -> io::Result<Stream> {
+        match addr {
+            Addr::Tcp(addr) => TcpStream::connect_timeout(addr, timeout).map(Stream::Tcp),
+            Addr::Unix(path) => UnixStream::connect(path).map(Stream::Unix),
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(s) => s.try_clone().map(Stream::Tcp),
+            Stream::Unix(s) => s.try_clone().map(Stream::Unix),
+        }
+    }
+
+    /// Set the timeout of the reads, `None` blocks forever.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_read_timeout(timeout),
+            Stream::Unix(s) => s.set_read_timeout(timeout),
+        }
+    }
+
+    /// Set the timeout of the writes, `None` blocks forever.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_write_timeout(timeout),
+            Stream::Unix(s) => s.set_write_timeout(timeout),
+        }
+    }
+
+    /// A stream accepted by a nonblocking listener might be nonblocking too, depending on the platform.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_nonblocking(nonblocking),
+            Stream::Unix(s) => s.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.shutdown(how),
+            Stream::Unix(s) => s.shutdown(how),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<Addr> {
+        match self {
+            Stream::Tcp(s) => s.local_addr().map(Addr::Tcp),
+            Stream::Unix(s) => unix_addr(s.local_addr()),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<Addr> {
+        match self {
+            Stream::Tcp(s) => s.peer_addr().map(Addr::Tcp),
+            Stream::Unix(s) => unix_addr(s.peer_addr()),
+        }
+    }
+}
+
+impl io::Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl io::Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::thread;
+
+    #[test]
+    fn test_addr() {
+        let tcp: Addr = "[::1]:14270".parse().unwrap();
+        assert_eq!(tcp, Addr::Tcp("[::1]:14270".parse().unwrap()));
+        let unix: Addr = "unix:/tmp/ezmpc.sock".parse().unwrap();
+        assert_eq!(unix, Addr::Unix(PathBuf::from("/tmp/ezmpc.sock")));
+        assert!("unix:".parse::<Addr>().is_err());
+        assert!("localhost".parse::<Addr>().is_err());
+
+        for addr in [tcp, unix] {
+            let s = ron::to_string(&addr).unwrap();
+            assert_eq!(ron::from_str::<Addr>(&s).unwrap(), addr);
+        }
+    }
+
+    #[test]
+    fn test_unix_stream() {
+        let path = std::env::temp_dir().join(format!("ezmpc-net-test-{}.sock", std::process::id()));
+        let addr = Addr::Unix(path.clone());
+        let listener = Listener::bind(&addr).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+
+        let hdl = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap();
+            let mut buf = [0u8; 3];
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(&buf).unwrap();
+        });
+
+        let mut stream = Stream::connect(&addr).unwrap();
+        stream.write_all(&[1, 2, 3]).unwrap();
+        let mut buf = [0u8; 3];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+
+        // the socket file is removed when the listener is dropped
+        hdl.join().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_unix_bind() {
+        let path = std::env::temp_dir().join(format!("ezmpc-net-bind-test-{}.sock", std::process::id()));
+        let addr = Addr::Unix(path.clone());
+
+        // a socket that nobody listens on is replaced
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let listener = Listener::bind(&addr).unwrap();
+
+        // but a socket that is in use is not
+        assert_eq!(Listener::bind(&addr).err().unwrap().kind(), io::ErrorKind::AddrInUse);
+        assert!(path.exists());
+        drop(listener);
+        assert!(!path.exists());
+    }
+}
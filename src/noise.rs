@@ -0,0 +1,322 @@
+//! This module implements authenticated encryption for the links between parties
+//! using the Noise protocol framework.
+//! Every party has a long-term static key pair and the public keys are listed in the public configuration.
+//! We use the `KK` handshake pattern since both ends already know the static public key of the other,
+//! so a peer that does not hold the secret key of the party it claims to be cannot complete the handshake.
+
+use crate::error::FrameError;
+use crate::message::PartyID;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use snow::params::DHChoice;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+const NOISE_PARAMS: &str = "Noise_KK_25519_ChaChaPoly_BLAKE2s";
+const PROLOGUE: &[u8] = b"ezmpc";
+const MAX_MSG_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+fn builder() -> Builder<'static> {
+    Builder::new(NOISE_PARAMS.parse().expect("invalid noise parameters"))
+}
+
+fn to_io_error(e: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, e)
+}
+
+fn key_from_str(s: &str) -> Result<[u8; KEY_LEN], String> {
+    let v = base64::decode(s).map_err(|e| e.to_string())?;
+    if v.len() != KEY_LEN {
+        return Err(format!("expected a key of {} bytes, got {} bytes", KEY_LEN, v.len()));
+    }
+    let mut out = [0u8; KEY_LEN];
+    out.copy_from_slice(&v);
+    Ok(out)
+}
+
+pub(crate) fn deserialize_key<'de, D>(d: D) -> Result<[u8; KEY_LEN], D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(d)?;
+    key_from_str(&s).map_err(de::Error::custom)
+}
+
+/// The static public key of a party, it is serialized as a base64 string.
+#[derive(Clone, Eq, PartialEq)]
+pub struct PublicKey([u8; KEY_LEN]);
+
+/// The static secret key of a party, it is serialized as a base64 string.
+#[derive(Clone)]
+pub struct SecretKey([u8; KEY_LEN]);
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&base64::encode(self.0))
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey(..)")
+    }
+}
+
+impl Serialize for PublicKey {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&base64::encode(self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        deserialize_key(d).map(PublicKey)
+    }
+}
+
+impl Serialize for SecretKey {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&base64::encode(self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretKey {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        deserialize_key(d).map(SecretKey)
+    }
+}
+
+impl SecretKey {
+    /// The public key that corresponds to this secret key.
+    pub fn public_key(&self) -> PublicKey {
+        let mut dh = DefaultResolver.resolve_dh(&DHChoice::Curve25519).expect("unsupported key type");
+        dh.set(&self.0);
+        let mut public = [0u8; KEY_LEN];
+        public.copy_from_slice(dh.pubkey());
+        PublicKey(public)
+    }
+}
+
+/// Generate a fresh static key pair.
+pub fn generate_keypair() -> (PublicKey, SecretKey) {
+    let kp = builder().generate_keypair().expect("key generation failed");
+    let mut public = [0u8; KEY_LEN];
+    let mut secret = [0u8; KEY_LEN];
+    public.copy_from_slice(&kp.public);
+    secret.copy_from_slice(&kp.private);
+    (PublicKey(public), SecretKey(secret))
+}
+
+// the prologue binds the claimed identities to the handshake
+fn prologue(initiator: PartyID, responder: PartyID) -> Vec<u8> {
+    let mut out = PROLOGUE.to_vec();
+    out.write_u32::<LittleEndian>(initiator).unwrap();
+    out.write_u32::<LittleEndian>(responder).unwrap();
+    out
+}
+
+fn write_handshake_msg<W: io::Write>(writer: &mut W, hs: &mut HandshakeState) -> io::Result<()> {
+    let mut buf = vec![0u8; MAX_MSG_LEN];
+    let n = hs.write_message(&[], &mut buf).map_err(to_io_error)?;
+    writer.write_u16::<LittleEndian>(n as u16)?;
+    writer.write_all(&buf[..n])
+}
+
+fn read_handshake_msg<R: io::Read>(reader: &mut R, hs: &mut HandshakeState) -> io::Result<()> {
+    let n = reader.read_u16::<LittleEndian>()? as usize;
+    let mut msg = vec![0u8; n];
+    reader.read_exact(&mut msg)?;
+    let mut buf = vec![0u8; MAX_MSG_LEN];
+    hs.read_message(&msg, &mut buf).map_err(to_io_error)?;
+    Ok(())
+}
+
+/// Run the handshake as the party that opened the connection.
+/// The handshake fails if the peer does not hold the secret key that corresponds to `peer_key`.
+pub fn handshake_initiator<S: io::Read + io::Write>(
+    stream: &mut S,
+    my_id: PartyID,
+    my_key: &SecretKey,
+    peer_id: PartyID,
+    peer_key: &PublicKey,
+) -> io::Result<Session> {
+    let prologue = prologue(my_id, peer_id);
+    let mut hs = builder()
+        .local_private_key(&my_key.0)
+        .remote_public_key(&peer_key.0)
+        .prologue(&prologue)
+        .build_initiator()
+        .map_err(to_io_error)?;
+    write_handshake_msg(stream, &mut hs)?;
+    read_handshake_msg(stream, &mut hs)?;
+    Session::new(hs)
+}
+
+/// Run the handshake as the party that accepted the connection.
+/// The handshake fails if the peer does not hold the secret key that corresponds to `peer_key`.
+pub fn handshake_responder<S: io::Read + io::Write>(
+    stream: &mut S,
+    my_id: PartyID,
+    my_key: &SecretKey,
+    peer_id: PartyID,
+    peer_key: &PublicKey,
+) -> io::Result<Session> {
+    let prologue = prologue(peer_id, my_id);
+    let mut hs = builder()
+        .local_private_key(&my_key.0)
+        .remote_public_key(&peer_key.0)
+        .prologue(&prologue)
+        .build_responder()
+        .map_err(to_io_error)?;
+    read_handshake_msg(stream, &mut hs)?;
+    write_handshake_msg(stream, &mut hs)?;
+    Session::new(hs)
+}
+
+/// A session is the result of a successful handshake,
+/// it can be split into an `Encryptor` and a `Decryptor` so that the two directions can be handled by different threads.
+pub struct Session {
+    state: Arc<StatelessTransportState>,
+}
+
+impl Session {
+    fn new(hs: HandshakeState) -> io::Result<Session> {
+        let state = hs.into_stateless_transport_mode().map_err(to_io_error)?;
+        Ok(Session { state: Arc::new(state) })
+    }
+
+    /// Split the session into the sending and the receiving half.
+    pub fn split(self) -> (Encryptor, Decryptor) {
+        (
+            Encryptor {
+                state: self.state.clone(),
+                nonce: 0,
+            },
+            Decryptor { state: self.state, nonce: 0 },
+        )
+    }
+}
+
+/// The sending half of a session.
+pub struct Encryptor {
+    state: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl Encryptor {
+    /// Encrypt `data`, the data is split into chunks if it does not fit in a single Noise message.
+    pub fn encrypt(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len() + TAG_LEN * (data.len() / (MAX_MSG_LEN - TAG_LEN) + 1));
+        let mut buf = vec![0u8; MAX_MSG_LEN];
+        // an empty message is still encrypted so that it is authenticated
+        let chunks: Vec<&[u8]> = if data.is_empty() {
+            vec![data]
+        } else {
+            data.chunks(MAX_MSG_LEN - TAG_LEN).collect()
+        };
+        for chunk in chunks {
+            let n = self.state.write_message(self.nonce, chunk, &mut buf).map_err(to_io_error)?;
+            self.nonce += 1;
+            out.extend_from_slice(&buf[..n]);
+        }
+        Ok(out)
+    }
+}
+
+/// The receiving half of a session.
+pub struct Decryptor {
+    state: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl Decryptor {
+    /// Decrypt and authenticate data produced by `Encryptor::encrypt`,
+    /// data that was tampered with is a protocol violation, see `FrameError::Unauthenticated`.
+    pub fn decrypt(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len());
+        let mut buf = vec![0u8; MAX_MSG_LEN];
+        for chunk in data.chunks(MAX_MSG_LEN) {
+            let n = self
+                .state
+                .read_message(self.nonce, chunk, &mut buf)
+                .map_err(|_| FrameError::Unauthenticated)?;
+            self.nonce += 1;
+            out.extend_from_slice(&buf[..n]);
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    fn connect(
+        initiator_key: SecretKey,
+        responder_key: SecretKey,
+        expected_initiator: PublicKey,
+        expected_responder: PublicKey,
+    ) -> (io::Result<Session>, io::Result<Session>) {
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+        let hdl = thread::spawn(move || handshake_responder(&mut b, 1, &responder_key, 0, &expected_initiator));
+        let initiator = handshake_initiator(&mut a, 0, &initiator_key, 1, &expected_responder);
+        // shutdown so that the responder does not wait forever when the initiator fails
+        a.shutdown(std::net::Shutdown::Both).unwrap();
+        (initiator, hdl.join().unwrap())
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (public0, secret0) = generate_keypair();
+        let (public1, secret1) = generate_keypair();
+        let (s0, s1) = connect(secret0, secret1, public0, public1);
+        let (mut enc, _) = s0.unwrap().split();
+        let (_, mut dec) = s1.unwrap().split();
+
+        // check messages that need to be split into multiple chunks
+        for msg in [vec![], vec![1u8; 10], vec![2u8; MAX_MSG_LEN * 2 + 1]] {
+            let ciphertext = enc.encrypt(&msg).unwrap();
+            assert_ne!(ciphertext, msg);
+            assert_eq!(dec.decrypt(&ciphertext).unwrap(), msg);
+        }
+
+        // tampered messages must be rejected
+        let mut ciphertext = enc.encrypt(&[3u8; 10]).unwrap();
+        ciphertext[0] ^= 1;
+        dec.decrypt(&ciphertext).expect_err("tampered message should fail to decrypt");
+    }
+
+    #[test]
+    fn test_impostor() {
+        let (public0, _) = generate_keypair();
+        let (public1, secret1) = generate_keypair();
+        let (_, impostor_secret) = generate_keypair();
+        let (s0, s1) = connect(impostor_secret, secret1, public0, public1);
+        assert!(s0.is_err());
+        assert!(s1.is_err());
+    }
+
+    #[test]
+    fn test_key_serde() {
+        let (public, secret) = generate_keypair();
+        let public_str = ron::to_string(&public).unwrap();
+        let secret_str = ron::to_string(&secret).unwrap();
+        assert_eq!(ron::from_str::<PublicKey>(&public_str).unwrap(), public);
+        assert_eq!(ron::from_str::<SecretKey>(&secret_str).unwrap().0, secret.0);
+        assert!(ron::from_str::<PublicKey>("\"AAAA\"").is_err());
+    }
+
+    #[test]
+    fn test_public_key() {
+        let (public, secret) = generate_keypair();
+        assert_eq!(secret.public_key(), public);
+    }
+}
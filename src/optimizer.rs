@@ -0,0 +1,288 @@
+//! This module contains a simple dataflow optimizer for VM programs.
+//! It performs common subexpression elimination followed by dead code elimination.
+//! Only instructions that are local to a party (e.g. `SAdd`, `MMul`) are candidates for removal,
+//! instructions that communicate or consume preprocessing (`Input`, `Triple`, `Open`)
+//! are always kept and never reordered, so the optimized program reveals exactly what the original would.
+
+use crate::message::PartyID;
+use crate::vm::{Instruction, RegAddr};
+
+use std::collections::{HashMap, HashSet};
+
+/// A location in the register file of the VM.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+enum Loc {
+    Clear(RegAddr),
+    Secret(RegAddr),
+}
+
+/// A side-effect free computation, the operands are the locations after copy propagation.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+enum Expr {
+    CAdd(Loc, Loc),
+    CSub(Loc, Loc),
+    CMul(Loc, Loc),
+    SAdd(Loc, Loc),
+    SSub(Loc, Loc),
+    MAdd(Loc, Loc, PartyID),
+    MMul(Loc, Loc),
+}
+
+impl Expr {
+    fn operands(&self) -> [Loc; 2] {
+        match self {
+            Expr::CAdd(a, b) | Expr::CSub(a, b) | Expr::CMul(a, b) | Expr::SAdd(a, b) | Expr::SSub(a, b) | Expr::MMul(a, b) => [*a, *b],
+            Expr::MAdd(a, b, _) => [*a, *b],
+        }
+    }
+}
+
+// sort the operands of commutative operations so that `x + y` and `y + x` are the same expression
+fn sorted(a: Loc, b: Loc) -> (Loc, Loc) {
+    let key = |l: &Loc| match l {
+        Loc::Clear(r) => (0, *r),
+        Loc::Secret(r) => (1, *r),
+    };
+    if key(&a) <= key(&b) {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+/// Returns the locations read by an instruction.
+fn reads(inst: &Instruction) -> Vec<Loc> {
+    match *inst {
+        Instruction::CAdd(_, r1, r2) | Instruction::CSub(_, r1, r2) | Instruction::CMul(_, r1, r2) => vec![Loc::Clear(r1), Loc::Clear(r2)],
+        Instruction::SAdd(_, r1, r2) | Instruction::SSub(_, r1, r2) => vec![Loc::Secret(r1), Loc::Secret(r2)],
+        Instruction::MAdd(_, r1, r2, _) | Instruction::MMul(_, r1, r2) => vec![Loc::Secret(r1), Loc::Clear(r2)],
+        Instruction::Input(_, r1, _) => vec![Loc::Clear(r1)],
+        Instruction::Triple(_, _, _) => vec![],
+        Instruction::Open(_, r1) => vec![Loc::Secret(r1)],
+        Instruction::COutput(r) => vec![Loc::Clear(r)],
+        Instruction::SOutput(r) => vec![Loc::Secret(r)],
+        Instruction::Stop => vec![],
+    }
+}
+
+/// Returns the locations written by an instruction.
+fn writes(inst: &Instruction) -> Vec<Loc> {
+    match *inst {
+        Instruction::CAdd(r0, _, _) | Instruction::CSub(r0, _, _) | Instruction::CMul(r0, _, _) => vec![Loc::Clear(r0)],
+        Instruction::SAdd(r0, _, _) | Instruction::SSub(r0, _, _) | Instruction::MAdd(r0, _, _, _) | Instruction::MMul(r0, _, _) => {
+            vec![Loc::Secret(r0)]
+        }
+        Instruction::Input(r0, _, _) => vec![Loc::Secret(r0)],
+        Instruction::Triple(r0, r1, r2) => vec![Loc::Secret(r0), Loc::Secret(r1), Loc::Secret(r2)],
+        Instruction::Open(r0, _) => vec![Loc::Clear(r0)],
+        Instruction::COutput(_) | Instruction::SOutput(_) | Instruction::Stop => vec![],
+    }
+}
+
+/// Returns the expression computed by an instruction if it has no side effects.
+fn expr(inst: &Instruction) -> Option<Expr> {
+    match *inst {
+        Instruction::CAdd(_, r1, r2) => {
+            let (a, b) = sorted(Loc::Clear(r1), Loc::Clear(r2));
+            Some(Expr::CAdd(a, b))
+        }
+        Instruction::CSub(_, r1, r2) => Some(Expr::CSub(Loc::Clear(r1), Loc::Clear(r2))),
+        Instruction::CMul(_, r1, r2) => {
+            let (a, b) = sorted(Loc::Clear(r1), Loc::Clear(r2));
+            Some(Expr::CMul(a, b))
+        }
+        Instruction::SAdd(_, r1, r2) => {
+            let (a, b) = sorted(Loc::Secret(r1), Loc::Secret(r2));
+            Some(Expr::SAdd(a, b))
+        }
+        Instruction::SSub(_, r1, r2) => Some(Expr::SSub(Loc::Secret(r1), Loc::Secret(r2))),
+        Instruction::MAdd(_, r1, r2, id) => Some(Expr::MAdd(Loc::Secret(r1), Loc::Clear(r2), id)),
+        Instruction::MMul(_, r1, r2) => Some(Expr::MMul(Loc::Secret(r1), Loc::Clear(r2))),
+        _ => None,
+    }
+}
+
+/// Rewrite the operands of an instruction using `f`, the destination registers are unchanged.
+fn rewrite_reads<F>(inst: &Instruction, f: F) -> Instruction
+where
+    F: Fn(Loc) -> Loc,
+{
+    let clear = |r: RegAddr| match f(Loc::Clear(r)) {
+        Loc::Clear(x) => x,
+        Loc::Secret(_) => panic!("clear register aliased to a secret register"),
+    };
+    let secret = |r: RegAddr| match f(Loc::Secret(r)) {
+        Loc::Secret(x) => x,
+        Loc::Clear(_) => panic!("secret register aliased to a clear register"),
+    };
+    match *inst {
+        Instruction::CAdd(r0, r1, r2) => Instruction::CAdd(r0, clear(r1), clear(r2)),
+        Instruction::CSub(r0, r1, r2) => Instruction::CSub(r0, clear(r1), clear(r2)),
+        Instruction::CMul(r0, r1, r2) => Instruction::CMul(r0, clear(r1), clear(r2)),
+        Instruction::SAdd(r0, r1, r2) => Instruction::SAdd(r0, secret(r1), secret(r2)),
+        Instruction::SSub(r0, r1, r2) => Instruction::SSub(r0, secret(r1), secret(r2)),
+        Instruction::MAdd(r0, r1, r2, id) => Instruction::MAdd(r0, secret(r1), clear(r2), id),
+        Instruction::MMul(r0, r1, r2) => Instruction::MMul(r0, secret(r1), clear(r2)),
+        Instruction::Input(r0, r1, id) => Instruction::Input(r0, clear(r1), id),
+        Instruction::Triple(r0, r1, r2) => Instruction::Triple(r0, r1, r2),
+        Instruction::Open(r0, r1) => Instruction::Open(r0, secret(r1)),
+        Instruction::COutput(r) => Instruction::COutput(clear(r)),
+        Instruction::SOutput(r) => Instruction::SOutput(secret(r)),
+        Instruction::Stop => Instruction::Stop,
+    }
+}
+
+/// Reuse the result of identical side-effect free computations.
+/// When a register holds a value that was already computed into another register,
+/// later reads are redirected to the earlier register.
+/// The redundant instruction is left in place so that it can be removed by `eliminate_dead_code`.
+pub fn eliminate_common_subexpressions(prog: &[Instruction]) -> Vec<Instruction> {
+    let mut aliases: HashMap<Loc, Loc> = HashMap::new();
+    let mut exprs: HashMap<Expr, Loc> = HashMap::new();
+    let mut out = Vec::with_capacity(prog.len());
+
+    for inst in prog {
+        let inst = rewrite_reads(inst, |l| *aliases.get(&l).unwrap_or(&l));
+
+        // a write invalidates every alias and expression that involves the destination
+        for dest in writes(&inst) {
+            aliases.retain(|k, v| *k != dest && *v != dest);
+            exprs.retain(|e, v| *v != dest && !e.operands().contains(&dest));
+        }
+
+        if let Some(e) = expr(&inst) {
+            let dest = writes(&inst)[0];
+            match exprs.get(&e) {
+                Some(l) => {
+                    aliases.insert(dest, *l);
+                }
+                None => {
+                    // the operands refer to the old value if the destination is also an operand
+                    if !e.operands().contains(&dest) {
+                        exprs.insert(e, dest);
+                    }
+                }
+            }
+        }
+        out.push(inst);
+    }
+    out
+}
+
+/// Remove side-effect free instructions whose results never reach an `SOutput` or a `COutput`.
+/// Instructions that communicate or consume preprocessing are never removed.
+pub fn eliminate_dead_code(prog: &[Instruction]) -> Vec<Instruction> {
+    let mut live: HashSet<Loc> = HashSet::new();
+    let mut out = Vec::with_capacity(prog.len());
+
+    for inst in prog.iter().rev() {
+        let ws = writes(inst);
+        if expr(inst).is_some() && !ws.iter().any(|w| live.contains(w)) {
+            continue;
+        }
+        for w in ws {
+            live.remove(&w);
+        }
+        live.extend(reads(inst));
+        out.push(inst.clone());
+    }
+    out.reverse();
+    out
+}
+
+/// Optimize a program by running common subexpression elimination and then dead code elimination.
+/// The optimization is deterministic, so every party that optimizes the same program obtains the same result.
+pub fn optimize(prog: &[Instruction]) -> Vec<Instruction> {
+    eliminate_dead_code(&eliminate_common_subexpressions(prog))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::tests::{IO_PROG, MUL_PROG};
+
+    #[test]
+    fn test_dead_code() {
+        let prog = vec![
+            Instruction::SAdd(2, 0, 1),
+            Instruction::SSub(3, 0, 1), // never used
+            Instruction::CMul(4, 0, 1), // overwritten before use
+            Instruction::CAdd(4, 0, 1),
+            Instruction::COutput(4),
+            Instruction::SOutput(2),
+            Instruction::Stop,
+        ];
+        let expected = vec![
+            Instruction::SAdd(2, 0, 1),
+            Instruction::CAdd(4, 0, 1),
+            Instruction::COutput(4),
+            Instruction::SOutput(2),
+            Instruction::Stop,
+        ];
+        assert_eq!(optimize(&prog), expected);
+    }
+
+    #[test]
+    fn test_common_subexpression() {
+        let prog = vec![
+            Instruction::SAdd(2, 0, 1),
+            Instruction::SAdd(3, 1, 0),
+            Instruction::MMul(4, 3, 0),
+            Instruction::MMul(5, 2, 0),
+            Instruction::SOutput(4),
+            Instruction::SOutput(5),
+            Instruction::Stop,
+        ];
+        let expected = vec![
+            Instruction::SAdd(2, 0, 1),
+            Instruction::MMul(4, 2, 0),
+            Instruction::SOutput(4),
+            Instruction::SOutput(4),
+            Instruction::Stop,
+        ];
+        assert_eq!(optimize(&prog), expected);
+    }
+
+    #[test]
+    fn test_overwritten_operand() {
+        // the second SAdd must not be reused since register 0 changes in between
+        let prog = vec![
+            Instruction::SAdd(2, 0, 1),
+            Instruction::SSub(0, 0, 1),
+            Instruction::SAdd(3, 0, 1),
+            Instruction::SOutput(2),
+            Instruction::SOutput(3),
+            Instruction::Stop,
+        ];
+        assert_eq!(optimize(&prog), prog);
+
+        // the first result is overwritten, so later reads cannot be redirected to it
+        let prog = vec![
+            Instruction::SAdd(2, 0, 1),
+            Instruction::SAdd(3, 0, 1),
+            Instruction::SSub(2, 2, 1),
+            Instruction::SOutput(2),
+            Instruction::SOutput(3),
+            Instruction::Stop,
+        ];
+        assert_eq!(optimize(&prog), prog);
+    }
+
+    #[test]
+    fn test_opens_are_kept() {
+        let prog = vec![
+            Instruction::Triple(0, 1, 2),
+            Instruction::SAdd(3, 0, 1),
+            Instruction::Open(0, 3),
+            Instruction::Open(1, 3),
+            Instruction::Stop,
+        ];
+        assert_eq!(optimize(&prog), prog);
+    }
+
+    #[test]
+    fn test_optimize_programs() {
+        assert_eq!(optimize(&MUL_PROG), MUL_PROG.to_vec());
+        assert_eq!(optimize(&IO_PROG), IO_PROG.to_vec());
+    }
+}
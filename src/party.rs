@@ -1,49 +1,133 @@
 //! This module implement a party that participates in the MPC protocol.
-//! It assumes perfect channels for sending and receiving messages.
+//! It assumes a perfect `Transport` for sending and receiving messages to and from the other parties.
 //! The actual networking layer is handled by an outer layer.
 
 use crate::algebra::Fp;
 use crate::crypto::commit;
 use crate::crypto::AuthShare;
-use crate::error::{MACCheckError, MPCError, TIMEOUT};
-use crate::message;
-use crate::message::{PartyID, PartyMsg, PrepMsg, SyncMsg, SyncReplyMsg};
+use crate::error::{AbortReason, MACCheckError, MPCError, Timeouts};
+use crate::message::{PartyID, PartyMsg, PrepMsg, PrepSeqs, RandShareMsg, SyncMsg, SyncReplyMsg, TripleMsg};
+use crate::transport::Transport;
 use crate::vm;
 
-use crossbeam::channel::{bounded, select, Receiver, Sender};
+use crossbeam::channel::{bounded, Receiver, Select, Sender};
 use log::{debug, error};
 use num_traits::Zero;
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::collections::VecDeque;
 use std::thread;
 
 const FORWARDING_CAP: usize = 1024;
 
-pub struct Party {
+/// `BroadcastMode` decides how the inputs and the commitments in the MAC check are broadcast,
+/// all the parties must use the same mode.
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum BroadcastMode {
+    /// Send the message to every party,
+    /// a malicious party can send different messages to different parties.
+    #[default]
+    Plain,
+    /// Send the message to every party and then run an extra round where
+    /// every party broadcasts a hash of what it received and checks that the hashes agree,
+    /// so that a party cannot equivocate.
+    Echo,
+}
+
+// move the preprocessing data to the VM until `stop` is closed,
+// the triples and the random sharings are buffered separately so that
+// a VM that does not consume one kind yet still gets the other kind,
+// but at most `FORWARDING_CAP` of them together, then we wait for the VM
+// and the preprocessing data must arrive in the order that the VM consumes it
+fn forward_prep(
+    id: PartyID,
+    preproc_chan: Receiver<PrepMsg>,
+    s_triple_chan: Sender<TripleMsg>,
+    s_rand_chan: Sender<RandShareMsg>,
+    stop: Receiver<()>,
+) {
+    let mut triples = VecDeque::new();
+    let mut rand_shares = VecDeque::new();
+    let mut preproc_open = true;
+    loop {
+        let mut sel = Select::new();
+        let stop_op = sel.recv(&stop);
+        let recv_op = if preproc_open && triples.len() + rand_shares.len() < FORWARDING_CAP {
+            Some(sel.recv(&preproc_chan))
+        } else {
+            None
+        };
+        let triple_op = if triples.is_empty() { None } else { Some(sel.send(&s_triple_chan)) };
+        let rand_op = if rand_shares.is_empty() { None } else { Some(sel.send(&s_rand_chan)) };
+        if recv_op.is_none() && triple_op.is_none() && rand_op.is_none() {
+            break;
+        }
+
+        let op = sel.select();
+        let i = Some(op.index());
+        let sent = if i == Some(stop_op) {
+            let _ = op.recv(&stop);
+            false
+        } else if i == recv_op {
+            match op.recv(&preproc_chan) {
+                Ok(msg) => {
+                    debug!("[{}] got preproc msg {:?}", id, msg);
+                    match msg {
+                        PrepMsg::Triple(m) => triples.push_back(m),
+                        PrepMsg::RandShare(m) => rand_shares.push_back(m),
+                    }
+                }
+                // deliver what is buffered before closing the channels to the VM
+                Err(_) => preproc_open = false,
+            }
+            true
+        } else if i == triple_op {
+            op.send(&s_triple_chan, triples.pop_front().expect("triple buffer is empty")).is_ok()
+        } else {
+            op.send(&s_rand_chan, rand_shares.pop_front().expect("rand share buffer is empty"))
+                .is_ok()
+        };
+        if !sent {
+            break;
+        }
+    }
+}
+
+pub struct Party<T> {
     id: PartyID,
     alpha_share: Fp,
     com_scheme: commit::Scheme,
-    s_sync_chan: Sender<SyncReplyMsg>,
-    r_sync_chan: Receiver<SyncMsg>,
+    bcast_mode: BroadcastMode,
+    timeouts: Timeouts,
+    sync_chans: Option<SyncChans>,
     preproc_chan: Receiver<PrepMsg>,
-    s_party_chans: Vec<Sender<PartyMsg>>,
-    r_party_chans: Vec<Receiver<PartyMsg>>,
+    transport: T,
 }
 
-impl Party {
+/// The channels that connect a party to the synchronizer, used for sending replies and receiving commands.
+pub type SyncChans = (Sender<SyncReplyMsg>, Receiver<SyncMsg>);
+
+impl<T: 'static + Transport<PartyMsg, PartyMsg>> Party<T> {
     /// Spawn a party thread and returns a handler.
     /// If successful, the handler will return the result of the computation,
     /// i.e., the result of calling `COutput` or `SOutput`.
+    /// The `transport` must connect to all the parties, including this party, using their IDs.
+    /// Before the computation starts, the parties check that they run the same `prog` and abort if they do not.
+    /// If `sync_chans` is `None`, the party does not wait for a synchronizer,
+    /// instead it executes the program at its own pace and only waits for the other parties when it needs to communicate.
+    #[allow(clippy::too_many_arguments)]
     pub fn spawn(
         id: PartyID,
         alpha_share: Fp,
         reg: vm::Reg,
         prog: Vec<vm::Instruction>,
-        s_sync_chan: Sender<SyncReplyMsg>,
-        r_sync_chan: Receiver<SyncMsg>,
+        bcast_mode: BroadcastMode,
+        timeouts: Timeouts,
+        sync_chans: Option<SyncChans>,
         prep_chan: Receiver<PrepMsg>,
-        s_party_chan: Vec<Sender<PartyMsg>>,
-        r_party_chan: Vec<Receiver<PartyMsg>>,
+        transport: T,
         rng_seed: Option<[u8; 32]>,
     ) -> thread::JoinHandle<Result<Vec<Fp>, MPCError>> {
         thread::spawn(move || {
@@ -51,11 +135,11 @@ impl Party {
                 id,
                 alpha_share,
                 com_scheme: commit::Scheme {},
-                s_sync_chan,
-                r_sync_chan,
+                bcast_mode,
+                timeouts,
+                sync_chans,
                 preproc_chan: prep_chan,
-                s_party_chans: s_party_chan,
-                r_party_chans: r_party_chan,
+                transport,
             };
             p.listen(reg, prog, rng_seed)
         })
@@ -82,92 +166,211 @@ impl Party {
             r_inner_rand_chan,
             r_inst_chan,
             s_action_chan,
+            self.timeouts,
         );
-        let mut pc = 0;
+        // forward the preprocessing data in the background so that it keeps flowing while an instruction runs
+        let (s_stop_chan, r_stop_chan) = bounded(0);
+        let forward_handler = {
+            let id = self.id;
+            let preproc_chan = self.preproc_chan.clone();
+            thread::spawn(move || forward_prep(id, preproc_chan, s_inner_triple_chan, s_inner_rand_chan, r_stop_chan))
+        };
 
-        // wait for start, collect the preprocessing message while we wait
-        loop {
-            select! {
-                recv(self.r_sync_chan) -> msg_res => {
-                    let msg = msg_res?;
-                    if msg == SyncMsg::Start {
-                        debug!("[{}] Starting", self.id);
-                        break;
-                    } else {
-                        debug!("[{}] Received {:?} while waiting to start", self.id, msg);
-                    }
-                }
-                recv(self.preproc_chan) -> x => {
-                    debug!("[{}] got preproc msg {:?}", self.id, x);
-                    match x? {
-                        PrepMsg::Triple(msg) => {
-                            s_inner_triple_chan.try_send(msg)?
-                        }
-                        PrepMsg::RandShare(msg) => {
-                            s_inner_rand_chan.try_send(msg)?
+        let run = || -> Result<(), MPCError> {
+            self.check_prog(&prog)?;
+            match &self.sync_chans {
+                Some((s_sync_chan, r_sync_chan)) => {
+                    let mut pc = 0;
+
+                    // wait for start
+                    loop {
+                        let msg = r_sync_chan.recv()?;
+                        if msg == SyncMsg::Start {
+                            debug!("[{}] Starting", self.id);
+                            break;
+                        } else {
+                            debug!("[{}] Received {:?} while waiting to start", self.id, msg);
                         }
                     }
-                }
-            }
-        }
 
-        // process instructions
-        loop {
-            select! {
-                recv(self.preproc_chan) -> x => {
-                    debug!("[{}] got preproc msg {:?}", self.id, x);
-                    match x? {
-                        PrepMsg::Triple(msg) => {
-                            s_inner_triple_chan.try_send(msg)?
-                        }
-                        PrepMsg::RandShare(msg) => {
-                            s_inner_rand_chan.try_send(msg)?
+                    // process instructions
+                    loop {
+                        match r_sync_chan.recv()? {
+                            SyncMsg::Start => return Err(self.violation(AbortReason::UnexpectedSyncMessage)),
+                            SyncMsg::Next => {
+                                if pc >= prog.len() {
+                                    return Err(self.violation(AbortReason::UnexpectedSyncMessage));
+                                }
+                                let instruction = prog[pc].clone();
+                                pc += 1;
+
+                                if self.execute(instruction, &s_inst_chan, &r_action_chan, &mut rng)? {
+                                    s_sync_chan.send(SyncReplyMsg::Done)?;
+                                    break;
+                                } else {
+                                    s_sync_chan.send(SyncReplyMsg::Ok)?;
+                                }
+                            }
+                            SyncMsg::Abort(by, reason) => return Err(MPCError::Aborted { by, reason }),
                         }
                     }
                 }
-                recv(self.r_sync_chan) -> v => {
-                    let msg: SyncMsg = v?;
-                    match msg {
-                        SyncMsg::Start => panic!("party already started"),
-                        SyncMsg::Next => {
-                            if pc >= prog.len() {
-                                panic!("instruction counter overflow");
-                            }
-                            let instruction = prog[pc].clone();
-                            pc += 1;
-
-                            debug!("[{}] Sending instruction {:?} to VM", self.id, instruction);
-                            s_inst_chan.send(instruction.clone())?;
-                            // NOTE there's a bug here because this function blocks,
-                            // which means we cannot forward preprocessing data to the VM.
-                            // then if the VM asks for more triples/rand shares when there's
-                            // nothing in the channel buffer then the program crashes
-                            self.handle_vm_actions(&r_action_chan, &mut rng)?;
-
-                            if instruction == vm::Instruction::Stop {
-                                self.s_sync_chan.send(SyncReplyMsg::Done)?;
-                                break;
-                            } else {
-                                self.s_sync_chan.send(SyncReplyMsg::Ok)?;
-                            }
-                        },
-                        SyncMsg::Abort => panic!("abort"),
+                None => {
+                    // without a synchronizer every party runs the program counter locally,
+                    // the parties only wait for each other when an instruction needs to communicate
+                    debug!("[{}] Starting without a synchronizer", self.id);
+                    for instruction in prog {
+                        if self.execute(instruction, &s_inst_chan, &r_action_chan, &mut rng)? {
+                            break;
+                        }
                     }
                 }
             }
+            Ok(())
+        };
+
+        let res = run();
+        if let Err(e) = &res {
+            self.notify_abort(e);
         }
 
-        vm_handler.join().expect("thread panicked")
+        // the VM stops when its channels are closed
+        drop(s_inst_chan);
+        drop(r_action_chan);
+        drop(s_stop_chan);
+        forward_handler.join().expect("forwarding thread panicked");
+        let vm_res = vm_handler.join().expect("thread panicked");
+        match res {
+            Ok(()) => vm_res,
+            Err(e @ MPCError::Aborted { .. }) => Err(e),
+            // if the VM failed then the party fails as a consequence, so the VM error is more useful
+            Err(e) => vm_res.and(Err(e)),
+        }
+    }
+
+    fn violation(&self, reason: AbortReason) -> MPCError {
+        MPCError::Aborted { by: self.id, reason }
+    }
+
+    // tell the other parties and the synchronizer to stop,
+    // an abort from another party is relayed so that no party waits for a party that has stopped
+    fn notify_abort(&self, e: &MPCError) {
+        if let MPCError::Aborted { by, reason } = e {
+            error!("[{}] Aborting because of party {}: {}", self.id, by, reason);
+            // some parties may have stopped already, so we try to reach every party
+            for to in 0..self.transport.peers() {
+                let _ = self.transport.send(to as PartyID, PartyMsg::Abort(*by, reason.clone()));
+            }
+            if let Some((s_sync_chan, _)) = &self.sync_chans {
+                let _ = s_sync_chan.send(SyncReplyMsg::Abort(*by, reason.clone()));
+            }
+        }
+    }
+
+    // check that a message has the expected type,
+    // the sender of an abort could claim anything so we only trust that it was `from` who aborted
+    fn expect<U>(&self, from: PartyID, res: Result<U, PartyMsg>) -> Result<U, MPCError> {
+        match res {
+            Ok(x) => Ok(x),
+            Err(PartyMsg::Abort(_, reason)) => Err(MPCError::Aborted {
+                by: from,
+                reason: reason.relayed(),
+            }),
+            Err(m) => {
+                error!("[{}] Unexpected message {:?} from {}", self.id, m, from);
+                Err(self.violation(AbortReason::UnexpectedMessage(from)))
+            }
+        }
+    }
+
+    // send the instruction to the VM and handle the actions that it requests,
+    // returns true if the program has stopped
+    fn execute(
+        &self,
+        instruction: vm::Instruction,
+        s_inst_chan: &Sender<vm::Instruction>,
+        r_action_chan: &Receiver<vm::Action>,
+        rng: &mut impl Rng,
+    ) -> Result<bool, MPCError> {
+        debug!("[{}] Sending instruction {:?} to VM", self.id, instruction);
+        let stop = instruction == vm::Instruction::Stop;
+        s_inst_chan.send(instruction)?;
+        self.handle_vm_actions(r_action_chan, rng)?;
+        Ok(stop)
     }
 
     fn bcast(&self, m: PartyMsg) -> Result<(), MPCError> {
-        message::broadcast(&self.s_party_chans, m)?;
+        debug!("[{}] Broadcasting {:?}", self.id, m);
+        self.transport.broadcast(m)?;
         Ok(())
     }
 
-    fn recv(&self) -> Result<Vec<PartyMsg>, MPCError> {
-        let out = message::receive(&self.r_party_chans, TIMEOUT)?;
-        Ok(out)
+    fn recv<U>(&self, extract: fn(PartyMsg) -> Result<U, PartyMsg>) -> Result<Vec<U>, MPCError> {
+        let out = self.transport.recv_all(self.timeouts.open)?;
+        debug!("[{}] All received {:?}", self.id, out);
+        out.into_iter()
+            .enumerate()
+            .map(|(from, m)| self.expect(from as PartyID, extract(m)))
+            .collect()
+    }
+
+    fn recv_from<U>(&self, from: PartyID, extract: fn(PartyMsg) -> Result<U, PartyMsg>) -> Result<U, MPCError> {
+        let m = self.transport.recv_from(from, self.timeouts.open)?;
+        debug!("[{}] Received {:?} from {}", self.id, m, from);
+        self.expect(from, extract(m))
+    }
+
+    // in the echo mode, check that every party received the same `msgs`
+    fn echo<M: Serialize>(&self, msgs: &M) -> Result<(), MPCError> {
+        if self.bcast_mode == BroadcastMode::Plain {
+            return Ok(());
+        }
+        let h: [u8; 32] = Sha3_256::digest(&bincode::serialize(msgs).expect("serialization failed")).into();
+        self.bcast(PartyMsg::Echo(h))?;
+        if self.recv(PartyMsg::into_echo)?.into_iter().all(|x| x == h) {
+            Ok(())
+        } else {
+            Err(self.violation(AbortReason::Equivocation))
+        }
+    }
+
+    // check that every party runs the same program before the computation starts,
+    // otherwise the results are wrong or the parties wait for messages that never come
+    fn check_prog(&self, prog: &[vm::Instruction]) -> Result<(), MPCError> {
+        let h = vm::prog_hash(prog);
+        self.bcast(PartyMsg::ProgHash(h))?;
+        let mismatches: Vec<PartyID> = self
+            .recv(PartyMsg::into_prog_hash)?
+            .into_iter()
+            .enumerate()
+            .filter(|(_, x)| *x != h)
+            .map(|(id, _)| id as PartyID)
+            .collect();
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            error!("[{}] Parties {:?} run a different program", self.id, mismatches);
+            Err(self.violation(AbortReason::ProgramMismatch(mismatches)))
+        }
+    }
+
+    // check that every party used the same preprocessing data,
+    // otherwise a party that skipped or repeated some of it passes its own checks but computes with the wrong shares
+    fn check_prep_seqs(&self, seqs: &PrepSeqs) -> Result<(), MPCError> {
+        self.bcast(PartyMsg::PrepSeqs(seqs.clone()))?;
+        let mismatches: Vec<PartyID> = self
+            .recv(PartyMsg::into_prep_seqs)?
+            .into_iter()
+            .enumerate()
+            .filter(|(_, x)| x != seqs)
+            .map(|(id, _)| id as PartyID)
+            .collect();
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            error!("[{}] Parties {:?} used different preprocessing data", self.id, mismatches);
+            Err(self.violation(AbortReason::PrepMismatch(mismatches)))
+        }
     }
 
     fn mac_check(&self, x: &Fp, share: &AuthShare, rng: &mut impl Rng) -> Result<Result<(), MACCheckError>, MPCError> {
@@ -177,18 +380,26 @@ impl Party {
         let (d_com, d_open) = self.com_scheme.commit(d, rng);
         self.bcast(PartyMsg::Com(d_com))?;
         // get commitment from others
-        let d_coms: Vec<_> = self.recv()?.into_iter().map(|x| x.unwrap_com()).collect();
+        let d_coms = self.recv(PartyMsg::into_com)?;
+        self.echo(&d_coms)?;
         // commit-open d and collect them
         self.bcast(PartyMsg::Opening(d_open))?;
-        let d_opens: Vec<_> = self.recv()?.into_iter().map(|x| x.unwrap_opening()).collect();
+        let d_opens = self.recv(PartyMsg::into_opening)?;
         // verify all the commitments of d
         // and check they sum to 0
-        let coms_ok = d_opens.iter().zip(d_coms).map(|(o, c)| self.com_scheme.verify(&o, &c)).all(|x| x);
+        // the messages are ordered by the party ID so we know who sent a bad opening
+        let bad_ids: Vec<PartyID> = d_opens
+            .iter()
+            .zip(d_coms)
+            .enumerate()
+            .filter(|(_, (o, c))| !self.com_scheme.verify(o, c))
+            .map(|(id, _)| id as PartyID)
+            .collect();
         let zero_ok = d_opens.into_iter().map(|o| o.get_v()).sum::<Fp>() == Fp::zero();
 
         // this is a weird kind of return type but it makes categorizing the errors easier
-        if !coms_ok {
-            Ok(Err(MACCheckError::BadCommitment))
+        if !bad_ids.is_empty() {
+            Ok(Err(MACCheckError::BadCommitment(bad_ids)))
         } else if !zero_ok {
             Ok(Err(MACCheckError::SumIsNotZero))
         } else {
@@ -198,7 +409,8 @@ impl Party {
 
     fn handle_vm_actions(&self, r_action_chan: &Receiver<vm::Action>, rng: &mut impl Rng) -> Result<(), MPCError> {
         loop {
-            let action = r_action_chan.recv_timeout(TIMEOUT)?;
+            // the VM might be waiting for preprocessing data before it sends the next action
+            let action = r_action_chan.recv_timeout(self.timeouts.prep)?;
             debug!("[{}], Received action {:?} from VM", self.id, action);
             match action {
                 vm::Action::Next => {
@@ -206,7 +418,7 @@ impl Party {
                 }
                 vm::Action::Open(x, sender) => {
                     self.bcast(PartyMsg::Elem(x))?;
-                    let result = self.recv()?.into_iter().map(|x| x.unwrap_elem()).sum();
+                    let result = self.recv(PartyMsg::into_elem)?.into_iter().sum();
                     debug!("[{}] Partially opened {:?}", self.id, result);
                     sender.send(result)?
                 }
@@ -215,28 +427,23 @@ impl Party {
                         Some(e) => self.bcast(PartyMsg::Elem(e))?,
                         None => (),
                     };
-                    let e = self.r_party_chans[id as usize].recv_timeout(TIMEOUT)?.unwrap_elem();
+                    let e = self.recv_from(id, PartyMsg::into_elem)?;
+                    self.echo(&e)?;
                     sender.send(e)?
                 }
-                vm::Action::Check(openings, sender) => {
-                    // mac_check everything and send error on first failure
-                    let mut ok = true;
+                vm::Action::Check(openings, seqs, sender) => {
+                    self.check_prep_seqs(&seqs)?;
+                    // mac_check everything and abort on first failure
                     for (x, opening) in openings {
-                        match self.mac_check(&x, &opening, rng)? {
-                            Ok(()) => {}
-                            e => {
-                                error!("[{}] MAC check failed: {:?}", self.id, e);
-                                sender.send(e)?;
-                                ok = false;
-                                break;
-                            }
+                        if let Err(e) = self.mac_check(&x, &opening, rng)? {
+                            error!("[{}] MAC check failed: {:?}", self.id, e);
+                            sender.send(Err(e.clone()))?;
+                            return Err(self.violation(e.into()));
                         }
                     }
 
-                    if ok {
-                        debug!("[{}] All MAC check ok", self.id);
-                        sender.send(Ok(()))?;
-                    }
+                    debug!("[{}] All MAC check ok", self.id);
+                    sender.send(Ok(()))?;
                 }
             }
         }
@@ -247,27 +454,70 @@ impl Party {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::crypto::{auth_share, unauth_share};
+    use crate::crypto::{auth_share, gen_fake_prep, unauth_share};
+    use crate::transport::ChanTransport;
+    use std::time::{Duration, Instant};
 
     const TEST_SEED: [u8; 32] = [8u8; 32];
     const TEST_CAP: usize = 5;
 
-    fn make_dummy_party(alpha_share: Fp, s_party_chans: Vec<Sender<PartyMsg>>, r_party_chans: Vec<Receiver<PartyMsg>>) -> Party {
-        let (dummy_s_sync_chan, _) = bounded(TEST_CAP);
-        let (_, dummy_r_sync_chan) = bounded(TEST_CAP);
+    fn make_dummy_party(
+        alpha_share: Fp,
+        bcast_mode: BroadcastMode,
+        s_party_chans: Vec<Sender<PartyMsg>>,
+        r_party_chans: Vec<Receiver<PartyMsg>>,
+    ) -> Party<ChanTransport<PartyMsg, PartyMsg>> {
         let (_, dummy_preproc_chan) = bounded(TEST_CAP);
         Party {
             id: 0,
             alpha_share,
             com_scheme: commit::Scheme {},
-            s_sync_chan: dummy_s_sync_chan,
-            r_sync_chan: dummy_r_sync_chan,
+            bcast_mode,
+            timeouts: Timeouts::default(),
+            sync_chans: None,
             preproc_chan: dummy_preproc_chan,
-            s_party_chans,
-            r_party_chans,
+            transport: ChanTransport::new(s_party_chans, r_party_chans),
         }
     }
 
+    #[test]
+    fn test_forward_prep() {
+        let rng = &mut ChaCha20Rng::from_seed(TEST_SEED);
+        let alpha = Fp::random(rng);
+        let count = 3 * FORWARDING_CAP;
+        let (rand_shares, triples) = gen_fake_prep(1, &alpha, 1, count, rng);
+        let (s_preproc_chan, r_preproc_chan) = bounded(count + 1);
+        for mut ts in triples {
+            s_preproc_chan.send(PrepMsg::Triple(ts.pop().unwrap())).unwrap();
+        }
+        let rand_share = rand_shares.into_iter().next().unwrap().pop().unwrap();
+        let seq = rand_share.seq;
+        s_preproc_chan.send(PrepMsg::RandShare(rand_share)).unwrap();
+
+        // the VM is not reading triples, so the party stops taking preprocessing data
+        // once the channel to the VM and its own buffer are full
+        let (s_triple_chan, r_triple_chan) = bounded(FORWARDING_CAP);
+        let (s_rand_chan, r_rand_chan) = bounded(FORWARDING_CAP);
+        let (s_stop_chan, r_stop_chan) = bounded(0);
+        let handle = thread::spawn(move || forward_prep(0, r_preproc_chan, s_triple_chan, s_rand_chan, r_stop_chan));
+        let left = count + 1 - 2 * FORWARDING_CAP;
+        let deadline = Instant::now() + Timeouts::default().prep;
+        while s_preproc_chan.len() > left && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(s_preproc_chan.len(), left);
+        assert!(r_rand_chan.is_empty());
+
+        // everything is delivered once the VM reads the triples
+        for _ in 0..count {
+            r_triple_chan.recv_timeout(Timeouts::default().prep).unwrap();
+        }
+        assert_eq!(r_rand_chan.recv_timeout(Timeouts::default().prep).unwrap().seq, seq);
+        drop(s_stop_chan);
+        handle.join().unwrap();
+    }
+
     #[test]
     fn test_mac_check() {
         let n = 2;
@@ -284,6 +534,7 @@ mod tests {
         let (s_party_chan2, r_party_chan2) = bounded(TEST_CAP);
         let party = make_dummy_party(
             alpha_shares[0].clone(),
+            BroadcastMode::Plain,
             vec![s_party_chan0, s_party_chan1],
             vec![r_party_chan0, r_party_chan2],
         );
@@ -304,7 +555,9 @@ mod tests {
 
             // party should fail with bad commitment
             let result = party.mac_check(&x, &x_shares[0], rng).unwrap();
-            assert_eq!(result.unwrap_err(), MACCheckError::BadCommitment);
+            let e = result.unwrap_err();
+            assert_eq!(e, MACCheckError::BadCommitment(vec![1]));
+            assert_eq!(e.culprits(), &[1]);
 
             // empty the black hole
             _r_party_chan1.recv().unwrap();
@@ -352,4 +605,70 @@ mod tests {
             _r_party_chan1.recv().unwrap();
         }
     }
+
+    #[test]
+    fn test_unexpected_message() {
+        let rng = &mut ChaCha20Rng::from_seed(TEST_SEED);
+        let (s_party_chan0, r_party_chan0) = bounded(TEST_CAP);
+        let (s_party_chan1, _r_party_chan1) = bounded(TEST_CAP);
+        let (s_party_chan2, r_party_chan2) = bounded(TEST_CAP);
+        let party = make_dummy_party(
+            Fp::zero(),
+            BroadcastMode::Plain,
+            vec![s_party_chan0, s_party_chan1],
+            vec![r_party_chan0, r_party_chan2],
+        );
+        let share = AuthShare {
+            share: Fp::zero(),
+            mac: Fp::zero(),
+        };
+
+        // the party expects a commitment but gets an element
+        s_party_chan2.send(PartyMsg::Elem(Fp::random(rng))).unwrap();
+        match party.mac_check(&Fp::zero(), &share, rng) {
+            Err(MPCError::Aborted { by: 0, reason }) => assert_eq!(reason, AbortReason::UnexpectedMessage(1)),
+            res => panic!("expected abort, got {:?}", res),
+        }
+
+        // an abort from another party is relayed, party 1 cannot frame party 2 by forging its abort
+        let reason = AbortReason::MACCheck(MACCheckError::BadCommitment(vec![2]));
+        s_party_chan2.send(PartyMsg::Abort(2, reason.clone())).unwrap();
+        match party.mac_check(&Fp::zero(), &share, rng) {
+            Err(MPCError::Aborted { by: 1, reason: r }) => {
+                assert_eq!(r, reason.relayed());
+                assert!(r.culprits().is_empty());
+            }
+            res => panic!("expected abort, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_echo() {
+        let rng = &mut ChaCha20Rng::from_seed(TEST_SEED);
+        let (s_party_chan0, r_party_chan0) = bounded(TEST_CAP);
+        let (s_party_chan1, _r_party_chan1) = bounded(TEST_CAP);
+        let (s_party_chan2, r_party_chan2) = bounded(TEST_CAP);
+        let party = make_dummy_party(
+            Fp::zero(),
+            BroadcastMode::Echo,
+            vec![s_party_chan0, s_party_chan1],
+            vec![r_party_chan0, r_party_chan2],
+        );
+
+        // the other party received the same message
+        let x = Fp::random(rng);
+        let h: [u8; 32] = Sha3_256::digest(&bincode::serialize(&x).unwrap()).into();
+        s_party_chan2.send(PartyMsg::Echo(h)).unwrap();
+        assert!(party.echo(&x).is_ok());
+        assert_eq!(_r_party_chan1.recv().unwrap().into_echo().unwrap(), h);
+
+        // the other party received a different message
+        let y = Fp::random(rng);
+        let h: [u8; 32] = Sha3_256::digest(&bincode::serialize(&y).unwrap()).into();
+        s_party_chan2.send(PartyMsg::Echo(h)).unwrap();
+        match party.echo(&x) {
+            Err(MPCError::Aborted { by: 0, reason }) => assert_eq!(reason, AbortReason::Equivocation),
+            res => panic!("expected abort, got {:?}", res),
+        }
+    }
 }
@@ -0,0 +1,743 @@
+//! This module contains the preprocessing service.
+//! The `Dealer` generates triples and random sharings when the parties ask for them.
+//! The triples form a sequence, and so do the random sharings of every party,
+//! so that all the parties consume their shares of the same element in the same order.
+//! The client side turns a program into requests and forwards the replies to a party.
+//! The preprocessing data of a party can also be stored in a file with `PrepStore` and used later,
+//! the `ConsumptionLog` makes sure that it is used only once.
+//! The networking is handled in the `io` module.
+
+use crate::algebra::{Fp, FIELD_MODULUS};
+use crate::crypto::{gen_fake_rand_share, gen_fake_triple};
+use crate::error::{ApplicationError, MPCError};
+use crate::message::{PartyID, PrepBatch, PrepMsg, PrepRequest, RandShareMsg, TripleMsg};
+use crate::vm::Instruction;
+
+use crossbeam::channel::{Receiver, Sender};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The maximum number of elements in one request.
+pub const MAX_BATCH_SIZE: usize = 1024;
+
+/// The maximum number of elements that the dealer keeps in one sequence for the parties that did not take them yet,
+/// a party that is this far ahead of the slowest party has to wait for it.
+pub const MAX_POOL_SIZE: usize = 16 * MAX_BATCH_SIZE;
+
+const STORE_MAGIC: &[u8; 8] = b"ezmpcprp";
+const STORE_VERSION: u32 = 1;
+
+// The elements of one sequence, the shares that are sent out are removed
+// and the elements are dropped once every party received its share.
+struct Pool<T> {
+    base: u64,
+    items: VecDeque<Vec<Option<T>>>,
+    cursors: Vec<u64>,
+}
+
+impl<T> Pool<T> {
+    fn new(n: usize) -> Pool<T> {
+        Pool {
+            base: 0,
+            items: VecDeque::new(),
+            cursors: vec![0; n],
+        }
+    }
+
+    // whether party `id` can take the next `k` elements without growing the pool beyond `MAX_POOL_SIZE`
+    fn can_take(&self, id: PartyID, k: usize) -> bool {
+        self.cursors[id as usize].saturating_add(k as u64) - self.base <= MAX_POOL_SIZE as u64
+    }
+
+    // give party `id` its shares of the next `k` elements,
+    // `gen` creates the elements at the given position that no party asked for yet
+    fn take(&mut self, id: PartyID, k: usize, mut gen: impl FnMut(u64) -> Vec<T>) -> io::Result<(u64, Vec<T>)> {
+        if !self.can_take(id, k) {
+            let msg = format!("party {} is too far ahead of the other parties", id);
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, msg));
+        }
+        let seq = self.cursors[id as usize];
+        let end = seq + k as u64;
+        while self.base + (self.items.len() as u64) < end {
+            let next = self.base + self.items.len() as u64;
+            self.items.push_back(gen(next).into_iter().map(Some).collect());
+        }
+        let out = (seq..end)
+            .map(|i| self.items[(i - self.base) as usize][id as usize].take().expect("share is sent twice"))
+            .collect();
+        self.cursors[id as usize] = end;
+
+        let min = *self.cursors.iter().min().expect("no parties");
+        while self.base < min {
+            self.items.pop_front();
+            self.base += 1;
+        }
+        Ok((seq, out))
+    }
+}
+
+/// `Dealer` generates the preprocessing data using the global MAC key `alpha`,
+/// so it must be trusted by all the parties.
+pub struct Dealer<R> {
+    n: usize,
+    alpha: Fp,
+    rng: R,
+    triples: Pool<TripleMsg>,
+    rand_shares: Vec<Pool<RandShareMsg>>,
+}
+
+impl<R: Rng> Dealer<R> {
+    /// Create a dealer for the parties with IDs `0..n`.
+    pub fn new(n: usize, alpha: Fp, rng: R) -> Dealer<R> {
+        Dealer {
+            n,
+            alpha,
+            rng,
+            triples: Pool::new(n),
+            rand_shares: (0..n).map(|_| Pool::new(n)).collect(),
+        }
+    }
+
+    /// Check whether the request of the party `id` can be answered now,
+    /// it cannot if the party is too far ahead of the slowest party in the requested sequence, see `MAX_POOL_SIZE`.
+    /// An invalid request is always ready so that `handle` rejects it.
+    pub fn ready(&self, id: PartyID, req: &PrepRequest) -> bool {
+        if id as usize >= self.n {
+            return true;
+        }
+        match *req {
+            PrepRequest::Triples(k) => self.triples.can_take(id, k),
+            PrepRequest::RandShares(owner, k) => self.rand_shares.get(owner as usize).is_none_or(|pool| pool.can_take(id, k)),
+        }
+    }
+
+    /// Answer the request of the party `id`.
+    /// The request fails with `WouldBlock` if it is not `ready`.
+    pub fn handle(&mut self, id: PartyID, req: &PrepRequest) -> io::Result<PrepBatch> {
+        let (n, alpha, rng) = (self.n, &self.alpha, &mut self.rng);
+        if id as usize >= n {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown party {}", id)));
+        }
+        match *req {
+            PrepRequest::Triples(k) if k <= MAX_BATCH_SIZE => {
+                let (seq, msgs) = self.triples.take(id, k, |seq| gen_fake_triple(n, alpha, seq, rng))?;
+                Ok(PrepBatch {
+                    seq,
+                    msgs: msgs.into_iter().map(PrepMsg::Triple).collect(),
+                })
+            }
+            PrepRequest::RandShares(owner, k) if k <= MAX_BATCH_SIZE && (owner as usize) < n => {
+                let (seq, msgs) = self.rand_shares[owner as usize].take(id, k, |seq| gen_fake_rand_share(n, alpha, owner, seq, rng))?;
+                Ok(PrepBatch {
+                    seq,
+                    msgs: msgs.into_iter().map(PrepMsg::RandShare).collect(),
+                })
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid request {:?}", req))),
+        }
+    }
+}
+
+/// Compute the requests for the preprocessing data that `prog` needs,
+/// in the order that `prog` uses the data.
+pub fn plan_requests(prog: &[Instruction]) -> Vec<PrepRequest> {
+    let mut out: Vec<PrepRequest> = Vec::new();
+    for instruction in prog {
+        let next = match instruction {
+            Instruction::Triple(_, _, _) => PrepRequest::Triples(1),
+            Instruction::Input(_, _, id) => PrepRequest::RandShares(*id, 1),
+            _ => continue,
+        };
+        match (out.last_mut(), next) {
+            (Some(PrepRequest::Triples(k)), PrepRequest::Triples(_)) if *k < MAX_BATCH_SIZE => *k += 1,
+            (Some(PrepRequest::RandShares(owner, k)), PrepRequest::RandShares(id, _)) if *owner == id && *k < MAX_BATCH_SIZE => *k += 1,
+            (_, next) => out.push(next),
+        }
+    }
+    out
+}
+
+fn check_batch(req: &PrepRequest, batch: &PrepBatch, expected_seq: u64) -> bool {
+    let k = match *req {
+        PrepRequest::Triples(k) | PrepRequest::RandShares(_, k) => k,
+    };
+    let msg_ok = |(i, m): (usize, &PrepMsg)| match (req, m) {
+        (PrepRequest::Triples(_), PrepMsg::Triple(t)) => t.seq == expected_seq + i as u64,
+        (PrepRequest::RandShares(owner, _), PrepMsg::RandShare(r)) => r.party_id == *owner && r.seq == expected_seq + i as u64,
+        _ => false,
+    };
+    batch.seq == expected_seq && batch.msgs.len() == k && batch.msgs.iter().enumerate().all(msg_ok)
+}
+
+/// Send the `requests` to the preprocessing server one at a time and forward the replies to `out`.
+/// The replies must have the requested content and follow each other in their sequences.
+/// It stops early without an error if `out` is closed.
+pub fn run_client(requests: Vec<PrepRequest>, s_req: &Sender<PrepRequest>, r_batch: &Receiver<PrepBatch>, out: &Sender<PrepMsg>) -> io::Result<()> {
+    let gone = || io::Error::new(io::ErrorKind::BrokenPipe, "preprocessing server is gone");
+    let mut next_triple = 0;
+    let mut next_rand_share: HashMap<PartyID, u64> = HashMap::new();
+    for req in requests {
+        s_req.send(req.clone()).map_err(|_| gone())?;
+        let batch = r_batch.recv().map_err(|_| gone())?;
+        let next_seq = match req {
+            PrepRequest::Triples(_) => &mut next_triple,
+            PrepRequest::RandShares(owner, _) => next_rand_share.entry(owner).or_default(),
+        };
+        if !check_batch(&req, &batch, *next_seq) {
+            let msg = format!("unexpected batch at {} for request {:?}", batch.seq, req);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        *next_seq += batch.msgs.len() as u64;
+        for m in batch.msgs {
+            if out.send(m).is_err() {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The header of a preprocessing file.
+/// It identifies the party, the field and the MAC key share that the data belongs to.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct StoreHeader {
+    pub party_id: PartyID,
+    /// The field modulus in decimal.
+    pub modulus: String,
+    /// The SHA3-256 digest of the MAC key share of the party.
+    pub mac_fingerprint: [u8; 32],
+    pub triple_count: u64,
+    /// The number of random sharings known by every party, indexed by the party ID.
+    pub rand_share_counts: Vec<u64>,
+}
+
+/// `PrepStore` is the preprocessing data of one party that can be written to and read from a file.
+/// The file starts with a magic string and a version number, followed by the header and the data in bincode.
+/// The triples and the random sharings of every party are stored in the order of their sequence numbers.
+#[derive(Clone, Debug)]
+pub struct PrepStore {
+    header: StoreHeader,
+    triples: Vec<TripleMsg>,
+    rand_shares: Vec<Vec<RandShareMsg>>,
+}
+
+fn mac_fingerprint(alpha_share: &Fp) -> [u8; 32] {
+    Sha3_256::digest(&bincode::serialize(alpha_share).expect("serialization failed")).into()
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+impl PrepStore {
+    /// Create the store of the party `party_id` with the MAC key share `alpha_share`,
+    /// `rand_shares[i]` are the random sharings known by party `i`.
+    pub fn new(party_id: PartyID, alpha_share: &Fp, triples: Vec<TripleMsg>, rand_shares: Vec<Vec<RandShareMsg>>) -> PrepStore {
+        let header = StoreHeader {
+            party_id,
+            modulus: FIELD_MODULUS.to_string(),
+            mac_fingerprint: mac_fingerprint(alpha_share),
+            triple_count: triples.len() as u64,
+            rand_share_counts: rand_shares.iter().map(|v| v.len() as u64).collect(),
+        };
+        PrepStore {
+            header,
+            triples,
+            rand_shares,
+        }
+    }
+
+    pub fn header(&self) -> &StoreHeader {
+        &self.header
+    }
+
+    /// The SHA3-256 digest of the content of the store, it identifies the store in the `ConsumptionLog`.
+    pub fn digest(&self) -> [u8; 32] {
+        let mut h = Sha3_256::new();
+        for bytes in [
+            bincode::serialize(&self.header),
+            bincode::serialize(&self.triples),
+            bincode::serialize(&self.rand_shares),
+        ] {
+            h.update(bytes.expect("serialization failed"));
+        }
+        h.finalize().into()
+    }
+
+    /// Write the store to `writer`.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(STORE_MAGIC)?;
+        writer.write_all(&STORE_VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut writer, &self.header).map_err(invalid_data)?;
+        bincode::serialize_into(&mut writer, &self.triples).map_err(invalid_data)?;
+        bincode::serialize_into(&mut writer, &self.rand_shares).map_err(invalid_data)?;
+        writer.flush()
+    }
+
+    /// Read a store from `reader`, the data must agree with the header.
+    pub fn read<R: Read>(mut reader: R) -> io::Result<PrepStore> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != STORE_MAGIC {
+            return Err(invalid_data("not a preprocessing file"));
+        }
+        let mut version = [0u8; 4];
+        reader.read_exact(&mut version)?;
+        if u32::from_le_bytes(version) != STORE_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported preprocessing file version {}",
+                u32::from_le_bytes(version)
+            )));
+        }
+
+        let header: StoreHeader = bincode::deserialize_from(&mut reader).map_err(invalid_data)?;
+        let triples: Vec<TripleMsg> = bincode::deserialize_from(&mut reader).map_err(invalid_data)?;
+        let rand_shares: Vec<Vec<RandShareMsg>> = bincode::deserialize_from(&mut reader).map_err(invalid_data)?;
+
+        let triples_ok = triples.len() as u64 == header.triple_count && triples.iter().enumerate().all(|(i, t)| t.seq == i as u64);
+        let rand_shares_ok =
+            rand_shares.len() == header.rand_share_counts.len()
+                && rand_shares.iter().zip(&header.rand_share_counts).enumerate().all(|(owner, (v, k))| {
+                    v.len() as u64 == *k && v.iter().enumerate().all(|(i, r)| r.seq == i as u64 && r.party_id as usize == owner)
+                });
+        if !triples_ok || !rand_shares_ok {
+            return Err(invalid_data("preprocessing data does not match the header"));
+        }
+        Ok(PrepStore {
+            header,
+            triples,
+            rand_shares,
+        })
+    }
+
+    /// Check that the store belongs to the party `id` with the MAC key share `alpha_share`
+    /// and that it has enough data for the `requests`.
+    pub fn check(&self, id: PartyID, alpha_share: &Fp, requests: &[PrepRequest]) -> io::Result<()> {
+        let header = &self.header;
+        if header.party_id != id {
+            return Err(invalid_data(format!("preprocessing file belongs to party {}", header.party_id)));
+        }
+        if header.modulus != FIELD_MODULUS {
+            return Err(invalid_data(format!("preprocessing file uses the modulus {}", header.modulus)));
+        }
+        if header.mac_fingerprint != mac_fingerprint(alpha_share) {
+            return Err(invalid_data("preprocessing file uses a different MAC key share"));
+        }
+
+        let mut triple_count = 0;
+        let mut rand_share_counts = vec![0; header.rand_share_counts.len()];
+        for req in requests {
+            match *req {
+                PrepRequest::Triples(k) => triple_count += k as u64,
+                PrepRequest::RandShares(owner, k) => match rand_share_counts.get_mut(owner as usize) {
+                    Some(count) => *count += k as u64,
+                    None => return Err(invalid_data(format!("preprocessing file has no random sharings for party {}", owner))),
+                },
+            }
+        }
+        if triple_count > header.triple_count || rand_share_counts.iter().zip(&header.rand_share_counts).any(|(need, have)| need > have) {
+            return Err(invalid_data("not enough preprocessing data in the file"));
+        }
+        Ok(())
+    }
+}
+
+/// The number of used elements in every sequence of a store.
+#[derive(Serialize, Deserialize, Clone, Default, Eq, PartialEq, Debug)]
+pub struct Consumed {
+    pub triples: u64,
+    /// The number of used random sharings known by every party, indexed by the party ID.
+    pub rand_shares: Vec<u64>,
+}
+
+/// `ConsumptionLog` is a file that records the preprocessing data that a party used from its stores.
+/// The parties use every sequence of a store from the start,
+/// so it is enough to keep the number of used elements of every sequence.
+/// The data is marked as used before the computation starts and the file is replaced atomically,
+/// so a crash may waste some data but it never allows the data to be used again.
+/// A reservation reads, checks and writes the log while it holds the lock file `<log>.lock`,
+/// so two nodes that use the same log at the same time cannot both reserve the same data.
+pub struct ConsumptionLog {
+    path: PathBuf,
+    entries: BTreeMap<[u8; 32], Consumed>,
+}
+
+impl ConsumptionLog {
+    /// Open the log at `path`, the log is empty if the file does not exist.
+    pub fn open(path: &Path) -> io::Result<ConsumptionLog> {
+        Ok(ConsumptionLog {
+            path: path.to_path_buf(),
+            entries: ConsumptionLog::load(path)?,
+        })
+    }
+
+    fn load(path: &Path) -> io::Result<BTreeMap<[u8; 32], Consumed>> {
+        match File::open(path) {
+            Ok(f) => bincode::deserialize_from(io::BufReader::new(f)).map_err(invalid_data),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The data of `store` that is used according to the log.
+    pub fn consumed(&self, store: &PrepStore) -> Consumed {
+        self.entries.get(&store.digest()).cloned().unwrap_or_default()
+    }
+
+    /// Mark the data of `store` that the `requests` need as used and write the log to disk,
+    /// it fails with `MPCError::PrepConsumedError` if some of the data is used already.
+    /// The log is read again under the lock, so the reservations of other processes since `open` are seen.
+    pub fn reserve(&mut self, store: &PrepStore, requests: &[PrepRequest]) -> Result<(), ApplicationError> {
+        let _lock = LogLock::acquire(&self.path)?;
+        self.entries = ConsumptionLog::load(&self.path)?;
+        let digest = store.digest();
+        let mut consumed = self.entries.get(&digest).cloned().unwrap_or_default();
+        let old = consumed.clone();
+        consumed.rand_shares.resize(store.header.rand_share_counts.len(), 0);
+        for req in requests {
+            let (used, before, k) = match *req {
+                PrepRequest::Triples(k) => (&mut consumed.triples, old.triples, k),
+                PrepRequest::RandShares(owner, k) => match consumed.rand_shares.get_mut(owner as usize) {
+                    Some(used) => (used, old.rand_shares.get(owner as usize).copied().unwrap_or_default(), k),
+                    None => return Err(invalid_data(format!("preprocessing file has no random sharings for party {}", owner)).into()),
+                },
+            };
+            if before > 0 {
+                return Err(MPCError::PrepConsumedError(req.clone()).into());
+            }
+            *used += k as u64;
+        }
+        self.entries.insert(digest, consumed);
+        self.save()?;
+        Ok(())
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let data = bincode::serialize(&self.entries).map_err(invalid_data)?;
+        crate::io::write_atomic(&self.path, &data, crate::io::PUBLIC_FILE_MODE)
+    }
+}
+
+/// How long a reservation waits for another process to release the lock of a consumption log.
+const LOG_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+const LOG_LOCK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// `LogLock` is the exclusive lock of a consumption log,
+/// it is the file `<log>.lock` that exists while the lock is held and it is removed on drop.
+struct LogLock {
+    path: PathBuf,
+}
+
+impl LogLock {
+    fn acquire(log: &Path) -> io::Result<LogLock> {
+        let mut name = log.as_os_str().to_owned();
+        name.push(".lock");
+        let path = PathBuf::from(name);
+        let deadline = Instant::now() + LOG_LOCK_TIMEOUT;
+        loop {
+            match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(LogLock { path }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && Instant::now() < deadline => thread::sleep(LOG_LOCK_INTERVAL),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        format!("the consumption log is locked by {}, remove it if no node is running", path.display()),
+                    ))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for LogLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Forward the data in `store` to `out` in the order of the `requests`,
+/// so that the party can use it like the replies of the preprocessing server.
+/// It stops early without an error if `out` is closed.
+pub fn run_store(requests: Vec<PrepRequest>, store: PrepStore, out: &Sender<PrepMsg>) -> io::Result<()> {
+    let mut triples = store.triples.into_iter();
+    let mut rand_shares: Vec<_> = store.rand_shares.into_iter().map(|v| v.into_iter()).collect();
+    for req in requests {
+        let (k, msgs): (usize, Vec<_>) = match req {
+            PrepRequest::Triples(k) => (k, triples.by_ref().take(k).map(PrepMsg::Triple).collect()),
+            PrepRequest::RandShares(owner, k) => match rand_shares.get_mut(owner as usize) {
+                Some(v) => (k, v.take(k).map(PrepMsg::RandShare).collect()),
+                None => (k, vec![]),
+            },
+        };
+        if msgs.len() != k {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "not enough preprocessing data in the file"));
+        }
+        for m in msgs {
+            if out.send(m).is_err() {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::unauth_combine;
+    use crate::vm::tests::MUL_PROG;
+    use crossbeam::channel::{bounded, unbounded};
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+    use std::sync::{Arc, Barrier};
+
+    const TEST_SEED: [u8; 32] = [8u8; 32];
+
+    fn unwrap_triple(m: PrepMsg) -> TripleMsg {
+        match m {
+            PrepMsg::Triple(t) => t,
+            m => panic!("expected triple, got {:?}", m),
+        }
+    }
+
+    #[test]
+    fn test_dealer() {
+        let n = 2;
+        let rng = &mut ChaCha20Rng::from_seed(TEST_SEED);
+        let alpha = Fp::random(rng);
+        let mut dealer = Dealer::new(n, alpha, rng);
+
+        // the parties may ask for different batch sizes, but they get the same triples in the same order
+        let batch_0 = dealer.handle(0, &PrepRequest::Triples(3)).unwrap();
+        let batch_1 = dealer.handle(1, &PrepRequest::Triples(2)).unwrap();
+        let batch_2 = dealer.handle(1, &PrepRequest::Triples(1)).unwrap();
+        assert_eq!((batch_0.seq, batch_1.seq, batch_2.seq), (0, 0, 2));
+        let triples_1: Vec<_> = batch_1.msgs.into_iter().chain(batch_2.msgs).map(unwrap_triple).collect();
+        for (t0, t1) in batch_0.msgs.into_iter().map(unwrap_triple).zip(triples_1) {
+            let a = unauth_combine(&vec![t0.a.share, t1.a.share]);
+            let b = unauth_combine(&vec![t0.b.share, t1.b.share]);
+            let c = unauth_combine(&vec![t0.c.share, t1.c.share]);
+            assert_eq!(a * b, c);
+        }
+        // all the triples are consumed by both parties
+        assert!(dealer.triples.items.is_empty());
+
+        // the random sharings of every party form their own sequence
+        assert_eq!(dealer.handle(0, &PrepRequest::RandShares(1, 2)).unwrap().seq, 0);
+        assert_eq!(dealer.handle(0, &PrepRequest::RandShares(0, 1)).unwrap().seq, 0);
+        assert_eq!(dealer.handle(0, &PrepRequest::RandShares(1, 1)).unwrap().seq, 2);
+
+        // bad requests
+        assert!(dealer.handle(2, &PrepRequest::Triples(1)).is_err());
+        assert!(dealer.handle(0, &PrepRequest::RandShares(2, 1)).is_err());
+        assert!(dealer.handle(0, &PrepRequest::Triples(MAX_BATCH_SIZE + 1)).is_err());
+    }
+
+    #[test]
+    fn test_dealer_pool_size() {
+        let rng = &mut ChaCha20Rng::from_seed(TEST_SEED);
+        let alpha = Fp::random(rng);
+        let mut dealer = Dealer::new(2, alpha, rng);
+
+        // party 0 can only run ahead of party 1 until the pool is full
+        let req = PrepRequest::RandShares(1, MAX_BATCH_SIZE);
+        for _ in 0..MAX_POOL_SIZE / MAX_BATCH_SIZE {
+            assert!(dealer.ready(0, &req));
+            dealer.handle(0, &req).unwrap();
+        }
+        assert!(!dealer.ready(0, &PrepRequest::RandShares(1, 1)));
+        assert_eq!(dealer.handle(0, &req).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(dealer.rand_shares[1].items.len(), MAX_POOL_SIZE);
+
+        // the other sequences are not affected
+        assert!(dealer.ready(0, &PrepRequest::RandShares(0, 1)));
+        assert!(dealer.ready(0, &PrepRequest::Triples(1)));
+
+        // party 0 can continue when party 1 catches up
+        assert!(dealer.ready(1, &req));
+        assert_eq!(dealer.handle(1, &PrepRequest::RandShares(1, 1)).unwrap().seq, 0);
+        assert!(dealer.ready(0, &PrepRequest::RandShares(1, 1)));
+        assert!(!dealer.ready(0, &PrepRequest::RandShares(1, 2)));
+        assert_eq!(dealer.handle(0, &PrepRequest::RandShares(1, 1)).unwrap().seq, MAX_POOL_SIZE as u64);
+    }
+
+    #[test]
+    fn test_plan_requests() {
+        let requests = vec![PrepRequest::RandShares(0, 1), PrepRequest::RandShares(1, 1), PrepRequest::Triples(1)];
+        assert_eq!(plan_requests(&MUL_PROG), requests);
+
+        let prog = vec![Instruction::Triple(0, 1, 2); MAX_BATCH_SIZE + 1];
+        assert_eq!(plan_requests(&prog), vec![PrepRequest::Triples(MAX_BATCH_SIZE), PrepRequest::Triples(1)]);
+    }
+
+    #[test]
+    fn test_store() {
+        let n = 2;
+        let rng = &mut ChaCha20Rng::from_seed(TEST_SEED);
+        let alpha_share = Fp::random(rng);
+        let (rand_shares, triples) = crate::crypto::gen_fake_prep(n, &Fp::random(rng), 1, 2, rng);
+        let triples = triples.into_iter().map(|t| t[1].clone()).collect();
+        let rand_shares = rand_shares.into_iter().map(|r| vec![r[1].clone()]).collect();
+        let store = PrepStore::new(1, &alpha_share, triples, rand_shares);
+
+        let mut buf = vec![];
+        store.write(&mut buf).unwrap();
+        let store = PrepStore::read(&buf[..]).unwrap();
+        assert_eq!(store.header().triple_count, 2);
+        assert_eq!(store.header().rand_share_counts, vec![1, 1]);
+
+        // the file must belong to the party and have enough data
+        let requests = plan_requests(&MUL_PROG);
+        assert!(store.check(1, &alpha_share, &requests).is_ok());
+        assert!(store.check(0, &alpha_share, &requests).is_err());
+        assert!(store.check(1, &Fp::random(rng), &requests).is_err());
+        assert!(store.check(1, &alpha_share, &[PrepRequest::Triples(3)]).is_err());
+        assert!(store.check(1, &alpha_share, &[PrepRequest::RandShares(2, 1)]).is_err());
+
+        // the data is forwarded in the order of the requests
+        let (s_out, r_out) = unbounded();
+        run_store(requests, store, &s_out).unwrap();
+        let msgs: Vec<_> = r_out.try_iter().collect();
+        assert_eq!(msgs.len(), 3);
+        assert!(matches!(&msgs[0], PrepMsg::RandShare(r) if r.party_id == 0 && r.clear.is_none()));
+        assert!(matches!(&msgs[1], PrepMsg::RandShare(r) if r.party_id == 1 && r.clear.is_some()));
+        assert!(matches!(&msgs[2], PrepMsg::Triple(t) if t.seq == 0));
+
+        // corrupted files
+        assert!(PrepStore::read(&buf[1..]).is_err());
+        assert!(PrepStore::read(&buf[..buf.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_consumption_log() {
+        let rng = &mut ChaCha20Rng::from_seed(TEST_SEED);
+        let alpha_share = Fp::random(rng);
+        let (rand_shares, triples) = crate::crypto::gen_fake_prep(2, &Fp::random(rng), 2, 2, rng);
+        let triples: Vec<_> = triples.into_iter().map(|t| t[0].clone()).collect();
+        let rand_shares: Vec<Vec<_>> = rand_shares.chunks(2).map(|c| c.iter().map(|r| r[0].clone()).collect()).collect();
+        let store = PrepStore::new(0, &alpha_share, triples.clone(), rand_shares.clone());
+
+        let dir = std::env::temp_dir().join(format!("ezmpc_test_consumption_log_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("consumed_0.bin");
+
+        let mut log = ConsumptionLog::open(&path).unwrap();
+        log.reserve(&store, &[PrepRequest::RandShares(0, 1), PrepRequest::Triples(1), PrepRequest::Triples(1)])
+            .unwrap();
+
+        // the log survives a restart and refuses the used data
+        let mut log = ConsumptionLog::open(&path).unwrap();
+        let consumed = Consumed {
+            triples: 2,
+            rand_shares: vec![1, 0],
+        };
+        assert_eq!(log.consumed(&store), consumed);
+        let err = log.reserve(&store, &[PrepRequest::Triples(1)]).unwrap_err();
+        assert!(matches!(
+            err,
+            ApplicationError::MPCError(MPCError::PrepConsumedError(PrepRequest::Triples(1)))
+        ));
+        log.reserve(&store, &[PrepRequest::RandShares(1, 2)]).unwrap();
+        assert!(log.reserve(&store, &[PrepRequest::RandShares(1, 1)]).is_err());
+
+        // a different store has its own entry
+        let other = PrepStore::new(0, &alpha_share, triples[1..].to_vec(), rand_shares);
+        assert_eq!(log.consumed(&other), Consumed::default());
+        log.reserve(&other, &[PrepRequest::Triples(1)]).unwrap();
+
+        // a copy of a preprocessing file is the same store
+        for name in ["prep_0.bin", "copy_of_prep_0.bin"] {
+            store.write(File::create(dir.join(name)).unwrap()).unwrap();
+        }
+        let copy = PrepStore::read(File::open(dir.join("copy_of_prep_0.bin")).unwrap()).unwrap();
+        let mut log = ConsumptionLog::open(&path).unwrap();
+        assert!(log.reserve(&copy, &[PrepRequest::Triples(1)]).is_err());
+
+        // the log is replaced without leaving temporary files behind
+        let mut names: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        names.sort();
+        assert_eq!(names, ["consumed_0.bin", "copy_of_prep_0.bin", "prep_0.bin"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_consumption_log_concurrent_reserve() {
+        let rng = &mut ChaCha20Rng::from_seed(TEST_SEED);
+        let alpha_share = Fp::random(rng);
+        let (_, triples) = crate::crypto::gen_fake_prep(2, &Fp::random(rng), 0, 2, rng);
+        let triples: Vec<_> = triples.into_iter().map(|t| t[0].clone()).collect();
+        let store = Arc::new(PrepStore::new(0, &alpha_share, triples, vec![vec![], vec![]]));
+
+        let dir = std::env::temp_dir().join(format!("ezmpc_test_consumption_log_concurrent_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("consumed_0.bin");
+
+        // both logs are opened before either reserves, only one of them may get the triples
+        for _ in 0..10 {
+            let _ = fs::remove_file(&path);
+            let logs: Vec<_> = (0..2).map(|_| ConsumptionLog::open(&path).unwrap()).collect();
+            let barrier = Arc::new(Barrier::new(2));
+            let handles: Vec<_> = logs
+                .into_iter()
+                .map(|mut log| {
+                    let store = store.clone();
+                    let barrier = barrier.clone();
+                    thread::spawn(move || {
+                        barrier.wait();
+                        log.reserve(&store, &[PrepRequest::Triples(2)])
+                    })
+                })
+                .collect();
+            let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+            assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+            assert!(results
+                .iter()
+                .any(|r| matches!(r, Err(ApplicationError::MPCError(MPCError::PrepConsumedError(PrepRequest::Triples(2)))))));
+        }
+
+        // the lock file is removed after the reservations
+        let names: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names, ["consumed_0.bin"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_client() {
+        let (s_req, r_req) = unbounded();
+        let (s_batch, r_batch) = unbounded();
+        let (s_out, r_out) = bounded(MAX_BATCH_SIZE);
+        let handle = thread::spawn(move || {
+            let mut rng = ChaCha20Rng::from_seed(TEST_SEED);
+            let mut dealer = Dealer::new(2, Fp::random(&mut rng), rng);
+            for req in r_req.iter() {
+                s_batch.send(dealer.handle(0, &req).unwrap()).unwrap();
+            }
+        });
+
+        run_client(plan_requests(&MUL_PROG), &s_req, &r_batch, &s_out).unwrap();
+        let msgs: Vec<_> = r_out.try_iter().collect();
+        assert_eq!(msgs.len(), 3);
+        assert!(matches!(&msgs[0], PrepMsg::RandShare(r) if r.party_id == 0));
+        assert!(matches!(&msgs[1], PrepMsg::RandShare(r) if r.party_id == 1));
+        assert!(matches!(&msgs[2], PrepMsg::Triple(_)));
+
+        // the sequence numbers continue, so running the requests from the start again is an error
+        let err = run_client(plan_requests(&MUL_PROG), &s_req, &r_batch, &s_out).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        drop(s_req);
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,513 @@
+//! This module keeps the links between the parties alive when the network fails for a short time.
+//! Every message on a link carries a sequence number and the number of messages received from the peer so far,
+//! which acknowledges them, and a message is kept until the peer acknowledges it.
+//! When the stream of a link breaks, the party that made the connection connects again
+//! and the other party waits for it, both for at most a grace period.
+//! On the new stream, the unacknowledged messages are sent again and the receiver drops the duplicates,
+//! so the party on top of the link does not notice the failure and the computation carries on.
+//! Only the links between the parties are resumable, the links to the synchronizer and to the preprocessing server
+//! are not, so the computation fails if one of them breaks.
+
+use crate::frame::Tagged;
+use crate::io::{wrap_link, StreamHandle, WrappedStream, TCPSTREAM_CAP};
+use crate::net::Stream;
+use crate::noise;
+
+use crossbeam::channel::{at, bounded, never, select, Receiver, Select, Sender};
+use log::{debug, error, info};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// how long to wait between two attempts to connect again
+const REDIAL_INTERVAL: Duration = Duration::from_millis(100);
+// acknowledge the received messages explicitly if we did not send anything for this many messages
+const ACK_INTERVAL: u64 = 64;
+
+/// A new stream for a link, with the session that encrypts it.
+pub type NewStream = (Stream, Option<noise::Session>);
+
+/// `Redial` decides how a link gets a new stream after its stream broke.
+pub enum Redial {
+    /// We made the connection, so we connect again using this function.
+    Connect(Box<dyn FnMut() -> io::Result<NewStream> + Send>),
+    /// The peer made the connection, the streams of its new connections arrive on this channel.
+    Accept(Receiver<NewStream>),
+}
+
+/// The message that is sent on the stream of a resumable link.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum LinkMsg<T> {
+    /// A message with its sequence number and the number of messages received from the peer so far.
+    Data { seq: u64, ack: u64, msg: T },
+    /// The number of messages received from the peer so far,
+    /// it is sent first on a new stream so that the peer knows which messages we are missing.
+    Ack(u64),
+    /// The peer closes the link on purpose, so the link should not be connected again.
+    Close,
+}
+
+impl<T> Tagged for LinkMsg<T> {
+    const TAG: u8 = 7;
+}
+
+type Inner<T> = WrappedStream<LinkMsg<T>, LinkMsg<T>, StreamHandle>;
+
+struct Link<T> {
+    redial: Redial,
+    grace: Duration,
+    max_frame_size: usize,
+    // the sequence number of the next message that we send and of the next message that we expect
+    next_send: u64,
+    next_recv: u64,
+    // the last acknowledgement that we sent
+    acked: u64,
+    unacked: VecDeque<(u64, T)>,
+    // the messages that the stream and the party did not take yet,
+    // they are kept here so that a full channel in one direction never stops the other direction
+    to_peer: VecDeque<LinkMsg<T>>,
+    to_party: VecDeque<T>,
+    closed: bool,
+    shutdown: bool,
+    // the stream broke and the link is closed if there is no new stream by this time
+    deadline: Option<Instant>,
+    next_dial: Instant,
+}
+
+// what the link does next, see `Link::next_event`
+enum Event<T> {
+    Write(Option<T>),
+    Read(Option<LinkMsg<T>>),
+    Accept(Option<NewStream>),
+    Dial,
+    Expired,
+    Shutdown,
+    // a queued message was taken by the stream or by the party
+    Forwarded,
+}
+
+/// Wrap a stream into channels like `io::wrap_stream`,
+/// but connect again with `redial` when the stream breaks and resend the messages that the peer has not received.
+/// If there is no new stream within `grace`, or if the peer violates the protocol, the link is closed.
+pub fn resumable_link<T>(
+    stream: Stream,
+    session: Option<noise::Session>,
+    max_frame_size: usize,
+    redial: Redial,
+    grace: Duration,
+) -> WrappedStream<T, T, JoinHandle<()>>
+where
+    T: 'static + Sync + Send + Clone + Serialize + DeserializeOwned,
+{
+    let (reader_s, reader_r) = bounded(TCPSTREAM_CAP);
+    let (writer_s, writer_r) = bounded(TCPSTREAM_CAP);
+    let (error_s, error_r) = bounded(1);
+    let (shutdown_s, shutdown_r) = bounded(1);
+    let inner = wrap_link(stream, session, max_frame_size);
+
+    let hdl = thread::spawn(move || {
+        let mut link = Link {
+            redial,
+            grace,
+            max_frame_size,
+            next_send: 0,
+            next_recv: 0,
+            acked: 0,
+            unacked: VecDeque::new(),
+            to_peer: VecDeque::new(),
+            to_party: VecDeque::new(),
+            closed: false,
+            shutdown: false,
+            deadline: None,
+            next_dial: Instant::now(),
+        };
+        let inner = link.run(inner, &writer_r, &reader_s, &shutdown_r);
+        // the violation is reported before the receiver is disconnected
+        if let Ok(v) = inner.2.try_recv() {
+            let _ = error_s.try_send(v);
+        }
+        close_inner(inner);
+        link.deliver(&reader_s, &shutdown_r);
+    });
+
+    (writer_s, reader_r, error_r, shutdown_s, hdl)
+}
+
+fn close_inner<T>((writer_s, reader_r, _, shutdown_s, hdl): Inner<T>) {
+    drop(writer_s);
+    drop(reader_r);
+    let _ = shutdown_s.send(());
+    hdl.join().expect("stream thread panicked");
+}
+
+impl<T> Link<T>
+where
+    T: 'static + Sync + Send + Clone + Serialize + DeserializeOwned,
+{
+    // forward the messages between the channels and the stream until the link is closed,
+    // the last stream is returned
+    fn run(&mut self, mut inner: Inner<T>, writer_r: &Receiver<T>, reader_s: &Sender<T>, shutdown_r: &Receiver<()>) -> Inner<T> {
+        let mut accept_r = match &self.redial {
+            Redial::Accept(r) => r.clone(),
+            Redial::Connect(_) => never(),
+        };
+        loop {
+            match self.next_event(&inner, &accept_r, writer_r, reader_s, shutdown_r) {
+                Event::Write(Some(msg)) => self.send(msg),
+                // nobody can send on this link anymore
+                Event::Write(None) => {
+                    self.close(writer_r);
+                    break;
+                }
+                Event::Read(Some(msg)) => {
+                    if !self.handle(msg) {
+                        break;
+                    }
+                }
+                Event::Read(None) => {
+                    if !inner.2.is_empty() || self.closed {
+                        break;
+                    }
+                    info!("link broke, waiting at most {:?} for a new stream", self.grace);
+                    self.deadline = Some(Instant::now() + self.grace);
+                    self.next_dial = Instant::now();
+                }
+                // the peer may also connect again before we noticed that the stream broke
+                Event::Accept(Some(new)) => self.resume(&mut inner, new),
+                Event::Accept(None) => accept_r = never(),
+                Event::Dial => {
+                    if let Some(new) = self.dial() {
+                        self.resume(&mut inner, new);
+                    }
+                }
+                Event::Expired => {
+                    error!("link could not be resumed within {:?}", self.grace);
+                    break;
+                }
+                Event::Shutdown => {
+                    // the peer may still be waiting for the messages that are queued
+                    self.shutdown = true;
+                    self.close(writer_r);
+                    break;
+                }
+                Event::Forwarded => {}
+            }
+        }
+        self.flush(&inner);
+        inner
+    }
+
+    // wait until one of the channels is ready, the link never blocks on sending
+    // so that the stream is read while the party is busy and the other way around
+    fn next_event(
+        &mut self,
+        inner: &Inner<T>,
+        accept_r: &Receiver<NewStream>,
+        writer_r: &Receiver<T>,
+        reader_s: &Sender<T>,
+        shutdown_r: &Receiver<()>,
+    ) -> Event<T> {
+        let up = self.deadline.is_none();
+        let dial_r = match (&self.redial, self.deadline) {
+            (Redial::Connect(_), Some(_)) => at(self.next_dial),
+            _ => never(),
+        };
+        let expired_r = self.deadline.map_or_else(never, at);
+
+        let mut sel = Select::new();
+        // only take the messages of one side while the other side keeps up with them
+        let write = (self.to_peer.len() < TCPSTREAM_CAP).then(|| sel.recv(writer_r));
+        let read = (up && self.to_party.len() < TCPSTREAM_CAP).then(|| sel.recv(&inner.1));
+        let send = (up && !self.to_peer.is_empty()).then(|| sel.send(&inner.0));
+        let deliver = (!self.to_party.is_empty()).then(|| sel.send(reader_s));
+        let accept = Some(sel.recv(accept_r));
+        let dial = Some(sel.recv(&dial_r));
+        let expired = Some(sel.recv(&expired_r));
+        let shutdown = Some(sel.recv(shutdown_r));
+
+        let oper = sel.select();
+        let i = Some(oper.index());
+        if i == write {
+            Event::Write(oper.recv(writer_r).ok())
+        } else if i == read {
+            Event::Read(oper.recv(&inner.1).ok())
+        } else if i == send {
+            let msg = self.to_peer.pop_front().expect("nothing to send");
+            // if the stream is broken then the messages are sent again on the next stream
+            if oper.send(&inner.0, msg).is_err() {
+                self.to_peer.clear();
+            }
+            Event::Forwarded
+        } else if i == deliver {
+            let msg = self.to_party.pop_front().expect("nothing to deliver");
+            // the party does not read from this link anymore
+            if oper.send(reader_s, msg).is_err() {
+                self.to_party.clear();
+            }
+            Event::Forwarded
+        } else if i == accept {
+            Event::Accept(oper.recv(accept_r).ok())
+        } else if i == dial {
+            let _ = oper.recv(&dial_r);
+            Event::Dial
+        } else if i == expired {
+            let _ = oper.recv(&expired_r);
+            Event::Expired
+        } else if i == shutdown {
+            let _ = oper.recv(shutdown_r);
+            Event::Shutdown
+        } else {
+            unreachable!("unknown operation {}", oper.index())
+        }
+    }
+
+    fn send(&mut self, msg: T) {
+        let seq = self.next_send;
+        self.next_send += 1;
+        self.unacked.push_back((seq, msg.clone()));
+        self.acked = self.next_recv;
+        self.to_peer.push_back(LinkMsg::Data {
+            seq,
+            ack: self.next_recv,
+            msg,
+        });
+    }
+
+    // returns false if the link cannot continue
+    fn handle(&mut self, msg: LinkMsg<T>) -> bool {
+        match msg {
+            LinkMsg::Data { seq, ack, msg } => {
+                self.acknowledged(ack);
+                if seq < self.next_recv {
+                    debug!("dropping duplicate message {}", seq);
+                    return true;
+                }
+                if seq > self.next_recv {
+                    error!("expected message {} but got message {}", self.next_recv, seq);
+                    return false;
+                }
+                self.next_recv += 1;
+                if self.next_recv - self.acked >= ACK_INTERVAL {
+                    self.acked = self.next_recv;
+                    self.to_peer.push_back(LinkMsg::Ack(self.next_recv));
+                }
+                self.to_party.push_back(msg);
+            }
+            LinkMsg::Ack(ack) => self.acknowledged(ack),
+            LinkMsg::Close => self.closed = true,
+        }
+        true
+    }
+
+    // forget the messages that the peer has received
+    fn acknowledged(&mut self, ack: u64) {
+        while self.unacked.front().is_some_and(|(seq, _)| *seq < ack) {
+            self.unacked.pop_front();
+        }
+    }
+
+    fn close(&mut self, writer_r: &Receiver<T>) {
+        for msg in writer_r.try_iter() {
+            self.send(msg);
+        }
+        self.to_peer.push_back(LinkMsg::Close);
+    }
+
+    // hand the queued messages to the stream before it is closed
+    fn flush(&mut self, inner: &Inner<T>) {
+        if self.deadline.is_some() {
+            return;
+        }
+        for msg in self.to_peer.drain(..) {
+            if inner.0.send_timeout(msg, self.grace).is_err() {
+                break;
+            }
+        }
+    }
+
+    // hand the received messages to the party after the link is closed,
+    // unless the party shut the link down, then it does not read them anymore
+    fn deliver(&mut self, reader_s: &Sender<T>, shutdown_r: &Receiver<()>) {
+        for msg in self.to_party.drain(..) {
+            let res = if self.shutdown {
+                reader_s.try_send(msg).is_ok()
+            } else {
+                select! {
+                    send(reader_s, msg) -> res => res.is_ok(),
+                    recv(shutdown_r) -> _ => false,
+                }
+            };
+            if !res {
+                break;
+            }
+        }
+    }
+
+    // try to connect again, the next attempt is after `REDIAL_INTERVAL`
+    fn dial(&mut self) -> Option<NewStream> {
+        if let Redial::Connect(connect) = &mut self.redial {
+            match connect() {
+                Ok(new) => return Some(new),
+                Err(e) => debug!("failed to connect again: {}", e),
+            }
+        }
+        self.next_dial = Instant::now() + REDIAL_INTERVAL;
+        None
+    }
+
+    // replace the stream and send the messages that the peer might have missed
+    fn resume(&mut self, inner: &mut Inner<T>, (stream, session): NewStream) {
+        info!("resuming link, sending {} messages again", self.unacked.len());
+        close_inner(std::mem::replace(inner, wrap_link(stream, session, self.max_frame_size)));
+        self.closed = false;
+        self.deadline = None;
+        self.acked = self.next_recv;
+        // the messages for the old stream are either acknowledged or sent again below
+        self.to_peer.clear();
+        self.to_peer.push_back(LinkMsg::Ack(self.next_recv));
+        let ack = self.next_recv;
+        self.to_peer.extend(self.unacked.iter().map(|(seq, msg)| LinkMsg::Data {
+            seq: *seq,
+            ack,
+            msg: msg.clone(),
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::FrameError;
+    use crossbeam::channel::RecvTimeoutError;
+    use std::net::Shutdown;
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+    const GRACE: Duration = Duration::from_millis(500);
+
+    type Pair = (WrappedStream<u64, u64, JoinHandle<()>>, WrappedStream<u64, u64, JoinHandle<()>>);
+
+    // link a connects again by creating a new socket pair and handing the other end to link b,
+    // `connects` counts the attempts and `fail` makes them fail
+    fn make_pair(connects: Arc<AtomicUsize>, fail: bool) -> (Pair, UnixStream) {
+        let (sa, sb) = UnixStream::pair().unwrap();
+        let raw = sa.try_clone().unwrap();
+        let (accept_s, accept_r) = bounded(1);
+        let connect = move || {
+            connects.fetch_add(1, Ordering::SeqCst);
+            if fail {
+                return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"));
+            }
+            let (x, y) = UnixStream::pair()?;
+            accept_s.send((Stream::Unix(y), None)).unwrap();
+            Ok((Stream::Unix(x), None))
+        };
+        let a = resumable_link(Stream::Unix(sa), None, 1024, Redial::Connect(Box::new(connect)), GRACE);
+        let b = resumable_link(Stream::Unix(sb), None, 1024, Redial::Accept(accept_r), GRACE);
+        ((a, b), raw)
+    }
+
+    fn close(link: WrappedStream<u64, u64, JoinHandle<()>>) {
+        // the link might be closed already
+        let _ = link.3.send(());
+        link.4.join().unwrap();
+    }
+
+    #[test]
+    fn test_resume() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let ((a, b), raw) = make_pair(connects.clone(), false);
+        for i in 0..5 {
+            a.0.send(i).unwrap();
+            b.0.send(i + 100).unwrap();
+        }
+        for i in 0..5 {
+            assert_eq!(b.1.recv_timeout(TIMEOUT).unwrap(), i);
+            assert_eq!(a.1.recv_timeout(TIMEOUT).unwrap(), i + 100);
+        }
+
+        // break the stream, the messages are delivered exactly once and in order
+        raw.shutdown(Shutdown::Both).unwrap();
+        for i in 5..10 {
+            a.0.send(i).unwrap();
+            b.0.send(i + 100).unwrap();
+        }
+        for i in 5..10 {
+            assert_eq!(b.1.recv_timeout(TIMEOUT).unwrap(), i);
+            assert_eq!(a.1.recv_timeout(TIMEOUT).unwrap(), i + 100);
+        }
+        assert!(b.1.recv_timeout(Duration::from_millis(100)).is_err());
+        assert!(connects.load(Ordering::SeqCst) >= 1);
+
+        // closing on purpose does not connect again
+        let before = connects.load(Ordering::SeqCst);
+        close(a);
+        assert_eq!(b.1.recv_timeout(TIMEOUT), Err(RecvTimeoutError::Disconnected));
+        assert_eq!(connects.load(Ordering::SeqCst), before);
+        close(b);
+    }
+
+    #[test]
+    fn test_resume_both_directions() {
+        // both parties send much more than a channel holds before they read,
+        // the link keeps reading the stream while the party does not take the messages and the other way around
+        let count = 5 * TCPSTREAM_CAP as u64;
+        let ((a, b), _raw) = make_pair(Arc::new(AtomicUsize::new(0)), false);
+        for i in 0..count {
+            a.0.send_timeout(i, TIMEOUT).unwrap();
+            b.0.send_timeout(i + count, TIMEOUT).unwrap();
+        }
+        for i in 0..count {
+            assert_eq!(b.1.recv_timeout(TIMEOUT).unwrap(), i);
+            assert_eq!(a.1.recv_timeout(TIMEOUT).unwrap(), i + count);
+        }
+        close(a);
+        close(b);
+    }
+
+    #[test]
+    fn test_resume_timeout() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let ((a, b), raw) = make_pair(connects.clone(), true);
+        raw.shutdown(Shutdown::Both).unwrap();
+
+        // both ends give up after the grace period
+        let start = Instant::now();
+        assert_eq!(a.1.recv_timeout(GRACE * 4), Err(RecvTimeoutError::Disconnected));
+        assert_eq!(b.1.recv_timeout(GRACE * 4), Err(RecvTimeoutError::Disconnected));
+        assert!(start.elapsed() >= GRACE);
+        assert!(connects.load(Ordering::SeqCst) > 1);
+        assert!(a.2.try_recv().is_err());
+        close(a);
+        close(b);
+    }
+
+    #[test]
+    fn test_resume_violation() {
+        // a protocol violation is reported and the link is not connected again
+        let connects = Arc::new(AtomicUsize::new(0));
+        let (sa, mut sb) = UnixStream::pair().unwrap();
+        let counter = connects.clone();
+        let connect = move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Err(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"))
+        };
+        let a = resumable_link::<u64>(Stream::Unix(sa), None, 1024, Redial::Connect(Box::new(connect)), GRACE);
+        crate::frame::write_frame(&mut sb, LinkMsg::<u64>::TAG + 1, b"").unwrap();
+        assert!(a.1.recv_timeout(TIMEOUT).is_err());
+        assert_eq!(
+            a.2.recv_timeout(TIMEOUT).unwrap(),
+            FrameError::BadTag {
+                expected: LinkMsg::<u64>::TAG,
+                got: LinkMsg::<u64>::TAG + 1
+            }
+        );
+        assert_eq!(connects.load(Ordering::SeqCst), 0);
+        close(a);
+    }
+}
@@ -0,0 +1,314 @@
+//! This module runs a whole computation in one process, which is useful for development.
+//! The parties, the synchronizer and the preprocessing are connected by in-memory channels.
+
+use crate::algebra::Fp;
+use crate::crypto::{gen_fake_rand_share, gen_fake_triple, unauth_combine, unauth_share};
+use crate::error::{MPCError, Timeouts};
+use crate::fault::{FaultConfig, FaultTransport};
+use crate::message::{PartyID, PartyMsg, PrepMsg, PrepRequest, SyncMsg, SyncReplyMsg};
+use crate::party::{BroadcastMode, Party};
+use crate::prep::plan_requests;
+use crate::synchronizer::Synchronizer;
+use crate::transport::{ChanTransport, CountingTransport, LoopbackTransport, Transport, TransportStats};
+use crate::vm::{Instruction, Reg};
+
+use crossbeam::channel::{bounded, Receiver, Sender};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const SYNC_CAP: usize = 5;
+
+pub(crate) type PartyResult = Result<Vec<Fp>, MPCError>;
+
+/// The outcome of `simulate`.
+#[derive(Clone, Debug)]
+pub struct SimReport {
+    /// The outputs of the program, see `combine_outputs`.
+    pub outputs: Vec<Option<Fp>>,
+    /// The outputs of every party as returned by its VM, indexed by the party ID.
+    pub party_outputs: Vec<Vec<Fp>>,
+    /// The messages that every party sent and received, indexed by the party ID.
+    pub stats: Vec<TransportStats>,
+    /// The number of triples and random sharings that every party received.
+    pub triples: usize,
+    pub rand_shares: usize,
+    /// The time from starting the parties until all of them stopped.
+    pub elapsed: Duration,
+}
+
+// the channels of every party to the synchronizer, indexed by the party ID
+pub(crate) type PartySyncChans = (Vec<Sender<SyncReplyMsg>>, Vec<Receiver<SyncMsg>>);
+
+pub(crate) fn create_sync_chans(n: usize) -> (ChanTransport<SyncMsg, SyncReplyMsg>, PartySyncChans) {
+    let (from_sync, to_party) = (0..n).map(|_| bounded(SYNC_CAP)).unzip();
+    let (from_party, to_sync) = (0..n).map(|_| bounded(SYNC_CAP)).unzip();
+    (ChanTransport::new(from_sync, to_sync), (from_party, to_party))
+}
+
+/// The MAC key share and the preprocessing messages of every party, indexed by the party ID.
+pub(crate) struct FakePrep {
+    pub(crate) alpha_shares: Vec<Fp>,
+    pub(crate) msgs: Vec<Vec<PrepMsg>>,
+}
+
+impl FakePrep {
+    /// Create the preprocessing data that `prog` needs with a random MAC key,
+    /// in the order of `prep::plan_requests` like the preprocessing server and `prep::run_store` deliver it.
+    pub(crate) fn new(n: usize, prog: &[Instruction], rng: &mut impl Rng) -> FakePrep {
+        let alpha: Fp = Fp::random(rng);
+        let alpha_shares = unauth_share(&alpha, n, rng);
+
+        let mut msgs: Vec<Vec<PrepMsg>> = (0..n).map(|_| Vec::new()).collect();
+        let mut next_triple = 0;
+        let mut next_rand_share = vec![0; n];
+        for req in plan_requests(prog) {
+            let all: Vec<Vec<PrepMsg>> = match req {
+                PrepRequest::Triples(k) => (0..k)
+                    .map(|_| {
+                        next_triple += 1;
+                        let ss = gen_fake_triple(n, &alpha, next_triple - 1, rng);
+                        ss.into_iter().map(PrepMsg::Triple).collect()
+                    })
+                    .collect(),
+                // the program is rejected by the VM of every party
+                PrepRequest::RandShares(owner, _) if owner as usize >= n => continue,
+                PrepRequest::RandShares(owner, k) => (0..k)
+                    .map(|_| {
+                        next_rand_share[owner as usize] += 1;
+                        let ss = gen_fake_rand_share(n, &alpha, owner, next_rand_share[owner as usize] - 1, rng);
+                        ss.into_iter().map(PrepMsg::RandShare).collect()
+                    })
+                    .collect(),
+            };
+            for ms in all {
+                for (party_msgs, m) in msgs.iter_mut().zip(ms) {
+                    party_msgs.push(m);
+                }
+            }
+        }
+        FakePrep { alpha_shares, msgs }
+    }
+}
+
+/// Run the program on every transport and return the results of the parties and the synchronizer.
+/// The preprocessing data is created with a random MAC key before the parties start.
+pub(crate) fn run_parties<T>(
+    transports: Vec<T>,
+    synchronized: bool,
+    bcast_mode: BroadcastMode,
+    prog: Vec<Instruction>,
+    regs: Vec<Reg>,
+    rng: &mut impl Rng,
+) -> (Vec<PartyResult>, Option<Result<(), MPCError>>)
+where
+    T: 'static + Transport<PartyMsg, PartyMsg>,
+{
+    let prep = FakePrep::new(transports.len(), &prog, rng);
+    spawn_parties(transports, synchronized, bcast_mode, prog, regs, prep, rng)
+}
+
+/// Like `run_parties`, but every party uses the given preprocessing data.
+pub(crate) fn spawn_parties<T>(
+    transports: Vec<T>,
+    synchronized: bool,
+    bcast_mode: BroadcastMode,
+    prog: Vec<Instruction>,
+    regs: Vec<Reg>,
+    prep: FakePrep,
+    rng: &mut impl Rng,
+) -> (Vec<PartyResult>, Option<Result<(), MPCError>>)
+where
+    T: 'static + Transport<PartyMsg, PartyMsg>,
+{
+    let n = transports.len();
+    let (sync_chans_for_sync, sync_chans_for_party) = create_sync_chans(n);
+
+    // the preprocessing channels are large enough to hold all the messages
+    let FakePrep { alpha_shares, msgs } = prep;
+    let preproc_chans: Vec<Receiver<PrepMsg>> = msgs
+        .into_iter()
+        .map(|ms| {
+            let (s, r) = bounded(ms.len());
+            for m in ms {
+                s.send(m).unwrap();
+            }
+            r
+        })
+        .collect();
+
+    let sync_handle = if synchronized {
+        Some(Synchronizer::spawn(sync_chans_for_sync, Timeouts::default().sync))
+    } else {
+        None
+    };
+    let party_handles: Vec<JoinHandle<_>> = transports
+        .into_iter()
+        .zip(regs)
+        .enumerate()
+        .map(|(i, (transport, reg))| {
+            Party::spawn(
+                i as PartyID,
+                alpha_shares[i].clone(),
+                reg,
+                prog.clone(),
+                bcast_mode,
+                Timeouts::default(),
+                if synchronized {
+                    Some((sync_chans_for_party.0[i].clone(), sync_chans_for_party.1[i].clone()))
+                } else {
+                    None
+                },
+                preproc_chans[i].clone(),
+                transport,
+                Some(rng.gen()),
+            )
+        })
+        .collect();
+
+    let results = party_handles.into_iter().map(|h| h.join().expect("party thread panicked")).collect();
+    (results, sync_handle.map(|h| h.join().expect("synchronizer thread panicked")))
+}
+
+// the number of random sharings and the number of triples that every party receives for `prog`
+fn prep_counts(prog: &[Instruction]) -> (usize, usize) {
+    let rand_count = prog.iter().filter(|i| matches!(i, Instruction::Input(_, _, _))).count();
+    let triple_count = prog.iter().filter(|i| matches!(i, Instruction::Triple(_, _, _))).count();
+    (rand_count, triple_count)
+}
+
+/// Combine the outputs of the parties in the order of the output instructions in `prog`.
+/// The secret outputs are added up, a clear output is only kept if it is the same for every party,
+/// which is not the case if it is the clear input of one party.
+pub fn combine_outputs(prog: &[Instruction], party_outputs: &[Vec<Fp>]) -> Vec<Option<Fp>> {
+    prog.iter()
+        .filter(|i| matches!(i, Instruction::COutput(_) | Instruction::SOutput(_)))
+        .enumerate()
+        .map(|(k, i)| {
+            let values: Vec<Fp> = party_outputs.iter().map(|out| out[k].clone()).collect();
+            match i {
+                Instruction::COutput(_) if values.iter().all(|v| *v == values[0]) => Some(values[0].clone()),
+                Instruction::COutput(_) => None,
+                _ => Some(unauth_combine(&values)),
+            }
+        })
+        .collect()
+}
+
+/// Run `prog` with `n` parties in this process, party `i` inputs the values `inputs_per_party[i]`
+/// in the order of its input instructions.
+/// It returns the first error of a party or the synchronizer.
+pub fn simulate(n: usize, prog: Vec<Instruction>, inputs_per_party: Vec<Vec<Fp>>) -> Result<SimReport, MPCError> {
+    simulate_with_faults(n, prog, inputs_per_party, FaultConfig::default())
+}
+
+/// Like `simulate`, but the links between the parties suffer from the faults in `faults`.
+/// A fault that the parties cannot recover from is returned as an error.
+pub fn simulate_with_faults(n: usize, prog: Vec<Instruction>, inputs_per_party: Vec<Vec<Fp>>, faults: FaultConfig) -> Result<SimReport, MPCError> {
+    let mut regs = Vec::with_capacity(n);
+    for i in 0..n {
+        let inputs = inputs_per_party.get(i).cloned().unwrap_or_default();
+        regs.push(Reg::from_prog(i as PartyID, &prog, inputs)?);
+    }
+
+    let transports: Vec<_> = LoopbackTransport::mesh(n, Duration::ZERO)
+        .into_iter()
+        .map(|t| CountingTransport::new(FaultTransport::new(t, faults)))
+        .collect();
+    let counters: Vec<_> = transports.iter().map(|t| t.counters()).collect();
+
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let start = Instant::now();
+    let (results, sync_result) = run_parties(transports, true, BroadcastMode::Plain, prog.clone(), regs, rng);
+    let elapsed = start.elapsed();
+
+    let party_outputs = results.into_iter().collect::<Result<Vec<_>, _>>()?;
+    if let Some(res) = sync_result {
+        res?;
+    }
+    let (rand_count, triples) = prep_counts(&prog);
+    Ok(SimReport {
+        outputs: combine_outputs(&prog, &party_outputs),
+        party_outputs,
+        stats: counters.iter().map(|c| c.stats()).collect(),
+        triples,
+        rand_shares: rand_count,
+        elapsed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::tests::MUL_PROG;
+
+    #[test]
+    fn test_simulate() {
+        let rng = &mut ChaCha20Rng::from_entropy();
+        let (x, y) = (Fp::random(rng), Fp::random(rng));
+        let report = simulate(3, MUL_PROG.to_vec(), vec![vec![x.clone()], vec![y.clone()]]).unwrap();
+        assert_eq!(report.outputs, vec![Some(&x * &y)]);
+        assert_eq!(report.party_outputs.len(), 3);
+        assert_eq!(report.triples, 1);
+        // every party sends to every party, including itself, but only the input owners send the masked inputs
+        assert!(report.stats.iter().all(|s| s.sent > 0 && s.received > 0));
+        assert!(report.stats[0].sent > report.stats[2].sent);
+
+        // an opened value is the same for every party
+        let prog = vec![
+            Instruction::Input(0, 0, 1),
+            Instruction::Open(0, 0),
+            Instruction::COutput(0),
+            Instruction::SOutput(0),
+            Instruction::Stop,
+        ];
+        let report = simulate(2, prog, vec![vec![], vec![x.clone()]]).unwrap();
+        assert_eq!(report.outputs, vec![Some(x.clone()), Some(x.clone())]);
+    }
+
+    #[test]
+    fn test_simulate_with_faults() {
+        let rng = &mut ChaCha20Rng::from_entropy();
+        let inputs = vec![vec![Fp::random(rng)], vec![Fp::random(rng)]];
+        let delay = FaultConfig {
+            delay: Duration::from_millis(1),
+            ..Default::default()
+        };
+        assert!(simulate_with_faults(3, MUL_PROG.to_vec(), inputs.clone(), delay).is_ok());
+
+        // a lost message or a broken link stops the computation with an error
+        let drop = FaultConfig {
+            drop_rate: 1.0,
+            ..Default::default()
+        };
+        assert!(simulate_with_faults(3, MUL_PROG.to_vec(), inputs.clone(), drop).is_err());
+        let disconnect = FaultConfig {
+            disconnect_after: Some(1),
+            ..Default::default()
+        };
+        assert!(simulate_with_faults(3, MUL_PROG.to_vec(), inputs.clone(), disconnect).is_err());
+
+        // the parties expect the messages of a peer in order,
+        // so a reordered message either stops the computation or it did not matter
+        let reorder = FaultConfig {
+            reorder_rate: 1.0,
+            ..Default::default()
+        };
+        let expected = &inputs[0][0] * &inputs[1][0];
+        if let Ok(report) = simulate_with_faults(3, MUL_PROG.to_vec(), inputs, reorder) {
+            assert_eq!(report.outputs, vec![Some(expected)]);
+        }
+    }
+
+    #[test]
+    fn test_combine_outputs() {
+        let rng = &mut ChaCha20Rng::from_entropy();
+        let (x, y) = (Fp::random(rng), Fp::random(rng));
+        let prog = vec![Instruction::COutput(0), Instruction::SOutput(0), Instruction::Stop];
+        let outputs = combine_outputs(&prog, &[vec![x.clone(), x.clone()], vec![y.clone(), y.clone()]]);
+        assert_eq!(outputs, vec![None, Some(&x + &y)]);
+        let outputs = combine_outputs(&prog, &[vec![x.clone(), x.clone()], vec![x.clone(), y.clone()]]);
+        assert_eq!(outputs, vec![Some(x.clone()), Some(&x + &y)]);
+    }
+}
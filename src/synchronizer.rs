@@ -1,38 +1,42 @@
 //! This module contains a simple implementation of an alpha-synchronizer
-//! that communicates using channels.
+//! that communicates using a `Transport`.
 
-use crate::error::{MPCError, TIMEOUT};
-use crate::message;
-use crate::message::{SyncMsg, SyncReplyMsg};
+use crate::error::{AbortReason, MPCError, TransportError};
+use crate::message::{PartyID, SyncMsg, SyncReplyMsg};
+use crate::transport::Transport;
 
-use crossbeam::channel::{Receiver, RecvTimeoutError, SendError, Sender};
 use log::debug;
 use std::thread;
+use std::time::Duration;
 
-pub struct Synchronizer {
-    s_chans: Vec<Sender<SyncMsg>>,
-    r_chans: Vec<Receiver<SyncReplyMsg>>,
+pub struct Synchronizer<T> {
+    transport: T,
+    timeout: Duration,
 }
 
-impl Synchronizer {
+impl<T: 'static + Transport<SyncMsg, SyncReplyMsg>> Synchronizer<T> {
     /// Spawn a thread that runs the synchronizer.
-    /// It reads messages from `r_chans` and sends messages using `s_chans`.
-    /// These channels are assumed to be correctly connected to the parties.
-    pub fn spawn(s_chans: Vec<Sender<SyncMsg>>, r_chans: Vec<Receiver<SyncReplyMsg>>) -> thread::JoinHandle<Result<(), MPCError>> {
+    /// It sends and receives messages using `transport`,
+    /// which is assumed to be correctly connected to the parties.
+    /// The synchronizer waits at most `timeout` for the parties to finish an instruction.
+    pub fn spawn(transport: T, timeout: Duration) -> thread::JoinHandle<Result<(), MPCError>> {
         thread::spawn(move || {
-            let s = Synchronizer { s_chans, r_chans };
+            let s = Synchronizer { transport, timeout };
             s.broadcast(SyncMsg::Start)?;
             debug!("Starting");
             s.listen()
         })
     }
 
-    fn broadcast(&self, m: SyncMsg) -> Result<(), SendError<SyncMsg>> {
-        message::broadcast(&self.s_chans, m)
+    fn broadcast(&self, m: SyncMsg) -> Result<(), TransportError> {
+        debug!("Broadcasting {:?}", m);
+        self.transport.broadcast(m)
     }
 
-    fn recv_all(&self) -> Result<Vec<SyncReplyMsg>, RecvTimeoutError> {
-        message::receive(&self.r_chans, TIMEOUT)
+    fn recv_all(&self) -> Result<Vec<SyncReplyMsg>, TransportError> {
+        let out = self.transport.recv_all(self.timeout)?;
+        debug!("All received {:?}", out);
+        Ok(out)
     }
 
     fn listen(&self) -> Result<(), MPCError> {
@@ -42,32 +46,51 @@ impl Synchronizer {
             if msgs.iter().all(|x| *x == SyncReplyMsg::Done) {
                 debug!("All done");
                 break;
-            } else if msgs.contains(&SyncReplyMsg::Abort) {
-                self.broadcast(SyncMsg::Abort)?;
-                break;
+            } else if let Some((by, reason)) = msgs.iter().enumerate().find_map(|(i, x)| match x {
+                // like the parties, we only trust that the sender aborted and not the reason that it claims
+                SyncReplyMsg::Abort(_, reason) => Some((i as PartyID, reason.clone().relayed())),
+                _ => None,
+            }) {
+                return Err(self.abort(by, reason));
             } else if msgs.iter().all(|x| *x == SyncReplyMsg::Ok) {
                 self.broadcast(SyncMsg::Next)?;
             } else {
-                panic!("unexpected messages {:?}", msgs);
+                // the parties are not at the same instruction, e.g., one of them is done before the others,
+                // the first party that disagrees with party 0 is reported
+                let by = msgs.iter().position(|x| *x != msgs[0]).unwrap_or(0) as PartyID;
+                debug!("Unexpected messages {:?}", msgs);
+                return Err(self.abort(by, AbortReason::UnexpectedSyncMessage));
             }
         }
         Ok(())
     }
+
+    fn abort(&self, by: PartyID, reason: AbortReason) -> MPCError {
+        // some parties may have stopped already, so we try to reach every party
+        debug!("Aborting because of party {}: {}", by, reason);
+        for to in 0..self.transport.peers() {
+            let _ = self.transport.send(to as PartyID, SyncMsg::Abort(by, reason.clone()));
+        }
+        MPCError::Aborted { by, reason }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::error::MACCheckError;
     use crate::message::{SyncMsg, SyncReplyMsg};
+    use crate::transport::ChanTransport;
     use crossbeam::channel::bounded;
 
     const TEST_CAP: usize = 5;
+    const TIMEOUT: Duration = Duration::from_secs(1);
 
     #[test]
     fn test_synchronizer() {
         let (s_msg, r_msg) = bounded(TEST_CAP);
         let (s_reply, r_reply) = bounded(TEST_CAP);
-        let handler = Synchronizer::spawn(vec![s_msg], vec![r_reply]);
+        let handler = Synchronizer::spawn(ChanTransport::new(vec![s_msg], vec![r_reply]), TIMEOUT);
 
         // we expect to hear a Start followed by a Next
         assert_eq!(SyncMsg::Start, r_msg.recv_timeout(TIMEOUT).unwrap());
@@ -78,9 +101,36 @@ mod tests {
         assert_eq!(SyncMsg::Next, r_msg.recv_timeout(TIMEOUT).unwrap());
 
         // finally, sending Abort will respond with Abort
-        s_reply.send(SyncReplyMsg::Abort).unwrap();
-        assert_eq!(SyncMsg::Abort, r_msg.recv_timeout(TIMEOUT).unwrap());
+        let reason = AbortReason::MACCheck(MACCheckError::SumIsNotZero).relayed();
+        s_reply.send(SyncReplyMsg::Abort(0, reason.clone())).unwrap();
+        assert_eq!(SyncMsg::Abort(0, reason.clone()), r_msg.recv_timeout(TIMEOUT).unwrap());
+
+        match handler.join().unwrap() {
+            Err(MPCError::Aborted { by: 0, reason: r }) => assert_eq!(r, reason),
+            res => panic!("expected abort, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_synchronizer_unexpected() {
+        let (s_msgs, r_msgs): (Vec<_>, Vec<_>) = (0..2).map(|_| bounded(TEST_CAP)).unzip();
+        let (s_replies, r_replies): (Vec<_>, Vec<_>) = (0..2).map(|_| bounded(TEST_CAP)).unzip();
+        let handler = Synchronizer::spawn(ChanTransport::new(s_msgs, r_replies), TIMEOUT);
+        for r in &r_msgs {
+            assert_eq!(SyncMsg::Start, r.recv_timeout(TIMEOUT).unwrap());
+            assert_eq!(SyncMsg::Next, r.recv_timeout(TIMEOUT).unwrap());
+        }
 
-        assert_eq!((), handler.join().unwrap().unwrap());
+        // party 1 is done while party 0 is not, every party is told to abort
+        s_replies[0].send(SyncReplyMsg::Ok).unwrap();
+        s_replies[1].send(SyncReplyMsg::Done).unwrap();
+        let reason = AbortReason::UnexpectedSyncMessage;
+        for r in &r_msgs {
+            assert_eq!(SyncMsg::Abort(1, reason.clone()), r.recv_timeout(TIMEOUT).unwrap());
+        }
+        match handler.join().unwrap() {
+            Err(MPCError::Aborted { by: 1, reason: r }) => assert_eq!(r, reason),
+            res => panic!("expected abort, got {:?}", res),
+        }
     }
 }
@@ -0,0 +1,355 @@
+//! This module defines the `Transport` trait which abstracts how messages are delivered
+//! between the parties, or between the synchronizer and the parties.
+//! Peers are addressed by their `PartyID` and a transport of a party includes the party itself,
+//! i.e., a message that a party sends to itself is delivered back to it.
+//! The implementation over TCP and Unix domain sockets lives in the `io` module.
+
+use crate::error::TransportError;
+use crate::message::PartyID;
+
+use crossbeam::channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender};
+use log::debug;
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A transport sends messages of type `S` and receives messages of type `R`.
+pub trait Transport<S, R>: Send {
+    /// The number of peers, messages can be sent to and received from the ids `0..peers()`.
+    fn peers(&self) -> usize;
+
+    /// Send the message `m` to the peer `to`.
+    fn send(&self, to: PartyID, m: S) -> Result<(), TransportError>;
+
+    /// Receive the next message from the peer `from`, waiting at most `timeout`.
+    fn recv_from(&self, from: PartyID, timeout: Duration) -> Result<R, TransportError>;
+
+    /// Send the message `m` to every peer.
+    fn broadcast(&self, m: S) -> Result<(), TransportError>
+    where
+        S: Clone,
+    {
+        for to in 0..self.peers() {
+            self.send(to as PartyID, m.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Wait for one message from every peer, the messages are ordered by the id of the sender.
+    fn recv_all(&self, timeout: Duration) -> Result<Vec<R>, TransportError> {
+        (0..self.peers()).map(|from| self.recv_from(from as PartyID, timeout)).collect()
+    }
+}
+
+impl<S, R, T: Transport<S, R> + Sync + ?Sized> Transport<S, R> for Arc<T> {
+    fn peers(&self) -> usize {
+        (**self).peers()
+    }
+
+    fn send(&self, to: PartyID, m: S) -> Result<(), TransportError> {
+        (**self).send(to, m)
+    }
+
+    fn recv_from(&self, from: PartyID, timeout: Duration) -> Result<R, TransportError> {
+        (**self).recv_from(from, timeout)
+    }
+}
+
+fn recv_error(from: PartyID, e: RecvTimeoutError) -> TransportError {
+    match e {
+        RecvTimeoutError::Timeout => TransportError::Timeout(from),
+        RecvTimeoutError::Disconnected => TransportError::Disconnected(from),
+    }
+}
+
+fn get<T>(v: &[T], id: PartyID) -> Result<&T, TransportError> {
+    v.get(id as usize).ok_or(TransportError::UnknownParty(id))
+}
+
+/// An in-memory transport where every peer is connected using a pair of channels.
+pub struct ChanTransport<S, R> {
+    senders: Vec<Sender<S>>,
+    receivers: Vec<Receiver<R>>,
+}
+
+impl<S, R> ChanTransport<S, R> {
+    /// Create a transport where `senders[i]` and `receivers[i]` are connected to the peer with id `i`.
+    pub fn new(senders: Vec<Sender<S>>, receivers: Vec<Receiver<R>>) -> ChanTransport<S, R> {
+        assert_eq!(senders.len(), receivers.len(), "every peer needs a sender and a receiver");
+        ChanTransport { senders, receivers }
+    }
+}
+
+impl<T> ChanTransport<T, T> {
+    /// Create the transports of `n` fully connected parties, the transport of party `i` is at index `i`.
+    pub fn mesh(n: usize, cap: usize) -> Vec<ChanTransport<T, T>> {
+        // chans[i][j] carries the messages from i to j
+        let mut chans: Vec<Vec<_>> = (0..n).map(|_| (0..n).map(|_| bounded(cap)).collect()).collect();
+        let receivers: Vec<Vec<Receiver<T>>> = (0..n).map(|j| chans.iter().map(|row| row[j].1.clone()).collect()).collect();
+        chans
+            .iter_mut()
+            .zip(receivers)
+            .map(|(row, receivers)| ChanTransport::new(row.drain(..).map(|(s, _)| s).collect(), receivers))
+            .collect()
+    }
+}
+
+impl<S: Send, R: Send> Transport<S, R> for ChanTransport<S, R> {
+    fn peers(&self) -> usize {
+        self.senders.len()
+    }
+
+    fn send(&self, to: PartyID, m: S) -> Result<(), TransportError> {
+        get(&self.senders, to)?.send(m).map_err(|_| TransportError::Disconnected(to))
+    }
+
+    fn recv_from(&self, from: PartyID, timeout: Duration) -> Result<R, TransportError> {
+        get(&self.receivers, from)?.recv_timeout(timeout).map_err(|e| recv_error(from, e))
+    }
+}
+
+type Packet = (Instant, Vec<u8>);
+
+/// An in-memory transport that simulates network links.
+/// Messages are serialized as they would be on a real link
+/// and are only delivered after a fixed latency.
+pub struct LoopbackTransport<S, R> {
+    senders: Vec<Sender<Packet>>,
+    receivers: Vec<Receiver<Packet>>,
+    latency: Duration,
+    _marker: PhantomData<fn(S) -> R>,
+}
+
+impl<T> LoopbackTransport<T, T> {
+    /// Create the transports of `n` fully connected parties, the transport of party `i` is at index `i`.
+    /// Like a network, the links are unbounded.
+    pub fn mesh(n: usize, latency: Duration) -> Vec<LoopbackTransport<T, T>> {
+        let mut chans: Vec<Vec<(Sender<Packet>, Receiver<Packet>)>> = (0..n).map(|_| (0..n).map(|_| unbounded()).collect()).collect();
+        let receivers: Vec<Vec<Receiver<Packet>>> = (0..n).map(|j| chans.iter().map(|row| row[j].1.clone()).collect()).collect();
+        chans
+            .iter_mut()
+            .zip(receivers)
+            .map(|(row, receivers)| LoopbackTransport {
+                senders: row.drain(..).map(|(s, _)| s).collect(),
+                receivers,
+                latency,
+                _marker: PhantomData,
+            })
+            .collect()
+    }
+}
+
+impl<S: Serialize, R: DeserializeOwned> Transport<S, R> for LoopbackTransport<S, R> {
+    fn peers(&self) -> usize {
+        self.senders.len()
+    }
+
+    fn send(&self, to: PartyID, m: S) -> Result<(), TransportError> {
+        let data = bincode::serialize(&m).map_err(|_| TransportError::BadMessage(to))?;
+        get(&self.senders, to)?
+            .send((Instant::now() + self.latency, data))
+            .map_err(|_| TransportError::Disconnected(to))
+    }
+
+    fn recv_from(&self, from: PartyID, timeout: Duration) -> Result<R, TransportError> {
+        let (deliver_at, data) = get(&self.receivers, from)?.recv_timeout(timeout).map_err(|e| recv_error(from, e))?;
+        let now = Instant::now();
+        if deliver_at > now {
+            thread::sleep(deliver_at - now);
+        }
+        bincode::deserialize(&data).map_err(|_| TransportError::BadMessage(from))
+    }
+}
+
+/// A transport that logs every message going through the inner transport.
+pub struct LoggingTransport<T> {
+    inner: T,
+    name: String,
+}
+
+impl<T> LoggingTransport<T> {
+    /// Wrap `inner`, the log lines are prefixed with `name`.
+    pub fn new(inner: T, name: &str) -> LoggingTransport<T> {
+        LoggingTransport {
+            inner,
+            name: name.to_string(),
+        }
+    }
+}
+
+impl<S: Debug, R: Debug, T: Transport<S, R>> Transport<S, R> for LoggingTransport<T> {
+    fn peers(&self) -> usize {
+        self.inner.peers()
+    }
+
+    fn send(&self, to: PartyID, m: S) -> Result<(), TransportError> {
+        debug!("[{}] sending {:?} to {}", self.name, m, to);
+        self.inner.send(to, m)
+    }
+
+    fn recv_from(&self, from: PartyID, timeout: Duration) -> Result<R, TransportError> {
+        let res = self.inner.recv_from(from, timeout);
+        debug!("[{}] received {:?} from {}", self.name, res, from);
+        res
+    }
+}
+
+/// The number of messages and their total size in bincode that went through a `CountingTransport`.
+#[derive(Clone, Copy, Default, Eq, PartialEq, Debug)]
+pub struct TransportStats {
+    pub sent: u64,
+    pub received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// The counters of a `CountingTransport`, they can be read while the transport is in use.
+#[derive(Default, Debug)]
+pub struct TransportCounters {
+    sent: AtomicU64,
+    received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+impl TransportCounters {
+    pub fn stats(&self) -> TransportStats {
+        TransportStats {
+            sent: self.sent.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A transport that counts the messages that are sent and received successfully by the inner transport.
+pub struct CountingTransport<T> {
+    inner: T,
+    counters: Arc<TransportCounters>,
+}
+
+impl<T> CountingTransport<T> {
+    pub fn new(inner: T) -> CountingTransport<T> {
+        CountingTransport {
+            inner,
+            counters: Arc::new(TransportCounters::default()),
+        }
+    }
+
+    /// Get the counters, which stay readable after the transport is moved or dropped.
+    pub fn counters(&self) -> Arc<TransportCounters> {
+        self.counters.clone()
+    }
+}
+
+fn size_of<T: Serialize>(m: &T) -> u64 {
+    bincode::serialized_size(m).unwrap_or_default()
+}
+
+impl<S: Serialize, R: Serialize, T: Transport<S, R>> Transport<S, R> for CountingTransport<T> {
+    fn peers(&self) -> usize {
+        self.inner.peers()
+    }
+
+    fn send(&self, to: PartyID, m: S) -> Result<(), TransportError> {
+        let size = size_of(&m);
+        self.inner.send(to, m)?;
+        self.counters.sent.fetch_add(1, Ordering::Relaxed);
+        self.counters.bytes_sent.fetch_add(size, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn recv_from(&self, from: PartyID, timeout: Duration) -> Result<R, TransportError> {
+        let m = self.inner.recv_from(from, timeout)?;
+        self.counters.received.fetch_add(1, Ordering::Relaxed);
+        self.counters.bytes_received.fetch_add(size_of(&m), Ordering::Relaxed);
+        Ok(m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_CAP: usize = 5;
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    fn check_mesh<T: Transport<usize, usize>>(transports: &[T]) {
+        // every party broadcasts its id and receives all the ids in order
+        for (i, t) in transports.iter().enumerate() {
+            assert_eq!(t.peers(), transports.len());
+            t.broadcast(i).unwrap();
+        }
+        for t in transports {
+            assert_eq!(t.recv_all(TIMEOUT).unwrap(), (0..transports.len()).collect::<Vec<_>>());
+        }
+
+        // point-to-point messages only reach the recipient
+        transports[0].send(1, 42).unwrap();
+        assert_eq!(transports[1].recv_from(0, TIMEOUT).unwrap(), 42);
+        assert_eq!(transports[2].recv_from(0, TIMEOUT).unwrap_err(), TransportError::Timeout(0));
+        assert_eq!(transports[0].send(3, 0).unwrap_err(), TransportError::UnknownParty(3));
+        assert_eq!(transports[0].recv_from(3, TIMEOUT).unwrap_err(), TransportError::UnknownParty(3));
+    }
+
+    #[test]
+    fn test_chan_transport() {
+        let transports = ChanTransport::mesh(3, TEST_CAP);
+        check_mesh(&transports);
+
+        // dropping a party disconnects it
+        let mut transports = transports;
+        transports.pop();
+        assert_eq!(transports[0].send(2, 0).unwrap_err(), TransportError::Disconnected(2));
+    }
+
+    #[test]
+    fn test_loopback_transport() {
+        let latency = Duration::from_millis(20);
+        let transports = LoopbackTransport::mesh(3, latency);
+        check_mesh(&transports);
+
+        let start = Instant::now();
+        transports[0].send(0, 1).unwrap();
+        assert_eq!(transports[0].recv_from(0, TIMEOUT).unwrap(), 1);
+        assert!(start.elapsed() >= latency);
+    }
+
+    #[test]
+    fn test_logging_transport() {
+        let transports: Vec<_> = ChanTransport::mesh(3, TEST_CAP)
+            .into_iter()
+            .enumerate()
+            .map(|(i, t)| Arc::new(LoggingTransport::new(t, &format!("party {}", i))))
+            .collect();
+        check_mesh(&transports);
+    }
+
+    #[test]
+    fn test_counting_transport() {
+        let transports: Vec<_> = ChanTransport::mesh(3, TEST_CAP).into_iter().map(CountingTransport::new).collect();
+        let counters: Vec<_> = transports.iter().map(|t| t.counters()).collect();
+        check_mesh(&transports);
+
+        // a usize is 8 bytes in bincode, every party broadcasts and receives one message per peer,
+        // then party 0 sends one more message to party 1 and the failed calls are not counted
+        let size = size_of(&0usize);
+        let stats: Vec<_> = counters.iter().map(|c| c.stats()).collect();
+        assert_eq!(
+            stats[0],
+            TransportStats {
+                sent: 4,
+                received: 3,
+                bytes_sent: 4 * size,
+                bytes_received: 3 * size
+            }
+        );
+        assert_eq!(stats[1].received, 4);
+        assert_eq!(stats[2].sent, 3);
+    }
+}
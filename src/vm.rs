@@ -2,15 +2,17 @@
 
 use crate::algebra::Fp;
 use crate::crypto::AuthShare;
-use crate::error::{MACCheckError, MPCError, TIMEOUT};
-use crate::message::{PartyID, RandShareMsg, TripleMsg};
+use crate::error::{MACCheckError, MPCError, Timeouts};
+use crate::message::{PartyID, PrepSeqs, RandShareMsg, TripleMsg};
 
 use crate::error::MPCError::RegCreationError;
-use crossbeam::channel::{bounded, select, Receiver, Sender};
+use crossbeam::channel::{bounded, Receiver, Sender};
 use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
 use std::cmp::min;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::default::Default;
+use std::ops::Range;
 use std::thread;
 use std::thread::JoinHandle;
 
@@ -19,7 +21,7 @@ pub(crate) const DEFAULT_CAP: usize = 5;
 // for some reason Default trait for arrays only works up to 32 elements
 const REG_SIZE: usize = 32;
 
-type RegAddr = usize;
+pub type RegAddr = usize;
 
 /// Reg is the register stored by the VM.
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -92,8 +94,10 @@ pub struct VM {
     reg: Reg,
     triple_chan: Receiver<TripleMsg>,
     rand_chan: Receiver<RandShareMsg>,
-    rand_msgs: HashMap<PartyID, Vec<RandShareMsg>>,
+    rand_msgs: HashMap<PartyID, VecDeque<RandShareMsg>>,
+    used: PrepSeqs,
     partial_openings: Vec<(Fp, AuthShare)>,
+    timeouts: Timeouts,
 }
 
 /// These are the possible action items that the VM cannot handle by itself.
@@ -105,8 +109,8 @@ pub enum Action {
     Open(Fp, Sender<Fp>),
     /// Secret share an input.
     Input(PartyID, Option<Fp>, Sender<Fp>),
-    /// Perform the MAC check.
-    Check(Vec<(Fp, AuthShare)>, Sender<Result<(), MACCheckError>>),
+    /// Perform the MAC check, after checking that all the parties used the same preprocessing data.
+    Check(Vec<(Fp, AuthShare)>, PrepSeqs, Sender<Result<(), MACCheckError>>),
 }
 
 /// These are the instructions for the VM.
@@ -149,6 +153,12 @@ pub enum Instruction {
     Stop,
 }
 
+/// Compute the SHA3 hash of the serialized program,
+/// the parties use it to check that they run the same program.
+pub fn prog_hash(prog: &[Instruction]) -> [u8; 32] {
+    Sha3_256::digest(&bincode::serialize(prog).expect("serialization failed")).into()
+}
+
 fn opt_to_res<T>(v: Option<T>) -> Result<T, MPCError> {
     match v {
         Some(x) => Ok(x),
@@ -156,11 +166,27 @@ fn opt_to_res<T>(v: Option<T>) -> Result<T, MPCError> {
     }
 }
 
+// check that `got` is the next element of a sequence and add it to the `used` range,
+// the first element may have any number since the parties compare their ranges in the MAC check
+fn check_seq(used: &mut Range<u64>, got: u64) -> Result<(), MPCError> {
+    if used.is_empty() {
+        *used = got..got;
+    } else if got != used.end {
+        return Err(MPCError::PrepSeqError { expected: used.end, got });
+    }
+    used.end += 1;
+    Ok(())
+}
+
 impl VM {
     /// Spawns a new VM thread and returns its handler.
     /// This function assumes all the VMs running in the MPC cluster have a unique `id`,
     /// the global MAC key share (`alpha_share`) is correct and that
     /// the channels are not disconnected before calling `.join` on the returned handler.
+    /// The VM waits for the next instruction for `timeouts.sync` and for the preprocessing data for `timeouts.prep`.
+    /// There is no timeout on the replies to its actions, since an action may need several rounds of communication
+    /// and the party already bounds every round by `timeouts.open`.
+    #[allow(clippy::too_many_arguments)]
     pub fn spawn(
         id: PartyID,
         alpha_share: Fp,
@@ -169,14 +195,15 @@ impl VM {
         rand_chan: Receiver<RandShareMsg>,
         r_chan: Receiver<Instruction>,
         s_chan: Sender<Action>,
+        timeouts: Timeouts,
     ) -> JoinHandle<Result<Vec<Fp>, MPCError>> {
         thread::spawn(move || {
-            let mut vm = VM::new(id, alpha_share, reg, triple_chan, rand_chan);
+            let mut vm = VM::new(id, alpha_share, reg, triple_chan, rand_chan, timeouts);
             vm.listen(r_chan, s_chan)
         })
     }
 
-    fn new(id: PartyID, alpha_share: Fp, reg: Reg, triple_chan: Receiver<TripleMsg>, rand_chan: Receiver<RandShareMsg>) -> VM {
+    fn new(id: PartyID, alpha_share: Fp, reg: Reg, triple_chan: Receiver<TripleMsg>, rand_chan: Receiver<RandShareMsg>, timeouts: Timeouts) -> VM {
         VM {
             id,
             alpha_share,
@@ -184,7 +211,9 @@ impl VM {
             triple_chan,
             rand_chan,
             rand_msgs: HashMap::new(),
+            used: PrepSeqs::default(),
             partial_openings: Vec::new(),
+            timeouts,
         }
     }
 
@@ -193,7 +222,7 @@ impl VM {
         let mut output = Vec::new();
 
         loop {
-            let inst = r_chan.recv_timeout(TIMEOUT)?;
+            let inst = r_chan.recv_timeout(self.timeouts.sync)?;
             match inst {
                 Instruction::CAdd(r0, r1, r2) => self.do_clear_op(r0, r1, r2, |x, y| x + y)?,
                 Instruction::CSub(r0, r1, r2) => self.do_clear_op(r0, r1, r2, |x, y| x - y)?,
@@ -259,29 +288,17 @@ impl VM {
         Ok(())
     }
 
+    // the shares that belong to other parties are kept for later,
+    // they must arrive in the order of their sequence numbers so that all the parties use the same share
     fn get_rand_share_for_id(&mut self, id: PartyID) -> Result<RandShareMsg, MPCError> {
         loop {
-            select! {
-                recv(self.rand_chan) -> r_res => {
-                    // create empty entry if id does not exist
-                    let r = r_res?;
-                    if !self.rand_msgs.contains_key(&r.party_id) {
-                        self.rand_msgs.insert(r.party_id, vec![]);
-                    }
-                    // write the msg
-                    match self.rand_msgs.get_mut(&r.party_id) {
-                        Some(v) => v.push(r),
-                        None => panic!("rand share for id {} should exist", r.party_id),
-                    }
-                }
-                default => {
-                    break;
-                }
+            if let Some(r) = self.rand_msgs.get_mut(&id).and_then(|v| v.pop_front()) {
+                check_seq(self.used.rand_shares.entry(id).or_default(), r.seq)?;
+                return Ok(r);
             }
+            let r = self.rand_chan.recv_timeout(self.timeouts.prep)?;
+            self.rand_msgs.entry(r.party_id).or_default().push_back(r);
         }
-        // not that we're reading the rand msgs in a LIFO order
-        let opt_out = opt_to_res(self.rand_msgs.get_mut(&id))?.pop();
-        opt_to_res(opt_out)
     }
 
     fn do_input(&mut self, r0: RegAddr, r1: RegAddr, id: PartyID, s_chan: &Sender<Action>) -> Result<(), MPCError> {
@@ -296,14 +313,15 @@ impl VM {
             s_chan.send(Action::Input(id, None, s))?;
         }
 
-        let e = r.recv_timeout(TIMEOUT)?;
+        let e = r.recv()?;
         let input_share = rand_share.share.add_clear(&e, &self.alpha_share, self.id == id);
         self.reg.secret[r0] = Some(input_share);
         Ok(())
     }
 
     fn do_triple(&mut self, r0: RegAddr, r1: RegAddr, r2: RegAddr) -> Result<(), MPCError> {
-        let triple = self.triple_chan.recv_timeout(TIMEOUT)?;
+        let triple = self.triple_chan.recv_timeout(self.timeouts.prep)?;
+        check_seq(&mut self.used.triples, triple.seq)?;
         self.reg.secret[r0] = Some(triple.a);
         self.reg.secret[r1] = Some(triple.b);
         self.reg.secret[r2] = Some(triple.c);
@@ -318,7 +336,7 @@ impl VM {
                 s_chan.send(Action::Open(for_opening.share.clone(), s))?;
 
                 // wait for the response
-                let opened: Fp = r.recv_timeout(TIMEOUT)?;
+                let opened: Fp = r.recv()?;
                 self.reg.clear[to] = Some(opened.clone());
 
                 // store the opened value for mac_check later
@@ -336,7 +354,7 @@ impl VM {
             Some(x) => {
                 let (s, r) = bounded(1);
                 s_chan.send(Action::Open(x.share.clone(), s))?;
-                let opened: Fp = r.recv_timeout(TIMEOUT)?;
+                let opened: Fp = r.recv()?;
 
                 self.partial_openings.push((opened, x.clone()));
 
@@ -349,10 +367,10 @@ impl VM {
     fn do_mac_check(&mut self, s_chan: &Sender<Action>) -> Result<(), MPCError> {
         // next do the mac_check
         let (s, r) = bounded(1);
-        s_chan.send(Action::Check(self.partial_openings.clone(), s))?;
+        s_chan.send(Action::Check(self.partial_openings.clone(), self.used.clone(), s))?;
 
         // wait for response and clear the partial opening vector
-        r.recv_timeout(TIMEOUT)??;
+        r.recv()??;
         self.partial_openings.clear();
         Ok(())
     }
@@ -361,6 +379,7 @@ impl VM {
 #[cfg(test)]
 pub(crate) mod tests {
     use super::*;
+    use crossbeam::channel::RecvTimeoutError;
     use num_traits::Zero;
     use quickcheck_macros::quickcheck;
 
@@ -415,14 +434,28 @@ pub(crate) mod tests {
         let (s_action_chan, r_action_chan) = bounded(DEFAULT_CAP);
 
         let fake_alpha_share = Fp::zero();
-        let handle = VM::spawn(0, fake_alpha_share, reg, triple_chan, rand_chan, r_instruction_chan, s_action_chan);
+        let handle = VM::spawn(
+            0,
+            fake_alpha_share,
+            reg,
+            triple_chan,
+            rand_chan,
+            r_instruction_chan,
+            s_action_chan,
+            Timeouts::default(),
+        );
         for instruction in prog {
             s_instruction_chan.send(instruction.clone())?;
 
             loop {
                 // these replies are obviously not the correct implementation, they're only here for testing
                 // the actual implementation is in party.rs
-                let reply = r_action_chan.recv_timeout(TIMEOUT)?;
+                let reply = match r_action_chan.recv_timeout(Timeouts::default().open) {
+                    Ok(reply) => reply,
+                    // the VM stopped early, so return its error
+                    Err(RecvTimeoutError::Disconnected) => return handle.join().unwrap(),
+                    Err(e) => return Err(e.into()),
+                };
                 match reply {
                     Action::Next => {
                         break;
@@ -432,7 +465,7 @@ pub(crate) mod tests {
                         Some(e) => sender.send(e)?,
                         None => sender.send(Fp::zero())?,
                     },
-                    Action::Check(_, sender) => sender.send(Ok(()))?,
+                    Action::Check(_, _, sender) => sender.send(Ok(()))?,
                 }
             }
 
@@ -578,12 +611,30 @@ pub(crate) mod tests {
         let b_share = AuthShare { share: b, mac: Fp::zero() };
         let c_share = AuthShare { share: c, mac: Fp::zero() };
         s_triple_chan
-            .send(TripleMsg::new(a_share.clone(), b_share.clone(), c_share.clone()))
+            .send(TripleMsg::new(0, a_share.clone(), b_share.clone(), c_share.clone()))
             .unwrap();
         let result = vm_runner(prog, Reg::empty(), r_triple_chan, dummy_rand_chan).unwrap();
         result.len() == 3 && result[0] == a_share.share && result[1] == b_share.share && result[2] == c_share.share
     }
 
+    #[test]
+    fn test_triple_out_of_order() {
+        let prog = vec![Instruction::Triple(0, 1, 2), Instruction::Triple(0, 1, 2), Instruction::Stop];
+
+        let (s_triple_chan, r_triple_chan) = bounded(DEFAULT_CAP);
+        let (_, dummy_rand_chan) = bounded(DEFAULT_CAP);
+
+        let zero = AuthShare {
+            share: Fp::zero(),
+            mac: Fp::zero(),
+        };
+        for seq in [0, 2] {
+            s_triple_chan.send(TripleMsg::new(seq, zero.clone(), zero.clone(), zero.clone())).unwrap();
+        }
+        let err = vm_runner(prog, Reg::empty(), r_triple_chan, dummy_rand_chan).unwrap_err();
+        assert!(matches!(err, MPCError::PrepSeqError { expected: 1, got: 2 }));
+    }
+
     #[quickcheck]
     fn prop_input(r: Fp, r_share: Fp, x: Fp) -> bool {
         let prog = vec![Instruction::Input(0, 0, 0), Instruction::SOutput(0), Instruction::Stop];
@@ -592,6 +643,7 @@ pub(crate) mod tests {
         let (s_rand_chan, r_rand_chan) = bounded(DEFAULT_CAP);
 
         let rand_msg = RandShareMsg {
+            seq: 0,
             share: AuthShare {
                 share: r_share,
                 mac: Fp::zero(),